@@ -3,12 +3,11 @@
 #![no_std]
 #![no_main]
 
-use core::sync::atomic::Ordering;
 use defmt_rtt as _;
 use panic_probe as _;
-use rs_flash::flash_interface;
+use rs_flash::{flash_interface, ErrorCode};
+use spi_flash_device::ExFlash;
 
-use spi_memory::prelude::*;
 use spi_memory::series25::Flash;
 use stm32f1xx_hal::prelude::*;
 use stm32f1xx_hal::{pac, spi};
@@ -61,28 +60,16 @@ fn main() -> ! {
         clocks.pclk1(), // Run as fast as we can. The flash chip can go up to 133Mhz.
         clocks,
     );
-    let mut ex_flash = Flash::init(spi, cs).unwrap();
+    let ex_flash = Flash::init(spi, cs).unwrap_or_else(|_| rs_flash_error(ErrorCode::Init, 0));
 
-    // --- Dump the entire flash.
-    defmt::info!("dumping...");
-    for (offset, chunk) in (0..FLASH_SIZE).step_by(BUFFER_SIZE).zip(1..) {
-        defmt::info!("chunk {} / {} (at 0x{:08x})", chunk, _CHUNKS, offset);
-        // Read the next chunk into the buffer.
-        {
-            let buf = unsafe { &mut *RS_FLASH_BUFFER.as_mut_ptr() };
-            let addr = offset as _;
-            ex_flash.read(addr, buf).unwrap();
-        }
-        // Signal buffer is ready to be read.
-        unsafe { RS_FLASH_CONTROL.store(1, Ordering::SeqCst) };
-        // Spin until the host has read the buffer.
-        while unsafe { RS_FLASH_CONTROL.load(Ordering::SeqCst) } == 1 {
-            cortex_m::asm::nop();
-        }
+    // --- Run the protocol.
+    let mut ex_flash = ExFlash::new(ex_flash, FLASH_SIZE);
+    match rs_flash::run_read_only(rs_flash_interface(), &mut ex_flash) {
+        Ok(()) => defmt::info!("done."),
+        Err(e) => defmt::error!("{} failed at 0x{:08x}", e.code.as_str(), e.offset),
     }
 
     // --- Done.
-    defmt::info!("done.");
     cortex_m::asm::bkpt();
     cortex_m::asm::udf()
 }
@@ -3,12 +3,11 @@
 #![no_std]
 #![no_main]
 
-use core::sync::atomic::Ordering;
 use defmt_rtt as _;
 use panic_probe as _;
-use rs_flash::flash_interface;
+use rs_flash::{flash_interface, ErrorCode};
+use spi_flash_device::ExFlash;
 
-use spi_memory::prelude::*;
 use spi_memory::series25::Flash;
 use stm32f1xx_hal::prelude::*;
 use stm32f1xx_hal::{pac, spi};
@@ -19,8 +18,12 @@ const FLASH_SIZE: usize = 16 * 1024 * 1024;
 ///
 /// The RAM buffer size must divide the flash size without remainder.
 const BUFFER_SIZE: usize = 32 * 1024;
+/// The size of a flash sector in bytes.
+///
+/// The RAM buffer size must be a multiple of the sector size.
+const SECTOR_SIZE: usize = spi_flash_device::SECTOR_SIZE;
 
-flash_interface!(FLASH_SIZE, BUFFER_SIZE, load);
+flash_interface!(FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, load);
 
 /// Load example.
 #[cortex_m_rt::entry]
@@ -61,33 +64,16 @@ fn main() -> ! {
         clocks.pclk1(), // Run as fast as we can. The flash chip can go up to 133Mhz.
         clocks,
     );
-    let mut ex_flash = Flash::init(spi, cs).unwrap();
-
-    // --- Erase the entire flash.
-    defmt::info!("erasing chip...");
-    ex_flash.erase_all().unwrap();
-
-    // --- Load the entire flash.
-    defmt::info!("loading...");
+    let ex_flash = Flash::init(spi, cs).unwrap_or_else(|_| rs_flash_error(ErrorCode::Init, 0));
 
-    for (offset, chunk) in (0..FLASH_SIZE).step_by(BUFFER_SIZE).zip(1..) {
-        defmt::info!("chunk {} / {} (at 0x{:08x})", chunk, _CHUNKS, offset);
-        // Spin until the host has written the buffer.
-        while unsafe { RS_FLASH_CONTROL.load(Ordering::SeqCst) } == 0 {
-            cortex_m::asm::nop();
-        }
-        // Write the next chunk into the flash.
-        {
-            let buf = unsafe { &mut *RS_FLASH_BUFFER.as_mut_ptr() };
-            let addr = offset as _;
-            ex_flash.write_bytes(addr, buf).unwrap();
-        }
-        // Signal buffer is ready to be written.
-        unsafe { RS_FLASH_CONTROL.store(0, Ordering::SeqCst) };
+    // --- Run the protocol.
+    let mut ex_flash = ExFlash::new(ex_flash, FLASH_SIZE);
+    match rs_flash::run(rs_flash_interface(), &mut ex_flash) {
+        Ok(()) => defmt::info!("done."),
+        Err(e) => defmt::error!("{} failed at 0x{:08x}", e.code.as_str(), e.offset),
     }
 
     // --- Done.
-    defmt::info!("done.");
     cortex_m::asm::bkpt();
     cortex_m::asm::udf()
 }
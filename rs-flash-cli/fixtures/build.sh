@@ -0,0 +1,33 @@
+#!/bin/sh
+# SPDX-License-Identifier: MIT OR Apache-2.0
+#
+# Rebuild the example programs the emulated end-to-end tests run, after
+# changing `rs-flash` or the examples.
+#
+# The programs are linked without page alignment, and stripped down to the
+# sections and symbols the tests need, to keep the fixtures small.
+set -eu
+
+fixtures=$(cd "$(dirname "$0")" && pwd)
+root="$fixtures/../.."
+target_dir="${CARGO_TARGET_DIR:-$fixtures/../target/fixtures}"
+
+for example in dump load; do
+    (
+        cd "$root/$example-spi-flash"
+        CARGO_TARGET_DIR="$target_dir" cargo rustc --bin "$example" -- \
+            -C link-arg=-zmax-page-size=4 -C link-arg=-zcommon-page-size=4
+    )
+    llvm-objcopy --strip-all \
+        --keep-section=.rs-flash \
+        --keep-symbol=main \
+        --keep-symbol=_SEGGER_RTT \
+        --keep-symbol=_RS_FLASH_BUFFER \
+        --keep-symbol=_RS_FLASH_CONTROL \
+        --keep-symbol=_RS_FLASH_PARAMS \
+        --keep-symbol=_RS_FLASH_STATUS \
+        --keep-symbol=_RS_FLASH_SECTORS \
+        --keep-symbol=_RS_FLASH_CHECKSUM \
+        "$target_dir/thumbv7em-none-eabihf/debug/$example" \
+        "$fixtures/$example.elf"
+done
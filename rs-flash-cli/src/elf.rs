@@ -1,24 +1,133 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use color_eyre::eyre::{bail, eyre, OptionExt as _, Result};
+//! Parsing RAM programs, and the flash table they export.
+
+use color_eyre::eyre::{bail, OptionExt as _, Result};
 use ram_probe_rs::defmt::DefmtInfo;
 use ram_probe_rs::elf::{parse_vector_table, Parser, Segments, VectorTable};
 use ram_probe_rs::probe_rs::Target;
-use rs_flash::Direction;
+use ram_probe_rs::run::DefmtOpts;
+use rs_flash::{table, Direction};
+use std::ops::Range;
 
+/// The flash table of a RAM program, and the addresses of its interface.
 #[derive(Debug, Clone)]
-pub(crate) struct FlashTable {
-    pub(crate) direction: Direction,
-    pub(crate) flash_size: usize,
-    pub(crate) buffer_size: usize,
-    pub(crate) buffer_addr: u64,
-    pub(crate) control_addr: u64,
+pub struct FlashTable {
+    pub version: u32,
+    pub direction: Direction,
+    pub flash_size: usize,
+    pub buffer_size: usize,
+    pub buffer_addr: u64,
+    pub control_addr: u64,
+    /// Not present in version 1 tables.
+    pub params_addr: Option<u64>,
+    /// Not present if the program never reports errors.
+    pub status_addr: Option<u64>,
+    /// The protocol features the program supports (see `table::CAP_*`).
+    pub capabilities: u32,
+    /// The buffer size, unless the program specifies a smaller sector size.
+    pub sector_size: usize,
+    /// Not present if the program can't write partial chunks.
+    pub sectors_addr: Option<u64>,
+    /// Not present if the program doesn't checksum dumped chunks.
+    pub checksum_addr: Option<u64>,
+    /// The number of buffers, and control, checksum and sectors words.
+    pub buffers: usize,
+}
+
+impl FlashTable {
+    /// The address of buffer `slot`.
+    pub fn buffer(&self, slot: usize) -> u64 {
+        self.buffer_addr + (slot * self.buffer_size) as u64
+    }
+
+    /// The address of the control word of buffer `slot`.
+    pub fn control(&self, slot: usize) -> u64 {
+        self.control_addr + slot as u64 * 4
+    }
+
+    /// The address of the checksum of buffer `slot`.
+    pub fn checksum(&self, slot: usize) -> Option<u64> {
+        self.checksum_addr.map(|addr| addr + slot as u64 * 4)
+    }
+
+    /// The address of the sector mask of buffer `slot`.
+    pub fn sectors(&self, slot: usize) -> Option<u64> {
+        self.sectors_addr.map(|addr| addr + slot as u64 * 4)
+    }
+
+    /// Check the range at `offset` fits the flash, and is aligned to chunks.
+    ///
+    /// Without a `length`, the range extends to the end of the flash.
+    pub fn range(&self, offset: usize, length: Option<usize>) -> Result<Range<usize>> {
+        let Self {
+            flash_size,
+            buffer_size,
+            ..
+        } = *self;
+
+        if offset > flash_size {
+            bail!(
+                "offset 0x{:08x} exceeds flash size 0x{:08x}",
+                offset,
+                flash_size
+            );
+        }
+        let length = length.unwrap_or(flash_size - offset);
+        if length == 0 {
+            bail!("length is zero");
+        }
+        if length > flash_size - offset {
+            bail!(
+                "range 0x{:08x}..0x{:08x} exceeds flash size 0x{:08x}",
+                offset,
+                offset + length,
+                flash_size
+            );
+        }
+        if offset % buffer_size != 0 {
+            bail!(
+                "offset 0x{:08x} is not a multiple of the buffer size 0x{:08x}",
+                offset,
+                buffer_size
+            );
+        }
+        if length % buffer_size != 0 {
+            bail!(
+                "length 0x{:08x} is not a multiple of the buffer size 0x{:08x}",
+                length,
+                buffer_size
+            );
+        }
+        Ok(offset..offset + length)
+    }
+}
+
+/// A RAM program, parsed from its ELF file.
+pub struct Program<'data> {
+    /// The segments to download to RAM.
+    pub segments: Segments<'data>,
+    pub rtt_addr: u32,
+    pub vector_table: VectorTable,
+    pub flash_table: FlashTable,
+    pub defmt: DefmtInfo,
 }
 
-pub(crate) fn parse_elf<'data>(
-    data: &'data [u8],
-    target: &Target,
-) -> Result<(Segments<'data>, u32, VectorTable, FlashTable, DefmtInfo)> {
+impl Program<'_> {
+    /// The options to download and start the program, and decode its defmt
+    /// output.
+    pub fn defmt_opts(&self) -> DefmtOpts<'_> {
+        DefmtOpts::with_defaults(
+            &self.segments,
+            self.rtt_addr,
+            &self.vector_table,
+            &self.defmt,
+        )
+    }
+}
+
+/// Parse the RAM program in the ELF file `data`, for `target`.
+pub fn parse_elf<'data>(data: &'data [u8], target: &Target) -> Result<Program<'data>> {
     let elf = Parser::new(&data)?;
 
     let segments = elf.ram_loadable_segments(&target)?;
@@ -26,6 +135,10 @@ pub(crate) fn parse_elf<'data>(
     let mut rtt_addr = None;
     let mut buffer_addr = None;
     let mut control_addr = None;
+    let mut params_addr = None;
+    let mut status_addr = None;
+    let mut sectors_addr = None;
+    let mut checksum_addr = None;
 
     for (name, addr) in elf.named_symbols() {
         log::trace!("ELF symbol `{}` at 0x{:08x}", name, addr);
@@ -33,6 +146,10 @@ pub(crate) fn parse_elf<'data>(
             "_SEGGER_RTT" => rtt_addr = Some(addr),
             "_RS_FLASH_BUFFER" => buffer_addr = Some(addr),
             "_RS_FLASH_CONTROL" => control_addr = Some(addr),
+            "_RS_FLASH_PARAMS" => params_addr = Some(addr),
+            "_RS_FLASH_STATUS" => status_addr = Some(addr),
+            "_RS_FLASH_SECTORS" => sectors_addr = Some(addr),
+            "_RS_FLASH_CHECKSUM" => checksum_addr = Some(addr),
             _ => {}
         }
     }
@@ -42,6 +159,22 @@ pub(crate) fn parse_elf<'data>(
     log::debug!("Buffer address 0x{:08x}", buffer_addr);
     let control_addr = control_addr.ok_or_eyre("Flash control symbol not found")?;
     log::debug!("Control address 0x{:08x}", control_addr);
+    match params_addr {
+        Some(params_addr) => log::debug!("Params address 0x{:08x}", params_addr),
+        None => log::debug!("Params symbol not found"),
+    }
+    match status_addr {
+        Some(status_addr) => log::debug!("Status address 0x{:08x}", status_addr),
+        None => log::debug!("Status symbol not found"),
+    }
+    match sectors_addr {
+        Some(sectors_addr) => log::debug!("Sectors address 0x{:08x}", sectors_addr),
+        None => log::debug!("Sectors symbol not found"),
+    }
+    match checksum_addr {
+        Some(checksum_addr) => log::debug!("Checksum address 0x{:08x}", checksum_addr),
+        None => log::debug!("Checksum symbol not found"),
+    }
 
     let mut vector_table = None;
     let mut flash_table = None;
@@ -58,7 +191,16 @@ pub(crate) fn parse_elf<'data>(
                 vector_table = Some(parse_vector_table(section)?);
             }
             ".rs-flash" => {
-                flash_table = Some(parse_flash_table(&section, buffer_addr, control_addr)?);
+                use ram_probe_rs::elf::object::ObjectSection as _;
+                flash_table = Some(parse_flash_table(
+                    section.data()?,
+                    buffer_addr,
+                    control_addr,
+                    params_addr,
+                    status_addr,
+                    sectors_addr,
+                    checksum_addr,
+                )?);
             }
             _ => {}
         }
@@ -73,41 +215,62 @@ pub(crate) fn parse_elf<'data>(
     if defmt.is_missing_debug() {
         log::warn!("defmt locations empty, is the ELF compiled with `debug = 2`?");
     }
-    Ok((segments, rtt_addr, vector_table, flash_table, defmt))
+    Ok(Program {
+        segments,
+        rtt_addr,
+        vector_table,
+        flash_table,
+        defmt,
+    })
 }
 
-/// Parse the flash table section.
-fn parse_flash_table(
-    section: &ram_probe_rs::elf::ElfSection<'_, '_>,
+/// Parse the flash table section `data`.
+///
+/// Older table versions are accepted, but newer ones are rejected.
+pub(crate) fn parse_flash_table(
+    data: &[u8],
     buffer_addr: u32,
     control_addr: u32,
+    params_addr: Option<u32>,
+    status_addr: Option<u32>,
+    sectors_addr: Option<u32>,
+    checksum_addr: Option<u32>,
 ) -> Result<FlashTable> {
-    use ram_probe_rs::elf::object::ObjectSection as _;
-
-    let size = section.size() as u32;
-    if size != 3 * 4 {
-        bail!("flash table is wrong size");
+    if data.len() % 4 != 0 {
+        bail!("flash table size {} is not a multiple of 4", data.len());
     }
+    let words: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
 
-    let data = section.data()?;
-    assert_eq!(
-        data.len(),
-        3 * 4,
-        "data length {} is different than size {}",
-        data.len(),
-        size
-    );
-    let flash_size = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
-    let buffer_size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
-    let direction = u32::from_le_bytes(data[8..12].try_into().unwrap());
-    let direction = Direction::from_u32(direction)
-        .ok_or_else(|| eyre!("Invalid flash table direction 0x{:08x}", direction))?;
+    let table::Table {
+        version,
+        flash_size,
+        buffer_size,
+        direction,
+        capabilities,
+        sector_size,
+        buffers,
+    } = table::decode(&words)?;
+    log::debug!("flash table version {}", version);
 
     Ok(FlashTable {
+        version,
         direction,
-        flash_size,
-        buffer_size,
+        flash_size: flash_size as _,
+        buffer_size: buffer_size as _,
         buffer_addr: buffer_addr as _,
         control_addr: control_addr as _,
+        params_addr: params_addr.map(|addr| addr as _),
+        status_addr: status_addr.map(|addr| addr as _),
+        capabilities,
+        sector_size: sector_size as _,
+        sectors_addr: sectors_addr.map(|addr| addr as _),
+        // Only use the checksum if the program writes it.
+        checksum_addr: checksum_addr
+            .filter(|_| capabilities & table::CAP_CHECKSUM != 0)
+            .map(|addr| addr as _),
+        buffers: buffers as _,
     })
 }
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Output file formats for dumps.
+
+use color_eyre::eyre::{bail, Context as _};
+use color_eyre::{Section as _, SectionExt as _};
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+/// The number of data bytes per Intel HEX or S-record record.
+const RECORD_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Format {
+    /// Raw binary
+    #[default]
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// ELF file with a single load segment
+    Elf,
+}
+
+impl Format {
+    /// Write `data`, which starts at `address`, in this format.
+    ///
+    /// The caller must check the data fits in the 32-bit address space, i.e.
+    /// ends at or below 4 GiB.
+    pub fn write(self, out: &mut impl Write, data: &[u8], address: u32) -> Result<()> {
+        match self {
+            Self::Bin => out.write_all(data),
+            Self::Ihex => write_ihex(out, data, address),
+            Self::Srec => write_srec(out, data, address),
+            Self::Elf => write_elf(out, data, address),
+        }
+    }
+}
+
+/// Convert the raw dump at `raw_path` to `format` at `path`, and remove the
+/// raw dump.
+///
+/// The raw dump must be `len` bytes, and starts at `address`.
+pub fn convert(
+    raw_path: &Path,
+    path: &Path,
+    format: Format,
+    address: u32,
+    len: usize,
+) -> color_eyre::Result<()> {
+    let data = std::fs::read(raw_path)
+        .wrap_err("failed to read raw dump file")
+        .with_section(|| raw_path.display().to_string().header("Path"))?;
+    if data.len() != len {
+        bail!(
+            "dump is incomplete ({} / {} bytes), run again with `--resume`",
+            data.len(),
+            len
+        );
+    }
+    let file = std::fs::File::create(path)
+        .wrap_err("failed to create dump file")
+        .with_section(|| path.display().to_string().header("Path"))?;
+    let mut out = BufWriter::new(file);
+    format.write(&mut out, &data, address)?;
+    out.flush()?;
+    std::fs::remove_file(raw_path).wrap_err("failed to remove raw dump file")?;
+    log::info!("wrote `{}` ({:?})", path.display(), format);
+    Ok(())
+}
+
+/// Write an Intel HEX record. The checksum is appended.
+fn ihex_record(out: &mut impl Write, address: u16, kind: u8, data: &[u8]) -> Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+
+    write!(out, ":")?;
+    for b in bytes {
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out)
+}
+
+fn write_ihex(out: &mut impl Write, data: &[u8], address: u32) -> Result<()> {
+    let mut upper = None;
+    for (i, record) in data.chunks(RECORD_LEN).enumerate() {
+        // The end of a record can be at 4 GiB, which doesn't fit in a `u32`.
+        let addr = address as u64 + (i * RECORD_LEN) as u64;
+        // Records can't cross a 64 KiB boundary, so split them.
+        let split = (0x1_0000 - (addr & 0xffff) as usize).min(record.len());
+        for (addr, record) in [
+            (addr, &record[..split]),
+            (addr + split as u64, &record[split..]),
+        ] {
+            if record.is_empty() {
+                continue;
+            }
+            let hi = (addr >> 16) as u16;
+            if upper != Some(hi) {
+                // Extended linear address record.
+                ihex_record(out, 0, 0x04, &hi.to_be_bytes())?;
+                upper = Some(hi);
+            }
+            ihex_record(out, addr as u16, 0x00, record)?;
+        }
+    }
+    // End of file record.
+    ihex_record(out, 0, 0x01, &[])
+}
+
+/// Write an S-record. The byte count and checksum are added.
+fn srec_record(out: &mut impl Write, kind: u8, address: &[u8], data: &[u8]) -> Result<()> {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(!sum);
+
+    write!(out, "S{}", kind)?;
+    for b in bytes {
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out)
+}
+
+fn write_srec(out: &mut impl Write, data: &[u8], address: u32) -> Result<()> {
+    // Header record.
+    srec_record(out, 0, &[0, 0], b"rs-flash")?;
+    let mut count = 0;
+    for (i, record) in data.chunks(RECORD_LEN).enumerate() {
+        let addr = address + (i * RECORD_LEN) as u32;
+        srec_record(out, 3, &addr.to_be_bytes(), record)?;
+        count += 1;
+    }
+    // Record count record, if the count fits.
+    if count <= 0xffff {
+        srec_record(out, 5, &(count as u16).to_be_bytes(), &[])?;
+    } else if count <= 0xff_ffff {
+        srec_record(out, 6, &(count as u32).to_be_bytes()[1..], &[])?;
+    }
+    // Termination record, without a start address.
+    srec_record(out, 7, &[0; 4], &[])
+}
+
+/// The size of the ELF header.
+const ELF_HEADER_LEN: u32 = 52;
+/// The size of a program header.
+const ELF_PHDR_LEN: u32 = 32;
+/// The size of a section header.
+const ELF_SHDR_LEN: u32 = 40;
+/// The section names.
+const ELF_SHSTRTAB: &[u8] = b"\0.data\0.shstrtab\0";
+
+/// Write a 32-bit little-endian ARM ELF file, with the data in a single load
+/// segment (and section, for disassemblers).
+fn write_elf(out: &mut impl Write, data: &[u8], address: u32) -> Result<()> {
+    let data_offset = ELF_HEADER_LEN + ELF_PHDR_LEN;
+    let data_len = data.len() as u32;
+    let shstrtab_offset = data_offset + data_len;
+    let shstrtab_len = ELF_SHSTRTAB.len() as u32;
+    let shdr_offset = (shstrtab_offset + shstrtab_len).next_multiple_of(4);
+
+    let mut header = Vec::with_capacity(data_offset as usize);
+    // Identification: 32-bit, little-endian, version 1, System V ABI.
+    header.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    let half = |header: &mut Vec<u8>, value: u16| header.extend_from_slice(&value.to_le_bytes());
+    half(&mut header, 2); // ET_EXEC
+    half(&mut header, 40); // EM_ARM
+    let word = |header: &mut Vec<u8>, value: u32| header.extend_from_slice(&value.to_le_bytes());
+    word(&mut header, 1); // version
+    word(&mut header, 0); // entry
+    word(&mut header, ELF_HEADER_LEN); // program headers
+    word(&mut header, shdr_offset); // section headers
+    word(&mut header, 0x0500_0000); // flags: EABI version 5
+    half(&mut header, ELF_HEADER_LEN as u16);
+    half(&mut header, ELF_PHDR_LEN as u16);
+    half(&mut header, 1);
+    half(&mut header, ELF_SHDR_LEN as u16);
+    half(&mut header, 3);
+    half(&mut header, 2); // section name section
+
+    // The load segment.
+    for value in [
+        1, // PT_LOAD
+        data_offset,
+        address,
+        address,
+        data_len,
+        data_len,
+        4, // PF_R
+        1,
+    ] {
+        word(&mut header, value);
+    }
+
+    out.write_all(&header)?;
+    out.write_all(data)?;
+    out.write_all(ELF_SHSTRTAB)?;
+    out.write_all(&vec![
+        0;
+        (shdr_offset - shstrtab_offset - shstrtab_len) as usize
+    ])?;
+
+    let sections: [[u32; 10]; 3] = [
+        // The null section.
+        [0; 10],
+        // `.data`: SHT_PROGBITS, SHF_ALLOC
+        [1, 1, 2, address, data_offset, data_len, 0, 0, 1, 0],
+        // `.shstrtab`: SHT_STRTAB
+        [7, 3, 0, 0, shstrtab_offset, shstrtab_len, 0, 0, 1, 0],
+    ];
+    for section in sections {
+        for value in section {
+            out.write_all(&value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{decode, DataFormat};
+
+    /// Write `data` at `address` in `format`, and decode it like a data file.
+    fn round_trip(format: Format, data: &[u8], address: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        format.write(&mut out, data, address).unwrap();
+        let data_format = match format {
+            Format::Bin => DataFormat::Bin,
+            Format::Ihex => DataFormat::Ihex,
+            Format::Srec => DataFormat::Srec,
+            Format::Elf => DataFormat::Elf,
+        };
+        let base_address = (format != Format::Bin).then_some(address as u64);
+        decode(out, data_format, base_address, data.len(), &(0..data.len())).unwrap()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = pattern(0x1234);
+        for format in [Format::Bin, Format::Ihex, Format::Srec, Format::Elf] {
+            for address in [0, 0x9000_0000] {
+                assert_eq!(round_trip(format, &data, address), data, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_at_4_gib() {
+        let data = pattern(0x40);
+        let address = (0x1_0000_0000 - data.len() as u64) as u32;
+        for format in [Format::Ihex, Format::Srec, Format::Elf] {
+            assert_eq!(round_trip(format, &data, address), data, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn splits_ihex_records_at_64_kib() {
+        let data = pattern(0x20);
+        let mut out = Vec::new();
+        write_ihex(&mut out, &data, 0x0001_fff8).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let records: Vec<_> = text
+            .lines()
+            // The byte count, address and record type of data records.
+            .map(|line| {
+                if &line[7..9] == "00" {
+                    &line[..9]
+                } else {
+                    line
+                }
+            })
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ":020000040001F9",
+                ":08FFF800",
+                ":020000040002F8",
+                ":08000000",
+                ":10000800",
+                ":00000001FF",
+            ]
+        );
+        assert_eq!(round_trip(Format::Ihex, &data, 0x0001_fff8), data);
+    }
+}
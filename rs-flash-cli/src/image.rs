@@ -0,0 +1,661 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Input file formats for loading, verifying and hashing.
+//!
+//! Formats with addresses are parsed into a sparse image, which is rebased
+//! onto the flash and filled with erased flash in between.
+
+use crate::run::ERASED;
+use color_eyre::eyre::{bail, eyre, Context as _, Result};
+use color_eyre::{Section as _, SectionExt as _};
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::path::Path;
+
+/// The first magic of a UF2 block.
+const UF2_MAGIC_START0: u32 = 0x0a32_4655;
+/// The second magic of a UF2 block.
+const UF2_MAGIC_START1: u32 = 0x9e5d_5157;
+/// The magic at the end of a UF2 block.
+const UF2_MAGIC_END: u32 = 0x0ab1_6f30;
+/// The UF2 block size.
+const UF2_BLOCK_LEN: usize = 512;
+/// The UF2 flag for blocks that are not for the main flash.
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DataFormat {
+    /// Raw binary, starting at `--offset`
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// ELF file, using the physical address of each load segment
+    Elf,
+    /// UF2 file
+    Uf2,
+}
+
+impl DataFormat {
+    /// Detect the format from the magic, or the file extension.
+    ///
+    /// Unknown files are raw binary.
+    fn detect(path: &Path, data: &[u8]) -> Self {
+        if data.starts_with(b"\x7fELF") {
+            return Self::Elf;
+        }
+        if data.starts_with(&UF2_MAGIC_START0.to_le_bytes()) {
+            return Self::Uf2;
+        }
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => Self::Ihex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => Self::Srec,
+            _ => Self::Bin,
+        }
+    }
+}
+
+/// How to fill a data file that is smaller than the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Pad {
+    /// Up to the end of the last chunk, and shorten the range
+    Chunk,
+    /// Up to the end of the range
+    Flash,
+}
+
+/// A contiguous run of data at an address.
+pub(crate) struct Segment {
+    pub(crate) address: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Read the data to load, verify or hash for `range` from `path`.
+///
+/// The format is detected unless given, and the data decoded as in `decode`.
+pub fn read(
+    path: &str,
+    format: Option<DataFormat>,
+    base_address: Option<u64>,
+    flash_size: usize,
+    range: &Range<usize>,
+) -> Result<Vec<u8>> {
+    let data = std::fs::read(path)
+        .wrap_err("failed to read data file")
+        .with_section(|| path.to_owned().header("Path"))?;
+    let format = format.unwrap_or_else(|| DataFormat::detect(path.as_ref(), &data));
+    log::debug!("data format {:?}", format);
+    decode(data, format, base_address, flash_size, range)
+        .with_section(|| path.to_owned().header("Path"))
+}
+
+/// Decode the data for `range` from the contents of a data file.
+///
+/// Raw binary data is returned as-is. Other formats are rebased from
+/// `base_address` (default 0) onto the flash, and must fit in `flash_size`. Gaps are
+/// filled with erased flash, and data outside of `range` is ignored.
+pub fn decode(
+    data: Vec<u8>,
+    format: DataFormat,
+    base_address: Option<u64>,
+    flash_size: usize,
+    range: &Range<usize>,
+) -> Result<Vec<u8>> {
+    let segments = match format {
+        DataFormat::Bin if base_address.is_some() => {
+            bail!("`--base-address` requires a data format with addresses, not `bin`")
+        }
+        DataFormat::Bin => return Ok(data),
+        DataFormat::Ihex => parse_ihex(&data),
+        DataFormat::Srec => parse_srec(&data),
+        DataFormat::Elf => parse_elf(&data),
+        DataFormat::Uf2 => parse_uf2(&data),
+    }
+    .wrap_err_with(|| format!("failed to parse data file as {:?}", format))?;
+
+    let base_address = base_address.unwrap_or(0);
+    let mut image = vec![ERASED; range.len()];
+    let mut ignored = 0;
+    for segment in segments {
+        let len = segment.data.len() as u64;
+        let start = segment
+            .address
+            .checked_sub(base_address)
+            .filter(|start| start + len <= flash_size as u64)
+            .ok_or_else(|| {
+                eyre!(
+                    "data at 0x{:08x}..0x{:08x} is outside the flash (base address 0x{:08x}, size 0x{:08x})",
+                    segment.address,
+                    segment.address + len,
+                    base_address,
+                    flash_size
+                )
+                .suggestion("check `--base-address`")
+            })? as usize;
+
+        let end = start + segment.data.len();
+        let from = start.clamp(range.start, range.end);
+        let to = end.clamp(range.start, range.end);
+        ignored += segment.data.len() - (to - from);
+        image[from - range.start..to - range.start]
+            .copy_from_slice(&segment.data[from - start..to - start]);
+    }
+    if ignored > 0 {
+        log::warn!(
+            "ignoring {} byte(s) of data outside of 0x{:08x}..0x{:08x}",
+            ignored,
+            range.start,
+            range.end
+        );
+    }
+    Ok(image)
+}
+
+/// Check `data` is the size of `range`, padding or truncating it if asked to.
+///
+/// Padding to the end of the chunk shortens `range` to the padded data.
+pub fn fit(
+    mut data: Vec<u8>,
+    range: &mut Range<usize>,
+    buffer_size: usize,
+    pad: Option<Pad>,
+    truncate: bool,
+) -> Result<Vec<u8>> {
+    let len = data.len();
+    match len.cmp(&range.len()) {
+        Ordering::Greater => {
+            if !truncate {
+                return Err(eyre!(
+                    "data file is larger than the range 0x{:08x}..0x{:08x} ({} > {} bytes)",
+                    range.start,
+                    range.end,
+                    len,
+                    range.len()
+                )
+                .suggestion("check `--offset` and `--length`, or use `--truncate` to only load the start of the data file"));
+            }
+            log::warn!("truncating data file from {} to {} bytes", len, range.len());
+            data.truncate(range.len());
+        }
+        Ordering::Less => {
+            match pad {
+                None => {
+                    return Err(eyre!(
+                        "data file is smaller than the range 0x{:08x}..0x{:08x} ({} < {} bytes)",
+                        range.start,
+                        range.end,
+                        len,
+                        range.len()
+                    )
+                    .suggestion(
+                        "use `--length`, or `--pad chunk` or `--pad flash` to fill the rest with 0xff",
+                    ));
+                }
+                Some(_) if len == 0 => bail!("data file is empty"),
+                Some(Pad::Chunk) => range.end = range.start + len.next_multiple_of(buffer_size),
+                Some(Pad::Flash) => {}
+            }
+            log::info!("padding data file from {} to {} bytes", len, range.len());
+            data.resize(range.len(), ERASED);
+        }
+        Ordering::Equal => {}
+    }
+    Ok(data)
+}
+
+/// Parse a hexadecimal record line into bytes.
+fn parse_hex_bytes(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Append `data` at `address`, merging it into the last segment if adjacent.
+fn push(segments: &mut Vec<Segment>, address: u64, data: &[u8]) {
+    match segments.last_mut() {
+        Some(last) if last.address + last.data.len() as u64 == address => {
+            last.data.extend_from_slice(data)
+        }
+        _ => segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        }),
+    }
+}
+
+fn parse_ihex(data: &[u8]) -> Result<Vec<Segment>> {
+    let text = std::str::from_utf8(data).wrap_err("Intel HEX file is not text")?;
+    let mut segments = Vec::new();
+    let mut upper = 0u64;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(parse_hex_bytes)
+            .filter(|bytes| bytes.len() >= 5 && bytes.len() == bytes[0] as usize + 5)
+            .ok_or_else(|| eyre!("invalid record on line {}", i + 1))?;
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            bail!("checksum mismatch on line {}", i + 1);
+        }
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            // Data.
+            0x00 => push(&mut segments, upper + address, payload),
+            // End of file.
+            0x01 => return Ok(segments),
+            // Extended segment address.
+            0x02 if payload.len() == 2 => {
+                upper = (u16::from_be_bytes([payload[0], payload[1]]) as u64) << 4
+            }
+            // Extended linear address.
+            0x04 if payload.len() == 2 => {
+                upper = (u16::from_be_bytes([payload[0], payload[1]]) as u64) << 16
+            }
+            0x02 | 0x04 => bail!(
+                "invalid extended address length {} on line {}",
+                payload.len(),
+                i + 1
+            ),
+            // Start segment and start linear address.
+            0x03 | 0x05 => {}
+            kind => bail!("invalid record type 0x{:02x} on line {}", kind, i + 1),
+        }
+    }
+    bail!("end of file record missing")
+}
+
+fn parse_srec(data: &[u8]) -> Result<Vec<Segment>> {
+    let text = std::str::from_utf8(data).wrap_err("S-record file is not text")?;
+    let mut segments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, bytes) = line
+            .strip_prefix('S')
+            .and_then(|line| Some((line.get(..1)?, parse_hex_bytes(line.get(1..)?)?)))
+            .filter(|(_, bytes)| bytes.len() >= 2 && bytes.len() == bytes[0] as usize + 1)
+            .ok_or_else(|| eyre!("invalid record on line {}", i + 1))?;
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            bail!("checksum mismatch on line {}", i + 1);
+        }
+        let address_len = match kind {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // Header, count and termination records.
+            "0" | "5" | "6" | "7" | "8" | "9" => continue,
+            _ => bail!("invalid record type S{} on line {}", kind, i + 1),
+        };
+        let record = &bytes[1..bytes.len() - 1];
+        if record.len() < address_len {
+            bail!("invalid record on line {}", i + 1);
+        }
+        let (address, payload) = record.split_at(address_len);
+        let address = address
+            .iter()
+            .fold(0u64, |address, b| address << 8 | *b as u64);
+        push(&mut segments, address, payload);
+    }
+    Ok(segments)
+}
+
+pub(crate) fn parse_elf(data: &[u8]) -> Result<Vec<Segment>> {
+    use ram_probe_rs::elf::object::elf::{FileHeader32, PT_LOAD};
+    use ram_probe_rs::elf::object::read::elf::{FileHeader as _, ProgramHeader as _};
+    use ram_probe_rs::elf::object::Endianness;
+
+    let header = FileHeader32::<Endianness>::parse(data)?;
+    let endian = header.endian()?;
+    let mut segments = Vec::new();
+    for program_header in header.program_headers(endian, data)? {
+        if program_header.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        let data = program_header
+            .data(endian, data)
+            .map_err(|()| eyre!("invalid load segment data"))?;
+        if data.is_empty() {
+            continue;
+        }
+        // The physical address is where the segment is stored.
+        let address = program_header.p_paddr(endian) as u64;
+        log::debug!(
+            "ELF load segment at 0x{:08x} ({} bytes)",
+            address,
+            data.len()
+        );
+        segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+fn parse_uf2(data: &[u8]) -> Result<Vec<Segment>> {
+    if data.len() % UF2_BLOCK_LEN != 0 {
+        bail!("UF2 file size is not a multiple of {}", UF2_BLOCK_LEN);
+    }
+    let mut segments = Vec::new();
+    for (i, block) in data.chunks_exact(UF2_BLOCK_LEN).enumerate() {
+        let word =
+            |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        if word(0) != UF2_MAGIC_START0
+            || word(4) != UF2_MAGIC_START1
+            || word(UF2_BLOCK_LEN - 4) != UF2_MAGIC_END
+        {
+            bail!("invalid magic in block {}", i);
+        }
+        if word(8) & UF2_FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+        let address = word(12) as u64;
+        let len = word(16) as usize;
+        if len > 476 {
+            bail!("invalid payload size {} in block {}", len, i);
+        }
+        let payload = &block[32..32 + len];
+        push(&mut segments, address, payload);
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+
+    const FLASH_SIZE: usize = 0x40;
+    const BASE_ADDRESS: u64 = 0x9000_0000;
+
+    fn hex(bytes: &[u8]) -> String {
+        use std::fmt::Write as _;
+        bytes.iter().fold(String::new(), |mut hex, b| {
+            write!(hex, "{:02X}", b).unwrap();
+            hex
+        })
+    }
+
+    /// An Intel HEX record line.
+    fn ihex(address: u16, kind: u8, payload: &[u8]) -> String {
+        let mut bytes = vec![payload.len() as u8];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(payload);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex(&bytes))
+    }
+
+    /// An S3 record line.
+    fn srec(address: u32, payload: &[u8]) -> String {
+        let mut bytes = vec![payload.len() as u8 + 5];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.extend_from_slice(payload);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(!sum);
+        format!("S3{}\n", hex(&bytes))
+    }
+
+    /// A UF2 block.
+    fn uf2(flags: u32, address: u32, payload: &[u8]) -> Vec<u8> {
+        let mut block = vec![0; UF2_BLOCK_LEN];
+        for (offset, value) in [
+            (0, UF2_MAGIC_START0),
+            (4, UF2_MAGIC_START1),
+            (8, flags),
+            (12, address),
+            (16, payload.len() as u32),
+            (UF2_BLOCK_LEN - 4, UF2_MAGIC_END),
+        ] {
+            block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        block[32..32 + payload.len()].copy_from_slice(payload);
+        block
+    }
+
+    /// Erased flash, with `data` at each offset.
+    fn image(len: usize, data: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut image = vec![ERASED; len];
+        for (offset, data) in data {
+            image[*offset..offset + data.len()].copy_from_slice(data);
+        }
+        image
+    }
+
+    fn decode_err(data: impl Into<Vec<u8>>, format: DataFormat) -> String {
+        decode(data.into(), format, None, FLASH_SIZE, &(0..FLASH_SIZE))
+            .unwrap_err()
+            .root_cause()
+            .to_string()
+    }
+
+    #[test]
+    fn ihex_rebases_and_fills_gaps() {
+        let data = [
+            ihex(0, 0x04, &[0x90, 0x00]),
+            ihex(0x0010, 0x00, &[1, 2, 3, 4]),
+            ihex(0x0020, 0x00, &[5, 6]),
+            ihex(0, 0x05, &[0x90, 0x00, 0x00, 0x00]),
+            ihex(0, 0x01, &[]),
+        ]
+        .concat();
+
+        let decoded = decode(
+            data.clone().into(),
+            DataFormat::Ihex,
+            Some(BASE_ADDRESS),
+            FLASH_SIZE,
+            &(0..0x30),
+        )
+        .unwrap();
+        assert_eq!(
+            decoded,
+            image(0x30, &[(0x10, &[1, 2, 3, 4]), (0x20, &[5, 6])])
+        );
+
+        // Data outside the range is ignored.
+        let decoded = decode(
+            data.into(),
+            DataFormat::Ihex,
+            Some(BASE_ADDRESS),
+            FLASH_SIZE,
+            &(0x12..0x22),
+        )
+        .unwrap();
+        assert_eq!(decoded, image(0x10, &[(0, &[3, 4]), (0x0e, &[5, 6])]));
+    }
+
+    #[test]
+    fn ihex_extended_segment_address() {
+        let data = [
+            ihex(0, 0x02, &[0x00, 0x01]),
+            ihex(0x0000, 0x00, &[1, 2]),
+            ihex(0, 0x01, &[]),
+        ]
+        .concat();
+        let decoded = decode(data.into(), DataFormat::Ihex, None, FLASH_SIZE, &(0..0x20)).unwrap();
+        assert_eq!(decoded, image(0x20, &[(0x10, &[1, 2])]));
+    }
+
+    #[test]
+    fn ihex_rejects_invalid_records() {
+        let mut data = ihex(0x0000, 0x00, &[1, 2]);
+        data.replace_range(11..13, "00");
+        assert_eq!(
+            decode_err(data, DataFormat::Ihex),
+            "checksum mismatch on line 1"
+        );
+
+        let data = [ihex(0, 0x04, &[0x00, 0x00, 0x00]), ihex(0, 0x01, &[])].concat();
+        assert_eq!(
+            decode_err(data, DataFormat::Ihex),
+            "invalid extended address length 3 on line 1"
+        );
+
+        let data = [ihex(0, 0x06, &[]), ihex(0, 0x01, &[])].concat();
+        assert_eq!(
+            decode_err(data, DataFormat::Ihex),
+            "invalid record type 0x06 on line 1"
+        );
+
+        let data = ihex(0x0000, 0x00, &[1, 2]);
+        assert_eq!(
+            decode_err(data, DataFormat::Ihex),
+            "end of file record missing"
+        );
+    }
+
+    #[test]
+    fn srec_rebases_and_fills_gaps() {
+        let data = [
+            "S00600004844521B\n".to_owned(),
+            srec(0x9000_0004, &[1, 2]),
+            srec(0x9000_0010, &[3]),
+            "S70500000000FA\n".to_owned(),
+        ]
+        .concat();
+        let decoded = decode(
+            data.into(),
+            DataFormat::Srec,
+            Some(BASE_ADDRESS),
+            FLASH_SIZE,
+            &(0..0x20),
+        )
+        .unwrap();
+        assert_eq!(decoded, image(0x20, &[(0x04, &[1, 2]), (0x10, &[3])]));
+    }
+
+    #[test]
+    fn srec_rejects_bad_checksum() {
+        let mut data = srec(0x0000_0000, &[1, 2]);
+        data.replace_range(12..14, "00");
+        assert_eq!(
+            decode_err(data, DataFormat::Srec),
+            "checksum mismatch on line 1"
+        );
+    }
+
+    #[test]
+    fn elf_rebases_load_segments() {
+        let mut data = Vec::new();
+        Format::Elf
+            .write(&mut data, &[1, 2, 3], BASE_ADDRESS as u32 + 0x08)
+            .unwrap();
+        let decoded = decode(
+            data,
+            DataFormat::Elf,
+            Some(BASE_ADDRESS),
+            FLASH_SIZE,
+            &(0..0x10),
+        )
+        .unwrap();
+        assert_eq!(decoded, image(0x10, &[(0x08, &[1, 2, 3])]));
+    }
+
+    #[test]
+    fn uf2_skips_blocks_not_in_main_flash() {
+        let data = [
+            uf2(0, 0x0000_0010, &[1, 2]),
+            uf2(UF2_FLAG_NOT_MAIN_FLASH, 0x0000_0000, &[3]),
+            uf2(0, 0x0000_0012, &[4]),
+        ]
+        .concat();
+        let decoded = decode(data, DataFormat::Uf2, None, FLASH_SIZE, &(0..0x20)).unwrap();
+        assert_eq!(decoded, image(0x20, &[(0x10, &[1, 2, 4])]));
+
+        let mut data = uf2(0, 0, &[1]);
+        data[UF2_BLOCK_LEN - 1] = 0;
+        assert_eq!(
+            decode_err(data, DataFormat::Uf2),
+            "invalid magic in block 0"
+        );
+    }
+
+    #[test]
+    fn fit_pads_to_chunk() {
+        let mut range = 0x100..0x500;
+        let data = fit(vec![1; 0x150], &mut range, 0x100, Some(Pad::Chunk), false).unwrap();
+        assert_eq!(range, 0x100..0x300);
+        assert_eq!(data, image(0x200, &[(0, &[1; 0x150])]));
+
+        // Data that ends on a chunk boundary isn't padded.
+        let mut range = 0x100..0x500;
+        let data = fit(vec![1; 0x200], &mut range, 0x100, Some(Pad::Chunk), false).unwrap();
+        assert_eq!(range, 0x100..0x300);
+        assert_eq!(data, [1; 0x200]);
+    }
+
+    #[test]
+    fn fit_pads_to_flash() {
+        let mut range = 0x100..0x500;
+        let data = fit(vec![1; 0x150], &mut range, 0x100, Some(Pad::Flash), false).unwrap();
+        assert_eq!(range, 0x100..0x500);
+        assert_eq!(data, image(0x400, &[(0, &[1; 0x150])]));
+    }
+
+    #[test]
+    fn fit_truncates() {
+        let mut range = 0x100..0x300;
+        let data = [vec![1; 0x200], vec![2; 0x10]].concat();
+        let data = fit(data, &mut range, 0x100, None, true).unwrap();
+        assert_eq!(range, 0x100..0x300);
+        assert_eq!(data, [1; 0x200]);
+    }
+
+    #[test]
+    fn fit_rejects_wrong_size() {
+        let mut range = 0x100..0x300;
+        let err = fit(vec![1; 0x201], &mut range, 0x100, Some(Pad::Flash), false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "data file is larger than the range 0x00000100..0x00000300 (513 > 512 bytes)"
+        );
+
+        let err = fit(vec![1; 0x1ff], &mut range, 0x100, None, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "data file is smaller than the range 0x00000100..0x00000300 (511 < 512 bytes)"
+        );
+
+        let err = fit(Vec::new(), &mut range, 0x100, Some(Pad::Chunk), false).unwrap_err();
+        assert_eq!(err.to_string(), "data file is empty");
+        assert_eq!(range, 0x100..0x300);
+    }
+
+    #[test]
+    fn rejects_data_outside_flash() {
+        let data = [ihex(0x003e, 0x00, &[1, 2, 3, 4]), ihex(0, 0x01, &[])].concat();
+        assert!(decode_err(data, DataFormat::Ihex)
+            .starts_with("data at 0x0000003e..0x00000042 is outside the flash"));
+
+        // Below the base address.
+        let data = srec(0x8fff_fffe, &[1, 2]);
+        let err = decode(
+            data.into(),
+            DataFormat::Srec,
+            Some(BASE_ADDRESS),
+            FLASH_SIZE,
+            &(0..FLASH_SIZE),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("data at 0x8ffffffe..0x90000000 is outside the flash"));
+    }
+}
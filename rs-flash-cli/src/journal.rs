@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A sidecar journal of completed chunks, to resume interrupted dumps and
+//! loads.
+//!
+//! The journal is a text file. The first line describes the run, and each
+//! following line is the offset and checksum of a completed chunk.
+
+use color_eyre::eyre::{bail, Context as _, Result};
+use color_eyre::{Section as _, SectionExt as _};
+use rs_flash::crc::crc32;
+use rs_flash::Direction;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// The start of the first line.
+const MAGIC: &str = "rs-flash journal 1";
+
+/// The run a journal belongs to. Resuming requires the same run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub direction: Direction,
+    pub range: Range<usize>,
+    pub buffer_size: usize,
+}
+
+impl Header {
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} 0x{:08x} 0x{:08x} 0x{:08x}",
+            MAGIC,
+            self.direction.as_u32(),
+            self.range.start,
+            self.range.end,
+            self.buffer_size
+        )
+    }
+}
+
+/// An open journal, which records completed chunks as they complete.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// The journal path for the dump or load file at `path`.
+    pub fn path_for(path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.journal", path))
+    }
+
+    /// Start a new journal, replacing any existing one.
+    pub fn create(path: PathBuf, header: &Header) -> Result<Self> {
+        Self::write(path, header, &[])
+    }
+
+    /// Continue an existing journal.
+    ///
+    /// Returns the journal and the checksums of the completed chunks, in
+    /// order from the start of the range.
+    pub fn resume(path: PathBuf, header: &Header) -> Result<(Self, Vec<u32>)> {
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err("failed to read journal")
+            .with_section(|| path.display().to_string().header("Path"))?;
+        let mut lines = contents.lines();
+        if lines.next() != Some(header.to_line().as_str()) {
+            bail!(
+                "journal `{}` is for a different run, check `--offset` and `--length`",
+                path.display()
+            );
+        }
+
+        let chunks = header.range.len() / header.buffer_size;
+        let mut checksums = Vec::new();
+        for line in lines {
+            let expected = header.range.start + checksums.len() * header.buffer_size;
+            match line.split_once(' ').and_then(|(offset, crc)| {
+                Some((parse_hex(offset)?, u32::try_from(parse_hex(crc)?).ok()?))
+            }) {
+                Some((offset, crc)) if offset == expected && checksums.len() < chunks => {
+                    checksums.push(crc)
+                }
+                // Most likely a line cut short by the interruption.
+                _ => {
+                    log::warn!("ignoring journal entries from `{}` on", line);
+                    break;
+                }
+            }
+        }
+
+        // Rewrite the journal, so any invalid entries are dropped.
+        let journal = Self::write(path, header, &checksums)?;
+        Ok((journal, checksums))
+    }
+
+    fn write(path: PathBuf, header: &Header, checksums: &[u32]) -> Result<Self> {
+        let mut file = File::create(&path)
+            .wrap_err("failed to create journal")
+            .with_section(|| path.display().to_string().header("Path"))?;
+        writeln!(file, "{}", header.to_line())?;
+        for (i, crc) in checksums.iter().enumerate() {
+            let offset = header.range.start + i * header.buffer_size;
+            writeln!(file, "0x{:08x} 0x{:08x}", offset, crc)?;
+        }
+        file.sync_data()?;
+        Ok(Self { path, file })
+    }
+
+    /// Record the chunk at `offset` as completed.
+    pub fn record(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        writeln!(self.file, "0x{:08x} 0x{:08x}", offset, crc32(data))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Remove the journal, after the run has completed.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path).wrap_err("failed to remove journal")
+    }
+}
+
+/// A file that is synced to storage when flushed.
+///
+/// The runner flushes dumped chunks before recording them in the journal, so
+/// the journal never gets ahead of a dump file wrapped in this.
+pub struct SyncedFile(pub File);
+
+impl Write for SyncedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+/// Check the completed chunks at the start of `file` against their journal
+/// checksums.
+///
+/// Afterwards, the file is positioned right after the completed chunks.
+pub fn check(file: &mut dyn Read, checksums: &[u32], buffer_size: usize) -> Result<()> {
+    let mut buf = vec![0; buffer_size];
+    for (i, crc) in checksums.iter().enumerate() {
+        file.read_exact(&mut buf)
+            .wrap_err("file is shorter than the journal")?;
+        if crc32(&buf) != *crc {
+            bail!(
+                "file has changed since the journal was written (chunk at file offset 0x{:08x})",
+                i * buffer_size
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parse a hexadecimal integer prefixed with `0x`.
+fn parse_hex(value: &str) -> Option<usize> {
+    usize::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER_SIZE: usize = 0x100;
+
+    fn header() -> Header {
+        Header {
+            direction: Direction::Load,
+            range: 0x1000..0x1400,
+            buffer_size: BUFFER_SIZE,
+        }
+    }
+
+    /// A journal path in the temporary directory, which doesn't exist yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rs-flash-{}-{}.journal", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn chunk(value: u8) -> Vec<u8> {
+        vec![value; BUFFER_SIZE]
+    }
+
+    #[test]
+    fn records_and_resumes() {
+        let path = temp_path("resume");
+        let mut journal = Journal::create(path.clone(), &header()).unwrap();
+        journal.record(0x1000, &chunk(1)).unwrap();
+        journal.record(0x1100, &chunk(2)).unwrap();
+        drop(journal);
+
+        let (journal, checksums) = Journal::resume(path.clone(), &header()).unwrap();
+        assert_eq!(checksums, [crc32(&chunk(1)), crc32(&chunk(2))]);
+        let data = [chunk(1), chunk(2), chunk(3)].concat();
+        let mut file = data.as_slice();
+        check(&mut file, &checksums, BUFFER_SIZE).unwrap();
+        // Positioned right after the completed chunks.
+        assert_eq!(file, chunk(3));
+
+        journal.finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn drops_cut_off_entries() {
+        let path = temp_path("cut-off");
+        let contents = format!(
+            "{}\n0x00001000 0x{:08x}\n0x0000110",
+            header().to_line(),
+            crc32(&chunk(1))
+        );
+        std::fs::write(&path, contents).unwrap();
+
+        let (journal, checksums) = Journal::resume(path.clone(), &header()).unwrap();
+        assert_eq!(checksums, [crc32(&chunk(1))]);
+        drop(journal);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_different_run() {
+        let path = temp_path("different-run");
+        Journal::create(path.clone(), &header()).unwrap();
+
+        let other = Header {
+            range: 0x1000..0x1200,
+            ..header()
+        };
+        let err = Journal::resume(path.clone(), &other).err().unwrap();
+        assert!(
+            err.to_string()
+                .ends_with("is for a different run, check `--offset` and `--length`"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_changed_file() {
+        let checksums = [crc32(&chunk(1)), crc32(&chunk(2))];
+
+        let data = [chunk(1), chunk(9)].concat();
+        let err = check(&mut data.as_slice(), &checksums, BUFFER_SIZE).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file has changed since the journal was written (chunk at file offset 0x00000100)"
+        );
+
+        let data = chunk(1);
+        let err = check(&mut data.as_slice(), &checksums, BUFFER_SIZE).unwrap_err();
+        assert_eq!(err.to_string(), "file is shorter than the journal");
+    }
+}
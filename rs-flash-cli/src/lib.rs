@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The host side of rs-flash, for driving RAM programs from other tools.
+//!
+//! The `rs-flash` binary is a thin CLI over this crate. To dump, load, verify
+//! or hash flash programmatically, parse the RAM program with
+//! [`elf::parse_elf`], start it on a probe session with
+//! [`target::ProbeTarget::new`], and run a [`run::FlashRunner`]. Data is
+//! loaded, verified or hashed from any [`std::io::Read`], and dumped to any
+//! [`std::io::Write`].
+//!
+//! ```no_run
+//! use rs_flash_cli::elf::parse_elf;
+//! use rs_flash_cli::ram_probe_rs::probe_rs::config::get_target_by_name;
+//! use rs_flash_cli::ram_probe_rs::session::{connect, ProbeArgs};
+//! use rs_flash_cli::rs_flash::{Erase, Params};
+//! use rs_flash_cli::run::{FlashData, FlashRunner, RunnerOpts, Skip};
+//! use rs_flash_cli::target::ProbeTarget;
+//!
+//! # fn dump(probe: &ProbeArgs) -> color_eyre::Result<Vec<u8>> {
+//! let target = get_target_by_name(&probe.chip)?;
+//! let elf = std::fs::read("dump-spi-flash.elf")?;
+//! let program = parse_elf(&elf, &target)?;
+//! let flash_table = program.flash_table.clone();
+//! let range = flash_table.range(0, None)?;
+//! let params = Params {
+//!     offset: range.start,
+//!     length: range.len(),
+//!     flags: 0,
+//!     erase: Erase::None,
+//! };
+//!
+//! let mut session = connect(probe, target)?;
+//! let opts = program.defmt_opts();
+//! let target = ProbeTarget::new(&mut session, &opts, &flash_table, &params)?;
+//! let skip = Skip::new(flash_table.sector_size);
+//! let mut dump = Vec::new();
+//! let mut runner = FlashRunner::new(
+//!     target,
+//!     flash_table,
+//!     FlashData::Dump(&mut dump),
+//!     params,
+//!     RunnerOpts::default(),
+//!     skip,
+//! );
+//! runner.run()?;
+//! # Ok(dump)
+//! # }
+//! ```
+
+pub mod elf;
+pub mod format;
+pub mod image;
+pub mod journal;
+pub mod progress;
+pub mod run;
+#[cfg(test)]
+mod sim;
+pub mod target;
+
+pub use ram_probe_rs;
+pub use rs_flash;
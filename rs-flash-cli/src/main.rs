@@ -1,15 +1,19 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-mod elf;
-mod run;
-
 use color_eyre::eyre::{bail, Context as _, Result};
 use color_eyre::{Section as _, SectionExt as _};
 use ram_probe_rs::probe_rs::config::get_target_by_name;
-use ram_probe_rs::run::DefmtOpts;
 use ram_probe_rs::session::{connect, ProbeArgs};
-use rs_flash::Direction;
-use run::{FlashData, FlashRunner};
+use rs_flash::{Direction, Erase, Params};
+use rs_flash_cli::elf;
+use rs_flash_cli::format::{self, Format};
+use rs_flash_cli::image::{self, DataFormat, Pad};
+use rs_flash_cli::journal::{self, Journal};
+use rs_flash_cli::progress::{self, Progress};
+use rs_flash_cli::run::{FlashData, FlashRunner, RunnerOpts, Skip};
+use rs_flash_cli::target::ProbeTarget;
+use std::io::{Read, Write as _};
+use std::path::Path;
 use std::time::Duration;
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -21,28 +25,133 @@ struct Args {
     #[clap(flatten)]
     probe: ProbeArgs,
 
-    /// When running a loader program, the data to load
+    /// When running a loader, verifier or hashing program, the data to load, verify or hash
     #[clap(long)]
     data: Option<String>,
 
-    /// The timeout for the erase step, in seconds
+    /// When running a dumping program, the file to dump to, or `-` for stdout [default: dump.bin]
+    #[clap(long)]
+    output: Option<String>,
+
+    /// Overwrite the dump file if it already exists
+    #[clap(long)]
+    force: bool,
+
+    /// The format of the dump file [default: bin]
+    #[clap(long, value_enum)]
+    format: Option<Format>,
+
+    /// The address of the start of the flash in the dump or data file [default: 0]
+    ///
+    /// Only for formats with addresses, not `bin`. Prefix with `0x` for hexadecimal.
+    #[clap(long, value_parser = parse_int::<usize>)]
+    base_address: Option<usize>,
+
+    /// The format of the data file [default: detected from the contents or extension]
+    #[clap(long, value_enum)]
+    data_format: Option<DataFormat>,
+
+    /// Fill a data file that is smaller than the range with erased flash (0xff)
+    ///
+    /// Either up to the end of the chunk, which also shortens the range, or up
+    /// to the end of the range (`--length`, or the end of the flash).
+    #[clap(long, value_enum)]
+    pad: Option<Pad>,
+
+    /// Only use the start of a data file that is larger than the range
+    #[clap(long)]
+    truncate: bool,
+
+    /// The offset into the flash to start at, in bytes
+    ///
+    /// Must be a multiple of the buffer size. Prefix with `0x` for hexadecimal.
+    #[clap(long, default_value_t = 0, value_parser = parse_int::<usize>)]
+    offset: usize,
+
+    /// The length to dump or load, in bytes [default: to the end of the flash]
+    ///
+    /// Must be a multiple of the buffer size. Prefix with `0x` for hexadecimal.
+    #[clap(long, value_parser = parse_int::<usize>)]
+    length: Option<usize>,
+
+    /// How to erase flash when loading [default: chunk]
+    #[clap(long, value_enum)]
+    erase: Option<EraseArg>,
+
+    /// Program-specific operation flags, passed through to the target
+    ///
+    /// Prefix with `0x` for hexadecimal.
+    #[clap(long, default_value_t = 0, value_parser = parse_int::<u32>)]
+    flags: u32,
+
+    /// Don't skip writing chunks that are entirely erased (0xff) when loading
+    #[clap(long)]
+    no_skip_erased: bool,
+
+    /// A previous dump of the same range, to only load the sectors that changed
+    ///
+    /// Requires `--erase chunk`.
+    #[clap(long)]
+    diff_against: Option<String>,
+
+    /// How often to re-read a dumped chunk that doesn't match its checksum
+    #[clap(long, default_value_t = 3)]
+    retries: usize,
+
+    /// Continue an interrupted dump or load from its journal
+    ///
+    /// Dumps record completed chunks in a journal next to the dump file
+    /// (`<file>.journal`), which is removed once they complete. Loads only
+    /// record a journal next to the data file with `--resume`, so pass it to
+    /// the first run too.
+    #[clap(long)]
+    resume: bool,
+
+    /// The timeout for erasing before loading (`--erase range` or `--erase chip`), in seconds
     #[clap(long, default_value_t = 60 * 5)]
     erase_timeout: u64,
 
-    /// The timeout for the dump/load steps, in seconds
+    /// The timeout for each chunk, including erasing it (`--erase chunk`), in seconds
     #[clap(long, default_value_t = 10)]
     timeout: u64,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum EraseArg {
+    /// Don't erase, the flash is already erased
+    None,
+    /// Erase only the requested range, before loading
+    Range,
+    /// Erase the entire chip, before loading
+    Chip,
+    /// Erase only the sectors of each chunk, right before writing it
+    Chunk,
+}
+
+impl From<EraseArg> for Erase {
+    fn from(value: EraseArg) -> Self {
+        match value {
+            EraseArg::None => Self::None,
+            EraseArg::Range => Self::Range,
+            EraseArg::Chip => Self::Chip,
+            EraseArg::Chunk => Self::Chunk,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
-    try_init_logging()?;
+    let progress = Progress::new();
+    try_init_logging(progress.clone())?;
 
     use clap::Parser as _;
     let args = Args::parse();
 
-    let erase_timeout = Duration::from_secs(args.erase_timeout);
-    let timeout = Duration::from_secs(args.timeout);
+    let runner_opts = RunnerOpts {
+        timeout: Duration::from_secs(args.timeout),
+        erase_timeout: Duration::from_secs(args.erase_timeout),
+        retries: args.retries,
+    };
 
     log::debug!("target `{}`", args.probe.chip);
     let target = get_target_by_name(&args.probe.chip)?;
@@ -52,41 +161,337 @@ fn main() -> Result<()> {
         .wrap_err("failed to read ELF file")
         .with_section(|| args.path.clone().header("Path"))?;
 
-    let (segments, rtt_addr, vector_table, flash_table, defmt) = elf::parse_elf(&data, &target)?;
+    let program = elf::parse_elf(&data, &target)?;
+    let flash_table = program.flash_table.clone();
 
-    let opts = DefmtOpts::with_defaults(&segments, rtt_addr, &vector_table, &defmt);
+    let mut range = flash_table.range(args.offset, args.length)?;
+    // Read the data up front, to check it fits the range before connecting.
+    let file_data = match args.data.as_deref() {
+        Some(path) if flash_table.direction != Direction::Dump => {
+            let base_address = args.base_address.map(|address| address as u64);
+            let data = image::read(
+                path,
+                args.data_format,
+                base_address,
+                flash_table.flash_size,
+                &range,
+            )?;
+            Some(image::fit(
+                data,
+                &mut range,
+                flash_table.buffer_size,
+                args.pad,
+                args.truncate,
+            )?)
+        }
+        _ => None,
+    };
+    let mut params = Params {
+        offset: range.start,
+        length: range.len(),
+        flags: args.flags,
+        erase: match args.erase {
+            Some(erase) => erase.into(),
+            None if flash_table.params_addr.is_some() => Erase::Chunk,
+            // Programs without parameters always erase the entire chip.
+            None => Erase::Chip,
+        },
+    };
+    log::debug!("{:?}", params);
+    if flash_table.params_addr.is_none() {
+        let is_default = range == (0..flash_table.flash_size) && args.flags == 0;
+        if !is_default || args.erase.is_some() {
+            bail!(
+                "ELF file (flash table version {}) doesn't support `--offset`, `--length`, `--flags` or `--erase`",
+                flash_table.version
+            );
+        }
+    }
 
-    let flash_data = match flash_table.direction {
+    let is_dump = flash_table.direction == Direction::Dump;
+    check_direction_args(&args, flash_table.direction)?;
+    let output = args.output.as_deref().unwrap_or(DUMP_PATH);
+    let format = args.format.unwrap_or_default();
+    // Stream the dump to stdout, e.g. to pipe it into another tool.
+    let to_stdout = output == "-";
+    if is_dump && to_stdout && args.resume {
+        bail!("`--resume` can't resume a dump to stdout");
+    }
+    // Resuming continues the existing dump file.
+    if is_dump && !to_stdout && !args.resume && !args.force && Path::new(output).exists() {
+        bail!(
+            "dump file `{}` already exists, use `--force` to overwrite it",
+            output
+        );
+    }
+    if is_dump && format == Format::Bin && args.base_address.is_some() {
+        bail!("`--base-address` requires `--format ihex`, `srec` or `elf`");
+    }
+    let base_address = args.base_address.unwrap_or(0) as u64;
+    if is_dump && base_address + range.end as u64 > 1 << 32 {
+        bail!(
+            "dump at base address 0x{:08x} exceeds the 32-bit address space",
+            base_address
+        );
+    }
+    let address = (base_address + range.start as u64) as u32;
+    // Other formats are converted from a raw dump once the dump completes.
+    let raw_path = match format {
+        Format::Bin => output.to_owned(),
+        _ => format!("{}.part", output),
+    };
+    let convert = || match format {
+        Format::Bin => Ok(()),
+        _ => format::convert(
+            raw_path.as_ref(),
+            output.as_ref(),
+            format,
+            address,
+            range.len(),
+        ),
+    };
+
+    let opts = program.defmt_opts();
+
+    let direction = flash_table.direction;
+    let is_load = matches!(direction, Direction::Load | Direction::LoadVerify);
+    let mut dump_file = match direction {
+        Direction::Dump if to_stdout => None,
         Direction::Dump => {
-            if args.data.is_some() {
-                bail!("`--data` is specified, but ELF file dumps data");
-            }
-            let file = std::fs::File::create("dump.bin").wrap_err("failed to open dump file")?;
-            FlashData::Dump(file)
+            // Keep the completed chunks when resuming.
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(!args.resume)
+                .open(&raw_path)
+                .wrap_err("failed to open dump file")
+                .with_section(|| raw_path.clone().header("Path"))?;
+            Some(file)
+        }
+        _ if file_data.is_some() => None,
+        Direction::Load | Direction::LoadVerify => {
+            bail!("`--data` not specified, but ELF file loads data")
         }
-        Direction::Load => match args.data.as_deref() {
+        Direction::Verify => bail!("`--data` not specified, but ELF file verifies data"),
+        Direction::Hash => bail!("`--data` not specified, but ELF file hashes data"),
+    };
+    // The data to load, verify or hash.
+    let mut source = file_data.as_deref().unwrap_or_default();
+
+    let mut skip = if flash_table.capabilities & rs_flash::table::CAP_SKIP != 0 {
+        let diff_against = match args.diff_against.as_deref() {
+            Some(_) if !is_load => {
+                bail!("`--diff-against` is specified, but ELF file doesn't load data")
+            }
+            // Erasing up front would invalidate the previous dump, and
+            // without erasing, changed sectors can't be written.
+            Some(_) if params.erase != Erase::Chunk => {
+                bail!("`--diff-against` requires `--erase chunk`")
+            }
             Some(path) => {
-                let file = std::fs::File::open(path).wrap_err("failed to open load file")?;
-                FlashData::Load(file)
+                let file = std::fs::File::open(path)
+                    .wrap_err("failed to open previous dump file")
+                    .with_section(|| path.to_owned().header("Path"))?;
+                // Check up front, instead of failing after flash was changed.
+                let len = file.metadata()?.len();
+                if len < range.len() as u64 {
+                    bail!(
+                        "previous dump file `{}` is {} bytes, but the range is {} bytes",
+                        path,
+                        len,
+                        range.len()
+                    );
+                }
+                Some(file)
             }
-            None => bail!("`--data` not specified, but ELF file loads data"),
-        },
+            None => None,
+        };
+        let mut skip = Skip::new(flash_table.sector_size);
+        skip.erased = !args.no_skip_erased;
+        skip.diff_against = diff_against;
+        skip.partial = flash_table.capabilities & rs_flash::table::CAP_SECTORS != 0
+            && flash_table.sectors_addr.is_some();
+        skip
+    } else {
+        if args.diff_against.is_some() {
+            bail!("ELF file doesn't support `--diff-against`, rebuild it with a newer rs-flash");
+        }
+        log::debug!("ELF file doesn't support skipping chunks");
+        Skip::new(flash_table.sector_size)
+    };
+
+    let journal_path = match direction {
+        Direction::Dump if to_stdout => None,
+        Direction::Dump => Some(Journal::path_for(&raw_path)),
+        // Only leave a journal next to the data file when asked to.
+        Direction::Load | Direction::LoadVerify if args.resume => {
+            args.data.as_deref().map(Journal::path_for)
+        }
+        Direction::Load | Direction::LoadVerify | Direction::Verify | Direction::Hash => None,
+    };
+    let header = journal::Header {
+        direction,
+        range: range.clone(),
+        buffer_size: flash_table.buffer_size,
+    };
+    if args.resume && journal_path.is_some() {
+        if flash_table.params_addr.is_none() {
+            bail!(
+                "ELF file (flash table version {}) doesn't support `--resume`",
+                flash_table.version
+            );
+        }
+        // Erasing the chip again would lose the completed chunks.
+        if params.erase == Erase::Chip {
+            bail!("`--resume` can't erase the entire chip, use `--erase range` or `--erase chunk`");
+        }
+    }
+    let journal = match journal_path {
+        // The first load with `--resume` starts the journal.
+        Some(path) if args.resume && (is_dump || path.exists()) => {
+            let (journal, checksums) = Journal::resume(path, &header)?;
+            let done = checksums.len() * flash_table.buffer_size;
+            let data: &mut dyn Read = match &mut dump_file {
+                Some(file) => file,
+                None => &mut source,
+            };
+            journal::check(data, &checksums, flash_table.buffer_size)
+                .wrap_err("failed to resume")?;
+            if let Some(file) = &dump_file {
+                // Drop any partially written chunk.
+                file.set_len(done as u64)?;
+            }
+            if let Some(file) = &mut skip.diff_against {
+                use std::io::Seek as _;
+                file.seek(std::io::SeekFrom::Start(done as u64))?;
+            }
+            if done == range.len() {
+                log::info!("nothing to resume, all chunks are complete");
+                journal.finish()?;
+                return convert();
+            }
+            log::info!(
+                "resuming at 0x{:08x} ({} / {} chunks complete)",
+                range.start + done,
+                checksums.len(),
+                range.len() / flash_table.buffer_size
+            );
+            params.offset += done;
+            params.length -= done;
+            Some(journal)
+        }
+        Some(path) => Some(Journal::create(path, &header)?),
+        None if args.resume => {
+            bail!("`--resume` is specified, but ELF file doesn't dump or load data")
+        }
+        None => None,
+    };
+
+    let mut dump_file = dump_file.map(journal::SyncedFile);
+    let mut stdout = std::io::stdout();
+    // Other formats are written to stdout once the dump completes.
+    let mut dumped = Vec::new();
+    let flash_data = match (&mut dump_file, direction) {
+        (Some(file), _) => FlashData::Dump(file),
+        (None, Direction::Dump) if format == Format::Bin => FlashData::Dump(&mut stdout),
+        (None, Direction::Dump) => FlashData::Dump(&mut dumped),
+        (None, Direction::Load) => FlashData::Load(&mut source),
+        (None, Direction::LoadVerify) => FlashData::LoadVerify(&mut source),
+        (None, Direction::Verify) => FlashData::Verify(&mut source),
+        (None, Direction::Hash) => FlashData::Hash(&mut source),
     };
 
     let mut session = connect(&args.probe, target)?;
-    let mut runner = FlashRunner::new(
-        &mut session,
-        &opts,
-        flash_table,
-        flash_data,
-        timeout,
-        erase_timeout,
-    )?;
-    runner.run(&mut session)?;
+    let target = ProbeTarget::new(&mut session, &opts, &flash_table, &params)?;
+    let mut runner = FlashRunner::new(target, flash_table, flash_data, params, runner_opts, skip);
+    if let Some(journal) = journal {
+        runner = runner.with_journal(journal);
+    }
+    runner = runner.with_progress(progress);
+    runner.run()?;
+
+    if is_load {
+        let skip = runner.skip();
+        log::info!(
+            "{} sector(s) rewritten, {} skipped (erased), {} already matched",
+            skip.rewritten_sectors,
+            skip.erased_sectors,
+            skip.matched_sectors
+        );
+    }
+    if direction == Direction::Verify {
+        let mismatches = runner.mismatches();
+        for range in mismatches {
+            log::error!(
+                "mismatch at 0x{:08x}..0x{:08x} ({} bytes)",
+                range.start,
+                range.end,
+                range.len()
+            );
+        }
+        if !mismatches.is_empty() {
+            bail!("verify failed, {} range(s) differ", mismatches.len());
+        }
+        log::info!("verify ok");
+    }
+    if direction == Direction::Hash {
+        let regions = runner.regions();
+        for (range, matched) in regions {
+            if *matched {
+                log::info!("match at 0x{:08x}..0x{:08x}", range.start, range.end);
+            } else {
+                log::error!("mismatch at 0x{:08x}..0x{:08x}", range.start, range.end);
+            }
+        }
+        let mismatches = regions.iter().filter(|(_, matched)| !matched).count();
+        if mismatches > 0 {
+            bail!("hash failed, {} region(s) differ", mismatches);
+        }
+        log::info!("hash ok");
+    }
+    if is_dump {
+        match (to_stdout, format) {
+            // Already streamed.
+            (true, Format::Bin) => {}
+            (true, _) => {
+                format.write(&mut stdout, &dumped, address)?;
+                stdout.flush()?;
+            }
+            (false, _) => convert()?,
+        }
+    }
+    Ok(())
+}
+
+/// Check the arguments that only apply to dumping, or to the other directions,
+/// match the program's `direction`.
+fn check_direction_args(args: &Args, direction: Direction) -> Result<()> {
+    if direction == Direction::Dump {
+        if args.data.is_some() || args.data_format.is_some() || args.pad.is_some() || args.truncate
+        {
+            bail!("`--data`, `--data-format`, `--pad` or `--truncate` is specified, but ELF file dumps data");
+        }
+    } else if args.output.is_some() || args.format.is_some() || args.force {
+        bail!("`--output`, `--format` or `--force` is specified, but ELF file doesn't dump data");
+    }
     Ok(())
 }
 
-fn try_init_logging() -> Result<()> {
+/// The default file to dump to.
+const DUMP_PATH: &str = "dump.bin";
+
+/// Parse an integer, which may be prefixed with `0x` for hexadecimal.
+fn parse_int<T: TryFrom<u64>>(value: &str) -> Result<T, String> {
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    let value = value.map_err(|e| e.to_string())?;
+    T::try_from(value).map_err(|_| "number too large to fit in target type".to_string())
+}
+
+fn try_init_logging(progress: Progress) -> Result<()> {
     let mut builder = pretty_env_logger::formatted_builder();
     match std::env::var("RUST_LOG") {
         Ok(filters) => {
@@ -105,5 +510,66 @@ fn try_init_logging() -> Result<()> {
             bail!("`RUST_LOG` is not unicode");
         }
     }
-    Ok(builder.try_init()?)
+    // Print log lines, including target output, above the progress bars.
+    let logger = builder.build();
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(progress::Logger::new(logger, progress)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser as _;
+
+    fn args(extra: &[&str]) -> Args {
+        let args = ["rs-flash", "program.elf", "--chip", "STM32F103ZE"];
+        Args::try_parse_from(args.iter().chain(extra)).unwrap()
+    }
+
+    #[test]
+    fn rejects_load_args_when_dumping() {
+        for output in [None, Some("-")] {
+            for extra in [
+                &["--data", "data.bin"][..],
+                &["--data-format", "ihex"],
+                &["--pad", "chunk"],
+                &["--truncate"],
+            ] {
+                let mut args = args(extra);
+                args.output = output.map(str::to_owned);
+                let err = check_direction_args(&args, Direction::Dump).unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "`--data`, `--data-format`, `--pad` or `--truncate` is specified, but ELF file dumps data",
+                    "{:?} {:?}",
+                    output,
+                    extra
+                );
+            }
+        }
+        check_direction_args(&args(&["--output", "-"]), Direction::Dump).unwrap();
+    }
+
+    #[test]
+    fn rejects_dump_args_when_not_dumping() {
+        for extra in [&["--output", "-"][..], &["--format", "ihex"], &["--force"]] {
+            for direction in [
+                Direction::Load,
+                Direction::LoadVerify,
+                Direction::Verify,
+                Direction::Hash,
+            ] {
+                let mut args = args(extra);
+                args.data = Some("data.bin".to_owned());
+                let err = check_direction_args(&args, direction).unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "`--output`, `--format` or `--force` is specified, but ELF file doesn't dump data"
+                );
+            }
+        }
+        check_direction_args(&args(&["--data", "data.bin"]), Direction::Load).unwrap();
+    }
 }
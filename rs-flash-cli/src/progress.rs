@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Progress display, as a progress bar on a terminal, or plain log lines.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use pretty_env_logger::env_logger;
+use std::io::IsTerminal as _;
+use std::time::Duration;
+
+/// The progress bar layout of a transfer phase.
+const TEMPLATE: &str =
+    "{prefix:>7} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta})";
+
+/// The progress bar layout of a phase that doesn't report progress, e.g.
+/// erasing.
+const SPINNER_TEMPLATE: &str = "{prefix:>7} [{elapsed_precise}] {spinner}";
+
+/// The default only logs plain lines.
+#[derive(Clone, Default)]
+pub struct Progress {
+    bars: Option<MultiProgress>,
+}
+
+impl Progress {
+    /// Show progress bars if stdout and stderr are terminals, otherwise log
+    /// plain lines.
+    ///
+    /// Like log lines, the progress bars are drawn on stderr.
+    pub fn new() -> Self {
+        if !std::io::stdout().is_terminal() || !std::io::stderr().is_terminal() {
+            return Self { bars: None };
+        }
+        let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        Self { bars: Some(bars) }
+    }
+
+    /// Run `f` with the progress bars hidden, e.g. to print log lines above
+    /// them.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bars {
+            Some(bars) => bars.suspend(f),
+            None => f(),
+        }
+    }
+
+    /// Start a phase transferring `len` bytes, of which `pos` are already
+    /// done, e.g. loading.
+    ///
+    /// The elapsed time, throughput and estimate only count the bytes
+    /// transferred from now on, so they don't include a preceding erase.
+    pub fn phase(&self, name: &'static str, len: usize, pos: usize) -> Phase {
+        let Some(bars) = &self.bars else {
+            log::info!("{}...", name);
+            return Phase::default();
+        };
+        let bar = bars.add(ProgressBar::new(len as u64));
+        bar.set_style(
+            ProgressStyle::with_template(TEMPLATE)
+                .expect("invalid progress template")
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(name);
+        bar.set_position(pos as u64);
+        bar.reset_elapsed();
+        bar.reset_eta();
+        Phase { bar: Some(bar) }
+    }
+
+    /// Start a phase that doesn't report progress, e.g. erasing.
+    pub fn spinner(&self, name: &'static str) -> Phase {
+        let Some(bars) = &self.bars else {
+            log::info!("{}...", name);
+            return Phase::default();
+        };
+        let bar = bars.add(ProgressBar::new_spinner());
+        bar.set_style(
+            ProgressStyle::with_template(SPINNER_TEMPLATE).expect("invalid progress template"),
+        );
+        bar.set_prefix(name);
+        // Keep the elapsed time ticking without any progress.
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Phase { bar: Some(bar) }
+    }
+
+    /// Start transferring a chunk.
+    pub fn chunk(&self, chunk: usize, chunks: usize, offset: usize) {
+        match &self.bars {
+            Some(_) => log::debug!("chunk {} / {} (at 0x{:08x})", chunk, chunks, offset),
+            None => log::info!("chunk {} / {} (at 0x{:08x})", chunk, chunks, offset),
+        }
+    }
+}
+
+/// The progress of one phase, until it is dropped. The default doesn't
+/// display anything.
+#[derive(Default)]
+pub struct Phase {
+    bar: Option<ProgressBar>,
+}
+
+impl Phase {
+    /// Set the number of bytes transferred.
+    pub fn set_position(&self, count: usize) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(count as u64);
+        }
+    }
+}
+
+impl Drop for Phase {
+    /// Remove the progress bar, once the phase has completed or failed.
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// A logger that prints above the progress bar.
+pub struct Logger {
+    inner: env_logger::Logger,
+    progress: Progress,
+}
+
+impl Logger {
+    pub fn new(inner: env_logger::Logger, progress: Progress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.inner.matches(record) {
+            self.progress.suspend(|| self.inner.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -1,163 +1,1391 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::elf::FlashTable;
-use color_eyre::eyre::{bail, eyre, Result};
-use ram_probe_rs::defmt::DefmtDecoder;
-use ram_probe_rs::probe_rs::rtt::UpChannel;
-use ram_probe_rs::probe_rs::{MemoryInterface as _, Session};
-use ram_probe_rs::run::{init_cpu, setup_rtt, DefmtOpts};
-use std::io::{Read as _, Write as _};
+use crate::journal::Journal;
+use crate::progress::{Phase, Progress};
+use crate::target::Target;
+use color_eyre::eyre::{bail, Context as _, Result};
+use rs_flash::crc::crc32;
+use rs_flash::protocol::{Control, Owner};
+use rs_flash::{Erase, ErrorCode, Params};
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::time::{Duration, Instant};
 
-pub(crate) enum FlashData {
-    Dump(std::fs::File),
-    Load(std::fs::File),
+/// The data to transfer, which must match the direction of the program.
+///
+/// The data to load, verify or hash starts at the start of the range.
+///
+/// Dumped chunks are written in order. The sink is flushed before each chunk
+/// is recorded in the journal, and once the dump completes.
+pub enum FlashData<'a> {
+    Dump(&'a mut dyn Write),
+    Load(&'a mut dyn Read),
+    Verify(&'a mut dyn Read),
+    LoadVerify(&'a mut dyn Read),
+    Hash(&'a mut dyn Read),
 }
 
-pub(crate) struct FlashRunner<'opts> {
-    decoder: DefmtDecoder<'opts>,
-    defmt: UpChannel,
+impl FlashData<'_> {
+    /// The progress phase name of the transfer.
+    fn phase(&self) -> &'static str {
+        match self {
+            Self::Dump(_) => "dump",
+            Self::Load(_) | Self::LoadVerify(_) => "load",
+            Self::Verify(_) => "verify",
+            Self::Hash(_) => "hash",
+        }
+    }
+}
+
+/// The value of erased flash.
+pub const ERASED: u8 = 0xff;
+
+/// Decides which chunks or sectors don't need to be written when loading.
+pub struct Skip {
+    /// Skip chunks that are entirely erased.
+    pub erased: bool,
+    /// A previous dump of the same range, to skip sectors that are unchanged.
+    pub diff_against: Option<std::fs::File>,
+    /// The program can write partial chunks.
+    pub partial: bool,
+    sector_size: usize,
+    /// The number of sectors written.
+    pub rewritten_sectors: usize,
+    /// The number of sectors skipped because they are erased.
+    pub erased_sectors: usize,
+    /// The number of sectors skipped because they are unchanged.
+    pub matched_sectors: usize,
+}
+
+impl Skip {
+    /// Don't skip anything, but count sectors of `sector_size`.
+    pub fn new(sector_size: usize) -> Self {
+        Self {
+            erased: false,
+            diff_against: None,
+            partial: false,
+            sector_size,
+            rewritten_sectors: 0,
+            erased_sectors: 0,
+            matched_sectors: 0,
+        }
+    }
+
+    /// The control command and the mask of sectors to write for loading `buf`.
+    fn command(&mut self, buf: &[u8]) -> Result<(Control, u32)> {
+        let sectors = buf.len() / self.sector_size;
+        let all = u32::MAX >> (32 - sectors);
+        // Always read the previous chunk, to keep the files in step.
+        let changed = match &mut self.diff_against {
+            Some(file) => {
+                let mut previous = vec![0; buf.len()];
+                file.read_exact(&mut previous)
+                    .wrap_err("failed to read previous dump file")?;
+                buf.chunks(self.sector_size)
+                    .zip(previous.chunks(self.sector_size))
+                    .enumerate()
+                    .filter(|(_, (a, b))| a != b)
+                    .fold(0, |mask, (i, _)| mask | 1 << i)
+            }
+            None => all,
+        };
+        if changed == 0 {
+            self.matched_sectors += sectors;
+            return Ok((Control::SkipUnchanged, 0));
+        }
+        if self.erased && buf.iter().all(|&b| b == ERASED) {
+            self.erased_sectors += sectors;
+            return Ok((Control::SkipErased, 0));
+        }
+        if changed != all && self.partial {
+            let rewritten = changed.count_ones() as usize;
+            self.rewritten_sectors += rewritten;
+            self.matched_sectors += sectors - rewritten;
+            return Ok((Control::WriteSectors, changed));
+        }
+        self.rewritten_sectors += sectors;
+        Ok((Control::Full, all))
+    }
+
+    /// Write the chunk `buf` to buffer `slot`, or the parts that need writing,
+    /// and signal the target to commit it.
+    fn load(
+        &mut self,
+        target: &mut impl Target,
+        ft: &FlashTable,
+        slot: usize,
+        buf: &[u8],
+    ) -> Result<()> {
+        let (command, sectors) = self.command(buf)?;
+        match (command, ft.sectors(slot)) {
+            (Control::Full, _) => {
+                // Write chunk to target.
+                target.write(ft.buffer(slot), buf)?;
+            }
+            (Control::WriteSectors, Some(sectors_addr)) => {
+                log::debug!("writing sectors 0b{:b}", sectors);
+                // Write changed sectors to target.
+                for (i, sector) in buf.chunks(self.sector_size).enumerate() {
+                    if sectors & (1 << i) != 0 {
+                        let addr = ft.buffer(slot) + (i * self.sector_size) as u64;
+                        target.write(addr, sector)?;
+                    }
+                }
+                target.write_word_32(sectors_addr, sectors)?;
+            }
+            _ => log::debug!("skipping chunk (command {:?})", command),
+        }
+        // Signal target to write or skip the current chunk.
+        signal(target, ft, slot, Control::Free, command)
+    }
+}
+
+/// Hand buffer `slot` over, by changing its state from `state` to `next`.
+fn signal(
+    target: &mut impl Target,
+    ft: &FlashTable,
+    slot: usize,
+    state: Control,
+    next: Control,
+) -> Result<()> {
+    debug_assert!(
+        state.owner(ft.direction) == Owner::Host && state.can_become(next, ft.direction),
+        "invalid control transition {:?} -> {:?}",
+        state,
+        next
+    );
+    target.write_word_32(ft.control(slot), next.as_u32())
+}
+
+/// Wait for buffer `slot` to become `expected`, or time out.
+///
+/// Fails right away if the target reports an error, sets an invalid state, or
+/// halts.
+fn wait_for_control(
+    target: &mut impl Target,
+    ft: &FlashTable,
+    slot: usize,
+    expected: Control,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if has_control(target, ft, slot, expected)? {
+            return Ok(());
+        }
+        // In the meantime, pump the defmt output.
+        target.pump()?;
+        // Or fail.
+        if target.core_halted()? {
+            // The target may have set the control word and halted since it
+            // was read, e.g. after committing the last chunk, or failing.
+            if has_control(target, ft, slot, expected)? {
+                return Ok(());
+            }
+            target.drain()?;
+            bail!(target.halt_error()?);
+        }
+        // Or time out.
+        if Instant::now() > deadline {
+            bail!("Time out");
+        }
+    }
+}
+
+/// Whether buffer `slot` is `expected`, or still owned by the target.
+///
+/// Fails if the target reports an error, or sets an invalid state.
+fn has_control(
+    target: &mut impl Target,
+    ft: &FlashTable,
+    slot: usize,
+    expected: Control,
+) -> Result<bool> {
+    let control = target.read_word_32(ft.control(slot))?;
+    log::trace!("control: {}", control);
+    match Control::from_u32(control) {
+        Some(state) if state == expected => Ok(true),
+        Some(Control::Error) => bail!(target_error(target, ft)?),
+        Some(state) if state.owner(ft.direction) == Owner::Target => Ok(false),
+        _ => bail!(
+            "target set invalid control word 0x{:08x} for buffer {}",
+            control,
+            slot
+        ),
+    }
+}
+
+/// Read the chunk at `offset` from target buffer `slot`.
+///
+/// If the program checksums chunks, the read is checked against it, and
+/// repeated up to `retries` times on a mismatch.
+fn read_chunk(
+    target: &mut impl Target,
+    ft: &FlashTable,
+    slot: usize,
+    offset: usize,
+    retries: usize,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0; ft.buffer_size];
+    let Some(checksum_addr) = ft.checksum(slot) else {
+        target.read(ft.buffer(slot), &mut buf)?;
+        return Ok(buf);
+    };
+    for retry in 0..=retries {
+        if retry > 0 {
+            log::warn!("re-reading chunk ({} / {})", retry, retries);
+        }
+        // Either read may be corrupted, so re-read both.
+        target.read(ft.buffer(slot), &mut buf)?;
+        let expected = target.read_word_32(checksum_addr)?;
+        let actual = crc32(&buf);
+        if actual == expected {
+            return Ok(buf);
+        }
+        log::warn!(
+            "checksum mismatch reading chunk at 0x{:08x} (0x{:08x}, expected 0x{:08x})",
+            offset,
+            actual,
+            expected
+        );
+    }
+    bail!(
+        "checksum mismatch reading chunk at 0x{:08x}, after {} retries",
+        offset,
+        retries
+    );
+}
+
+/// Read the error reported by the target.
+fn target_error(target: &mut impl Target, ft: &FlashTable) -> Result<String> {
+    let Some(status_addr) = ft.status_addr else {
+        return Ok("target failed".to_string());
+    };
+    let code = target.read_word_32(status_addr)?;
+    let offset = target.read_word_32(status_addr + 4)?;
+    Ok(match ErrorCode::from_u32(code) {
+        Some(code) => format!("{} failed at 0x{:08x}", code.as_str(), offset),
+        None => format!("target failed with code {} at 0x{:08x}", code, offset),
+    })
+}
+
+/// How long to wait for the target, and how often to retry.
+#[derive(Debug, Clone, Copy)]
+pub struct RunnerOpts {
+    /// The timeout for each chunk.
+    pub timeout: Duration,
+    /// The timeout for erasing the range or chip, before the first chunk.
+    pub erase_timeout: Duration,
+    /// How often to re-read a chunk that doesn't match its checksum.
+    pub retries: usize,
+}
+
+impl Default for RunnerOpts {
+    /// The defaults of the CLI.
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            erase_timeout: Duration::from_secs(60 * 5),
+            retries: 3,
+        }
+    }
+}
+
+/// The progress of each phase of a run.
+#[derive(Default)]
+struct Phases {
+    /// Erasing the range or chip, before the first chunk is committed.
+    erase: Option<Phase>,
+    transfer: Phase,
+    /// Verifying committed chunks when loading and verifying.
+    verify: Phase,
+}
+
+/// Runs the host side of the protocol, transferring `FlashData` through the
+/// buffers of a started program.
+pub struct FlashRunner<'a, T> {
+    target: T,
     flash_table: FlashTable,
-    flash_data: FlashData,
+    flash_data: FlashData<'a>,
+    range: Range<usize>,
     count: usize,
     timeout: Duration,
     erase_timeout: Option<Duration>,
+    retries: usize,
+    skip: Skip,
+    journal: Option<Journal>,
+    progress: Progress,
+    phases: Phases,
+    /// The number of bytes verified when loading and verifying.
+    verified: usize,
+    /// The chunks loaded through each buffer, until they are committed.
+    in_flight: Vec<Option<(usize, Vec<u8>)>>,
+    mismatches: Vec<Range<usize>>,
+    regions: Vec<(Range<usize>, bool)>,
 }
 
-impl<'opts> FlashRunner<'opts> {
-    pub(crate) fn new(
-        session: &mut Session,
-        opts: &'opts DefmtOpts<'_>,
+impl<'a, T: Target> FlashRunner<'a, T> {
+    /// Run the program on `target`, which must have been started with
+    /// `params`.
+    pub fn new(
+        target: T,
         flash_table: FlashTable,
-        flash_data: FlashData,
-        timeout: Duration,
-        erase_timeout: Duration,
-    ) -> Result<Self> {
-        init_cpu(session, &opts.segments, &opts.vector_table, opts.timeout)?;
+        flash_data: FlashData<'a>,
+        params: Params,
+        runner_opts: RunnerOpts,
+        skip: Skip,
+    ) -> Self {
+        Self {
+            target,
+            in_flight: vec![None; flash_table.buffers],
+            flash_table,
+            flash_data,
+            range: params.offset..params.offset + params.length,
+            count: 0,
+            timeout: runner_opts.timeout,
+            // Only the first chunk waits for erasing the range or chip.
+            erase_timeout: matches!(params.erase, Erase::Range | Erase::Chip)
+                .then_some(runner_opts.erase_timeout),
+            retries: runner_opts.retries,
+            skip,
+            journal: None,
+            progress: Progress::default(),
+            phases: Phases::default(),
+            verified: 0,
+            mismatches: Vec::new(),
+            regions: Vec::new(),
+        }
+    }
 
-        let mut rtt = setup_rtt(session, opts.rtt_addr, opts.retries)?;
+    /// The flash ranges that differed from the data file when verifying.
+    ///
+    /// Adjacent mismatching bytes are merged into a single range, even across
+    /// chunk boundaries.
+    pub fn mismatches(&self) -> &[Range<usize>] {
+        &self.mismatches
+    }
 
-        let defmt = rtt
-            .up_channels()
-            .take(0)
-            .ok_or_else(|| eyre!("RTT up channel 0 not found"))?;
+    /// Record completed chunks in `journal`, and remove it once the run has
+    /// completed.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
 
-        let decoder = DefmtDecoder::new(&opts.defmt, "target");
+    /// Display progress on `progress`, instead of plain log lines.
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
 
-        Ok(Self {
-            decoder,
-            defmt,
-            flash_table,
-            flash_data,
-            count: 0,
-            timeout,
-            erase_timeout: Some(erase_timeout),
-        })
+    /// The flash regions, and whether their checksums matched the data file
+    /// when hashing.
+    ///
+    /// Adjacent chunks that both match or both mismatch are merged into a
+    /// single region.
+    pub fn regions(&self) -> &[(Range<usize>, bool)] {
+        &self.regions
     }
 
-    pub(crate) fn run(&mut self, session: &mut Session) -> Result<()> {
-        let mut was_halted = false;
+    /// The sectors written and skipped when loading.
+    pub fn skip(&self) -> &Skip {
+        &self.skip
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let erasing = matches!(
+            self.flash_data,
+            FlashData::Load(_) | FlashData::LoadVerify(_)
+        ) && self.erase_timeout.is_some();
+        if erasing {
+            self.phases.erase = Some(self.progress.spinner("erase"));
+        } else {
+            self.start_transfer();
+        }
+
+        let result = self.run_to_completion();
+        // Remove the progress bars, even if the run failed.
+        self.phases = Phases::default();
+        result
+    }
+
+    /// Start the transfer phase, and the verify phase when loading and
+    /// verifying.
+    fn start_transfer(&mut self) {
+        let len = self.range.len();
+        let phase = self.flash_data.phase();
+        self.phases.transfer = self.progress.phase(phase, len, self.count);
+        if let FlashData::LoadVerify(_) = self.flash_data {
+            self.phases.verify = self.progress.phase("verify", len, self.verified);
+        }
+    }
 
+    fn run_to_completion(&mut self) -> Result<()> {
+        let mut was_halted = false;
         loop {
-            self.poll(session)?;
+            self.poll()?;
 
-            let mut core = session.core(0)?;
-            let is_halted = core.core_halted()?;
+            let is_halted = self.target.core_halted()?;
 
             if is_halted && was_halted {
+                let is_complete =
+                    self.count == self.range.len() && self.in_flight.iter().all(Option::is_none);
+                if !is_complete {
+                    // The host hasn't waited on the target since it halted,
+                    // e.g. while writing the first chunks when loading.
+                    self.target.drain()?;
+                    bail!(self.target.halt_error()?);
+                }
+                if let FlashData::Dump(file) = &mut self.flash_data {
+                    file.flush()?;
+                }
+                if let Some(journal) = self.journal.take() {
+                    journal.finish()?;
+                }
                 return Ok(());
             }
             was_halted = is_halted;
         }
     }
 
-    pub(crate) fn poll(&mut self, session: &mut Session) -> Result<()> {
-        let mut read_buf = [0; 1024];
-
-        if self.count < self.flash_table.flash_size {
+    pub fn poll(&mut self) -> Result<()> {
+        if self.count < self.range.len() {
             // Display progress.
             let ft = &self.flash_table;
-            let chunks = ft.flash_size / ft.buffer_size;
+            let chunks = self.range.len() / ft.buffer_size;
             let chunk = (self.count / ft.buffer_size) + 1;
-            log::info!("chunk {} / {} (at 0x{:08x})", chunk, chunks, self.count);
-
-            let mut core = session.core(0)?;
+            let offset = self.range.start + self.count;
+            self.progress.chunk(chunk, chunks, offset);
+            // The chunks rotate through the buffers.
+            let slot = (chunk - 1) % ft.buffers;
 
             match &mut self.flash_data {
                 FlashData::Dump(file) => {
                     log::debug!("waiting for chunk to become available");
-                    let deadline = Instant::now() + self.timeout;
-                    loop {
-                        // Wait for signal that the buffer is ready to be read.
-                        let control = core.read_word_32(ft.control_addr)?;
-                        log::trace!("control: {}", control);
-                        if control == 1 {
-                            break;
-                        }
-                        // In the meantime, pump the defmt output.
-                        let n = self.defmt.read(&mut core, &mut read_buf)?;
-                        log::trace!("defmt bytes: {}", n);
-                        if n > 0 {
-                            self.decoder.decode(&read_buf[..n])?;
-                        }
-                        // Or time out.
-                        if Instant::now() > deadline {
-                            bail!("Time out");
-                        }
-                    }
+                    // Wait for signal that the buffer is ready to be read.
+                    wait_for_control(&mut self.target, ft, slot, Control::Full, self.timeout)?;
 
-                    log::debug!("reading chunk from target (offset 0x{:08x})", self.count);
+                    log::debug!("reading chunk from target (offset 0x{:08x})", offset);
                     // Read chunk from target.
-                    let mut buf = vec![0; ft.buffer_size];
-                    core.read(ft.buffer_addr, &mut buf)?;
+                    let buf = read_chunk(&mut self.target, ft, slot, offset, self.retries)?;
                     // Write chunk to file.
                     file.write_all(&buf)?;
-                    // Signal target to read the next chunk.
-                    core.write_word_32(ft.control_addr, 0)?;
+                    if let Some(journal) = &mut self.journal {
+                        file.flush()?;
+                        journal.record(offset, &buf)?;
+                    }
+                    // Signal target to read the next chunk into the buffer.
+                    signal(&mut self.target, ft, slot, Control::Full, Control::Free)?;
                     self.count += buf.len();
                 }
-                FlashData::Load(file) => {
-                    log::debug!("writing chunk to target (offset 0x{:08x})", self.count);
+                FlashData::Load(data) | FlashData::LoadVerify(data) => {
                     // Read chunk from file.
                     let mut buf = vec![0; ft.buffer_size];
-                    file.read_exact(&mut buf)?;
-                    // Write chunk to target.
-                    core.write(ft.buffer_addr, &buf)?;
-                    // Signal target to write the current chunk.
-                    core.write_word_32(ft.control_addr, 1)?;
+                    data.read_exact(&mut buf)?;
+                    // Wait for the chunk previously loaded through the buffer.
+                    self.complete(slot)?;
+
+                    let ft = &self.flash_table;
+                    log::debug!("writing chunk to target (offset 0x{:08x})", offset);
+                    // Write chunk to target, and signal target to commit it.
+                    // Skipped sectors are read back and verified all the same.
+                    self.skip.load(&mut self.target, ft, slot, &buf)?;
+                    self.in_flight[slot] = Some((offset, buf));
+                    self.count += ft.buffer_size;
+                }
+                FlashData::Verify(file) => {
+                    log::debug!("waiting for chunk to become available");
+                    // Wait for signal that the buffer is ready to be read.
+                    wait_for_control(&mut self.target, ft, slot, Control::Full, self.timeout)?;
+
+                    log::debug!("reading chunk from target (offset 0x{:08x})", offset);
+                    // Read chunk from target.
+                    let buf = read_chunk(&mut self.target, ft, slot, offset, self.retries)?;
+                    // Read expected chunk from file.
+                    let mut expected = vec![0; ft.buffer_size];
+                    file.read_exact(&mut expected)?;
+                    // Compare the chunks.
+                    record_mismatches(&mut self.mismatches, offset, &buf, &expected);
+                    // Signal target to read the next chunk into the buffer.
+                    signal(&mut self.target, ft, slot, Control::Full, Control::Free)?;
                     self.count += buf.len();
+                }
+                FlashData::Hash(file) => {
+                    log::debug!("waiting for checksum to become available");
+                    // Wait for signal that the checksum is ready to be read.
+                    wait_for_control(&mut self.target, ft, slot, Control::Full, self.timeout)?;
 
-                    log::debug!("waiting for chunk to become committed");
-                    let timeout = self.erase_timeout.take().unwrap_or(self.timeout);
-                    let deadline = Instant::now() + timeout;
-                    loop {
-                        // Wait for signal that the buffer is ready to be written again.
-                        let control = core.read_word_32(ft.control_addr)?;
-                        log::trace!("control: {}", control);
-                        if control == 0 {
-                            break;
-                        }
-                        // In the meantime, pump the defmt output.
-                        let n = self.defmt.read(&mut core, &mut read_buf)?;
-                        log::trace!("defmt: {}", n);
-                        if n > 0 {
-                            self.decoder.decode(&read_buf[..n])?;
-                        }
-                        // Or time out.
-                        if Instant::now() > deadline {
-                            bail!("Time out");
+                    // Read checksum from target.
+                    let actual = self.target.read_word_32(ft.buffer(slot))?;
+                    // Read expected chunk from file, and checksum it.
+                    let mut expected = vec![0; ft.buffer_size];
+                    file.read_exact(&mut expected)?;
+                    let expected = crc32(&expected);
+                    log::debug!(
+                        "checksum 0x{:08x} (expected 0x{:08x}, offset 0x{:08x})",
+                        actual,
+                        expected,
+                        offset
+                    );
+                    // Compare the checksums.
+                    let range = offset..offset + ft.buffer_size;
+                    match self.regions.last_mut() {
+                        Some((last, matched))
+                            if last.end == range.start && *matched == (actual == expected) =>
+                        {
+                            last.end = range.end
                         }
+                        _ => self.regions.push((range, actual == expected)),
                     }
+                    // Signal target to hash the next chunk into the buffer.
+                    signal(&mut self.target, ft, slot, Control::Full, Control::Free)?;
+                    self.count += ft.buffer_size;
                 }
             }
+            self.phases.transfer.set_position(self.count);
         } else {
-            let mut core = session.core(0)?;
-            let n = self.defmt.read(&mut core, &mut read_buf)?;
-            if n > 0 {
-                self.decoder.decode(&read_buf[..n])?;
+            // Wait for the remaining loaded chunks, in order.
+            let next = self.count / self.flash_table.buffer_size;
+            for i in 0..self.flash_table.buffers {
+                self.complete((next + i) % self.flash_table.buffers)?;
+            }
+            self.target.pump()?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the chunk loaded through buffer `slot`, if any, to become
+    /// committed, and verify it when loading and verifying.
+    ///
+    /// Afterwards, the buffer can be written again.
+    fn complete(&mut self, slot: usize) -> Result<()> {
+        let Some((offset, buf)) = self.in_flight[slot].take() else {
+            return Ok(());
+        };
+        let ft = &self.flash_table;
+        let erasing = self.erase_timeout.is_some();
+        let timeout = self.erase_timeout.take().unwrap_or(self.timeout);
+
+        if let FlashData::LoadVerify(_) = self.flash_data {
+            log::debug!(
+                "waiting for chunk to become read back (offset 0x{:08x})",
+                offset
+            );
+            // Wait for signal that the committed chunk has been read back.
+            wait_for_control(&mut self.target, ft, slot, Control::ReadBack, timeout)?;
+
+            log::debug!("reading chunk from target (offset 0x{:08x})", offset);
+            // Read committed chunk from target.
+            let mut actual = vec![0; ft.buffer_size];
+            self.target.read(ft.buffer(slot), &mut actual)?;
+            // Compare the chunks.
+            if let Some(i) = actual.iter().zip(&buf).position(|(a, e)| a != e) {
+                bail!(
+                    "verify failed at 0x{:08x} (chunk {} / {} at 0x{:08x})",
+                    offset + i,
+                    (offset - self.range.start) / ft.buffer_size + 1,
+                    self.range.len() / ft.buffer_size,
+                    offset
+                );
             }
+            // Signal target the buffer can be written again.
+            signal(&mut self.target, ft, slot, Control::ReadBack, Control::Free)?;
+            self.verified += ft.buffer_size;
+            self.phases.verify.set_position(self.verified);
+        } else {
+            log::debug!(
+                "waiting for chunk to become committed (offset 0x{:08x})",
+                offset
+            );
+            // Wait for signal that the buffer is ready to be written again.
+            wait_for_control(&mut self.target, ft, slot, Control::Free, timeout)?;
         }
 
+        if erasing {
+            // Erasing the range or chip took as long as the first commit.
+            self.phases.erase = None;
+            self.start_transfer();
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.record(offset, &buf)?;
+        }
         Ok(())
     }
 }
+
+/// Record the ranges where `actual` differs from `expected`.
+///
+/// The `offset` is the flash offset of the start of the chunk. A range that
+/// starts where the last recorded range ends is merged into it.
+fn record_mismatches(
+    mismatches: &mut Vec<Range<usize>>,
+    offset: usize,
+    actual: &[u8],
+    expected: &[u8],
+) {
+    for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+        if a == e {
+            continue;
+        }
+        let addr = offset + i;
+        match mismatches.last_mut() {
+            Some(last) if last.end == addr => last.end += 1,
+            _ => mismatches.push(addr..addr + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::device::{DeviceTarget, MemFlash};
+    use crate::sim::emu::{EmuTarget, SpiNor};
+    use crate::sim::{flash_table, SimTarget};
+    use rs_flash::Direction;
+    use std::io::{Seek as _, SeekFrom};
+
+    const FLASH_SIZE: usize = 0x8000;
+    const BUFFER_SIZE: usize = 0x1000;
+    const SECTOR_SIZE: usize = 0x400;
+
+    /// The example programs, as built by `fixtures/build.sh`.
+    const DUMP_EXAMPLE: &[u8] = include_bytes!("../fixtures/dump.elf");
+    const LOAD_EXAMPLE: &[u8] = include_bytes!("../fixtures/load.elf");
+    const EXAMPLE_FLASH_SIZE: usize = 16 * 1024 * 1024;
+    const EXAMPLE_BUFFER_SIZE: usize = 32 * 1024;
+    const EXAMPLE_SECTOR_SIZE: usize = 4 * 1024;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ (i >> 8) as u8 ^ seed)
+            .collect()
+    }
+
+    fn params(erase: Erase) -> Params {
+        Params {
+            offset: 0,
+            length: FLASH_SIZE,
+            flags: 0,
+            erase,
+        }
+    }
+
+    /// A temporary file with `contents`, which is removed right away.
+    fn temp_file(name: &str, contents: &[u8]) -> std::fs::File {
+        let path = std::env::temp_dir().join(format!("rs-flash-{}-{}", std::process::id(), name));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.write_all(contents).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    fn sim_runner(
+        direction: Direction,
+        buffers: usize,
+        flash: Vec<u8>,
+        flash_data: FlashData<'_>,
+        params: Params,
+    ) -> FlashRunner<'_, SimTarget> {
+        let ft = flash_table(direction, FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, buffers);
+        let target = SimTarget::new(flash, ft.clone(), params);
+        let runner_opts = RunnerOpts {
+            timeout: Duration::from_millis(200),
+            erase_timeout: Duration::from_millis(200),
+            retries: 3,
+        };
+        let mut skip = Skip::new(SECTOR_SIZE);
+        skip.erased = true;
+        skip.partial = true;
+        FlashRunner::new(target, ft, flash_data, params, runner_opts, skip)
+    }
+
+    /// Run the real `rs_flash::run` on `flash`.
+    fn device_runner(
+        direction: Direction,
+        buffers: usize,
+        flash: MemFlash,
+        flash_data: FlashData<'_>,
+        params: Params,
+    ) -> FlashRunner<'_, DeviceTarget> {
+        let ft = flash_table(direction, FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, buffers);
+        let target = DeviceTarget::new(flash, ft.clone(), params);
+        // The program runs on its own, so only time out if it's stuck.
+        let runner_opts = RunnerOpts {
+            timeout: Duration::from_secs(5),
+            erase_timeout: Duration::from_secs(5),
+            retries: 0,
+        };
+        let mut skip = Skip::new(SECTOR_SIZE);
+        skip.erased = true;
+        skip.partial = true;
+        FlashRunner::new(target, ft, flash_data, params, runner_opts, skip)
+    }
+
+    /// Run the example program `elf` on an emulated core, on `flash`.
+    fn emu_runner<'a>(
+        elf: &[u8],
+        flash: SpiNor,
+        flash_data: FlashData<'a>,
+        params: Params,
+    ) -> FlashRunner<'a, EmuTarget> {
+        let (target, ft) = EmuTarget::new(elf, flash, &params).unwrap();
+        // Emulating the program is slow, so only time out if it's stuck.
+        let runner_opts = RunnerOpts {
+            timeout: Duration::from_secs(30),
+            erase_timeout: Duration::from_secs(30),
+            retries: 0,
+        };
+        let mut skip = Skip::new(ft.sector_size);
+        skip.erased = true;
+        skip.partial = true;
+        FlashRunner::new(target, ft, flash_data, params, runner_opts, skip)
+    }
+
+    #[test]
+    fn dump() {
+        for buffers in 1..=3 {
+            let flash = pattern(FLASH_SIZE, 1);
+            let mut dump = Vec::new();
+            let mut runner = sim_runner(
+                Direction::Dump,
+                buffers,
+                flash.clone(),
+                FlashData::Dump(&mut dump),
+                params(Erase::None),
+            );
+            runner.run().unwrap();
+            assert_eq!(dump, flash, "{} buffer(s)", buffers);
+        }
+    }
+
+    #[test]
+    fn dump_flushes_sink() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut dump = std::io::BufWriter::with_capacity(2 * FLASH_SIZE, Vec::new());
+        let mut runner = sim_runner(
+            Direction::Dump,
+            2,
+            flash.clone(),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.run().unwrap();
+        assert_eq!(dump.get_ref(), &flash);
+    }
+
+    #[test]
+    fn dump_retries_corrupt_reads() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            2,
+            flash.clone(),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.target.corrupt_reads = 3;
+        runner.run().unwrap();
+        assert_eq!(dump, flash);
+
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            2,
+            flash,
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.target.corrupt_reads = 4;
+        let err = runner.run().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "checksum mismatch reading chunk at 0x00000000, after 3 retries"
+        );
+    }
+
+    #[test]
+    fn dump_retries_corrupt_checksums() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            2,
+            flash.clone(),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.target.corrupt_checksum_reads = 1;
+        runner.run().unwrap();
+        assert_eq!(dump, flash);
+    }
+
+    #[test]
+    fn load_skips_chunks_and_sectors() {
+        let old = pattern(FLASH_SIZE, 1);
+        let mut data = old.clone();
+        // Chunk 0 is unchanged, chunk 1 is erased, and chunk 2 has one
+        // changed sector.
+        data[BUFFER_SIZE..2 * BUFFER_SIZE].fill(ERASED);
+        data[2 * BUFFER_SIZE + SECTOR_SIZE] ^= 0xff;
+        data[3 * BUFFER_SIZE..].copy_from_slice(&pattern(FLASH_SIZE - 3 * BUFFER_SIZE, 7));
+
+        for buffers in 1..=2 {
+            let mut source = data.as_slice();
+            let mut runner = sim_runner(
+                Direction::Load,
+                buffers,
+                old.clone(),
+                FlashData::Load(&mut source),
+                params(Erase::Chunk),
+            );
+            runner.skip.diff_against = Some(temp_file(&format!("load-{}", buffers), &old));
+            runner.run().unwrap();
+
+            assert_eq!(runner.target.flash, data, "{} buffer(s)", buffers);
+            let sectors = BUFFER_SIZE / SECTOR_SIZE;
+            assert_eq!(runner.skip.matched_sectors, sectors + sectors - 1);
+            assert_eq!(runner.skip.erased_sectors, sectors);
+            assert_eq!(runner.skip.rewritten_sectors, 1 + 5 * sectors);
+            assert!(runner
+                .target
+                .writes
+                .iter()
+                .all(|range| range.start >= 2 * BUFFER_SIZE));
+        }
+    }
+
+    #[test]
+    fn diff_load_erases_sectors() {
+        let old = pattern(FLASH_SIZE, 1);
+        let mut data = old.clone();
+        // A sector of chunk 1 is now erased.
+        data[BUFFER_SIZE + SECTOR_SIZE..BUFFER_SIZE + 2 * SECTOR_SIZE].fill(ERASED);
+
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Load,
+            2,
+            old.clone(),
+            FlashData::Load(&mut source),
+            params(Erase::Chunk),
+        );
+        runner.skip.diff_against = Some(temp_file("load-erased-sector", &old));
+        runner.run().unwrap();
+
+        assert_eq!(runner.target.flash, data);
+        assert_eq!(runner.skip.rewritten_sectors, 1);
+        let sector = BUFFER_SIZE + SECTOR_SIZE..BUFFER_SIZE + 2 * SECTOR_SIZE;
+        assert_eq!(runner.target.writes, [sector]);
+    }
+
+    #[test]
+    fn load_completes_when_target_halts_after_last_commit() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Load,
+            2,
+            vec![ERASED; FLASH_SIZE],
+            FlashData::Load(&mut source),
+            params(Erase::None),
+        );
+        runner.target.lag = true;
+        runner.run().unwrap();
+        assert_eq!(runner.target.flash, data);
+    }
+
+    #[test]
+    fn load_verify() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::LoadVerify,
+            2,
+            pattern(FLASH_SIZE, 1),
+            FlashData::LoadVerify(&mut source),
+            params(Erase::Range),
+        );
+        runner.run().unwrap();
+        assert_eq!(runner.target.flash, data);
+    }
+
+    #[test]
+    fn load_verify_fails_without_erase() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::LoadVerify,
+            1,
+            vec![0; FLASH_SIZE],
+            FlashData::LoadVerify(&mut source),
+            params(Erase::None),
+        );
+        let err = runner.run().unwrap_err();
+        assert!(
+            err.to_string().starts_with("verify failed at 0x"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn verify_reports_mismatches() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut data = flash.clone();
+        data[0x10] ^= 1;
+        data[0x11] ^= 1;
+        data[BUFFER_SIZE - 1] ^= 1;
+        data[BUFFER_SIZE] ^= 1;
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Verify,
+            2,
+            flash,
+            FlashData::Verify(&mut source),
+            params(Erase::None),
+        );
+        runner.run().unwrap();
+        assert_eq!(
+            runner.mismatches(),
+            [0x10..0x12, BUFFER_SIZE - 1..BUFFER_SIZE + 1]
+        );
+    }
+
+    #[test]
+    fn hash_reports_regions() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut data = flash.clone();
+        data[3 * BUFFER_SIZE + 5] ^= 1;
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Hash,
+            1,
+            flash,
+            FlashData::Hash(&mut source),
+            params(Erase::None),
+        );
+        runner.run().unwrap();
+        assert_eq!(
+            runner.regions(),
+            [
+                (0..3 * BUFFER_SIZE, true),
+                (3 * BUFFER_SIZE..4 * BUFFER_SIZE, false),
+                (4 * BUFFER_SIZE..FLASH_SIZE, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn times_out() {
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            1,
+            pattern(FLASH_SIZE, 1),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.target.stall_at = Some(2);
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "Time out");
+    }
+
+    #[test]
+    fn reports_target_error() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Load,
+            2,
+            vec![ERASED; FLASH_SIZE],
+            FlashData::Load(&mut source),
+            params(Erase::Chunk),
+        );
+        runner.target.fail_at = Some((1, ErrorCode::Write));
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "flash write failed at 0x00001000");
+    }
+
+    #[test]
+    fn reports_target_error_when_target_halts() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Load,
+            2,
+            vec![ERASED; FLASH_SIZE],
+            FlashData::Load(&mut source),
+            params(Erase::Chunk),
+        );
+        // The target fails and halts between reading the control word and
+        // checking whether it has halted.
+        runner.target.lag = true;
+        runner.target.fail_at = Some((1, ErrorCode::Write));
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "flash write failed at 0x00001000");
+    }
+
+    #[test]
+    fn reports_halt() {
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            1,
+            pattern(FLASH_SIZE, 1),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        runner.target.halt_at = Some(1);
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "target halted unexpectedly at chunk 1");
+    }
+
+    #[test]
+    fn reports_halt_before_completion() {
+        // The host writes the first chunks without waiting for the target, so
+        // it sees the target halted before it has waited on any.
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut runner = sim_runner(
+            Direction::Load,
+            2,
+            vec![ERASED; FLASH_SIZE],
+            FlashData::Load(&mut source),
+            params(Erase::None),
+        );
+        runner.target.halt_at = Some(0);
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "target halted unexpectedly at chunk 0");
+    }
+
+    #[test]
+    fn rejects_invalid_control() {
+        let mut dump = Vec::new();
+        let mut runner = sim_runner(
+            Direction::Dump,
+            2,
+            pattern(FLASH_SIZE, 1),
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        let control_addr = runner.flash_table.control(0);
+        runner.target.write_word_32(control_addr, 0x1234).unwrap();
+        let err = runner.run().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "target set invalid control word 0x00001234 for buffer 0"
+        );
+    }
+
+    #[test]
+    fn device_dump() {
+        let flash = pattern(FLASH_SIZE, 1);
+        for buffers in 1..=3 {
+            // A partial range, so the chunks don't line up with the buffers.
+            let params = Params {
+                offset: BUFFER_SIZE,
+                length: FLASH_SIZE - 2 * BUFFER_SIZE,
+                ..params(Erase::None)
+            };
+            let mut dump = Vec::new();
+            let mut runner = device_runner(
+                Direction::Dump,
+                buffers,
+                MemFlash::new(flash.clone()),
+                FlashData::Dump(&mut dump),
+                params,
+            );
+            runner.run().unwrap();
+            assert_eq!(
+                dump,
+                flash[BUFFER_SIZE..FLASH_SIZE - BUFFER_SIZE],
+                "{} buffer(s)",
+                buffers
+            );
+        }
+    }
+
+    #[test]
+    fn device_verify_and_hash() {
+        let flash = pattern(FLASH_SIZE, 1);
+        let mut data = flash.clone();
+        data[BUFFER_SIZE + 5] ^= 1;
+
+        let mut source = data.as_slice();
+        let mut runner = device_runner(
+            Direction::Verify,
+            2,
+            MemFlash::new(flash.clone()),
+            FlashData::Verify(&mut source),
+            params(Erase::None),
+        );
+        runner.run().unwrap();
+        let mismatch = BUFFER_SIZE + 5..BUFFER_SIZE + 6;
+        assert_eq!(runner.mismatches(), [mismatch]);
+
+        let mut source = data.as_slice();
+        let mut runner = device_runner(
+            Direction::Hash,
+            2,
+            MemFlash::new(flash),
+            FlashData::Hash(&mut source),
+            params(Erase::None),
+        );
+        runner.run().unwrap();
+        assert_eq!(
+            runner.regions(),
+            [
+                (0..BUFFER_SIZE, true),
+                (BUFFER_SIZE..2 * BUFFER_SIZE, false),
+                (2 * BUFFER_SIZE..FLASH_SIZE, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn device_load_skips_chunks_and_sectors() {
+        let old = pattern(FLASH_SIZE, 1);
+        let mut data = old.clone();
+        // Chunk 0 is unchanged, chunk 1 is erased, and chunk 2 has one
+        // changed sector.
+        data[BUFFER_SIZE..2 * BUFFER_SIZE].fill(ERASED);
+        data[2 * BUFFER_SIZE + SECTOR_SIZE] ^= 0xff;
+        data[3 * BUFFER_SIZE..].copy_from_slice(&pattern(FLASH_SIZE - 3 * BUFFER_SIZE, 7));
+
+        for buffers in 1..=3 {
+            let mut source = data.as_slice();
+            let mut runner = device_runner(
+                Direction::Load,
+                buffers,
+                MemFlash::new(old.clone()),
+                FlashData::Load(&mut source),
+                params(Erase::Chunk),
+            );
+            runner.skip.diff_against = Some(temp_file(&format!("device-{}", buffers), &old));
+            runner.run().unwrap();
+
+            let flash = runner.target.flash();
+            assert_eq!(flash.data, data, "{} buffer(s)", buffers);
+            // The erased chunk is only erased, and only the changed sector of
+            // the next chunk is erased and written.
+            let sector = 2 * BUFFER_SIZE + SECTOR_SIZE..2 * BUFFER_SIZE + 2 * SECTOR_SIZE;
+            assert_eq!(
+                flash.erases[..2],
+                [BUFFER_SIZE..2 * BUFFER_SIZE, sector.clone()]
+            );
+            assert_eq!(flash.writes[0], sector);
+            assert!(flash.writes[1..]
+                .iter()
+                .all(|range| range.start >= 3 * BUFFER_SIZE));
+        }
+    }
+
+    #[test]
+    fn device_load_verify() {
+        let data = pattern(FLASH_SIZE, 3);
+        for (buffers, erase) in [(1, Erase::Range), (2, Erase::Chip), (3, Erase::Chunk)] {
+            let mut source = data.as_slice();
+            let mut runner = device_runner(
+                Direction::LoadVerify,
+                buffers,
+                MemFlash::new(pattern(FLASH_SIZE, 1)),
+                FlashData::LoadVerify(&mut source),
+                params(erase),
+            );
+            runner.run().unwrap();
+            let flash = runner.target.flash();
+            assert_eq!(flash.data, data, "{:?}", erase);
+            if erase != Erase::Chunk {
+                assert_eq!(flash.erases, vec![0..FLASH_SIZE], "{:?}", erase);
+            }
+        }
+
+        let mut source = data.as_slice();
+        let mut runner = device_runner(
+            Direction::LoadVerify,
+            2,
+            MemFlash::new(pattern(FLASH_SIZE, 1)),
+            FlashData::LoadVerify(&mut source),
+            params(Erase::None),
+        );
+        let err = runner.run().unwrap_err();
+        assert!(
+            err.to_string().starts_with("verify failed at 0x"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn device_reports_errors() {
+        let data = pattern(FLASH_SIZE, 3);
+        let mut source = data.as_slice();
+        let mut flash = MemFlash::new(vec![ERASED; FLASH_SIZE]);
+        flash.fail_at = Some(BUFFER_SIZE + SECTOR_SIZE);
+        let mut runner = device_runner(
+            Direction::Load,
+            2,
+            flash,
+            FlashData::Load(&mut source),
+            params(Erase::Chunk),
+        );
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "flash erase failed at 0x00001000");
+
+        let mut dump = Vec::new();
+        let mut flash = MemFlash::new(pattern(FLASH_SIZE, 1));
+        flash.fail_at = Some(2 * BUFFER_SIZE);
+        let mut runner = device_runner(
+            Direction::Dump,
+            2,
+            flash,
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "flash read failed at 0x00002000");
+
+        let mut dump = Vec::new();
+        let mut flash = MemFlash::new(pattern(FLASH_SIZE, 1));
+        flash.panic_at = Some(BUFFER_SIZE);
+        let mut runner = device_runner(
+            Direction::Dump,
+            1,
+            flash,
+            FlashData::Dump(&mut dump),
+            params(Erase::None),
+        );
+        let err = runner.run().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "target halted unexpectedly: program panicked (injected panic at 0x00001000)"
+        );
+    }
+
+    #[test]
+    fn device_rejects_invalid_control() {
+        // Dumping waits for the host to read the first chunk, so the program
+        // is at the second chunk.
+        for (direction, offset) in [(Direction::Dump, BUFFER_SIZE), (Direction::Load, 0)] {
+            let ft = flash_table(direction, FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, 1);
+            let flash = MemFlash::new(vec![ERASED; FLASH_SIZE]);
+            let mut target = DeviceTarget::new(flash, ft.clone(), params(Erase::None));
+            if direction == Direction::Dump {
+                wait_for_control(&mut target, &ft, 0, Control::Full, Duration::from_secs(5))
+                    .unwrap();
+            }
+            // Neither a valid state, nor a command.
+            target.write_word_32(ft.control(0), 0x1234).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !target.core_halted().unwrap() {
+                assert!(Instant::now() < deadline, "program didn't stop");
+            }
+            assert_eq!(
+                target.read_word_32(ft.control(0)).unwrap(),
+                Control::Error.as_u32()
+            );
+            assert_eq!(
+                target_error(&mut target, &ft).unwrap(),
+                format!("protocol failed at 0x{:08x}", offset)
+            );
+            assert_eq!(
+                target.halt_error().unwrap(),
+                "target halted unexpectedly: program failed (protocol)"
+            );
+        }
+    }
+
+    #[test]
+    fn emu_dump() {
+        let flash = pattern(EXAMPLE_FLASH_SIZE, 1);
+        let range = 0x10_0000..0x10_0000 + 2 * EXAMPLE_BUFFER_SIZE;
+        let params = Params {
+            offset: range.start,
+            length: range.len(),
+            flags: 0,
+            erase: Erase::None,
+        };
+        let mut dump = Vec::new();
+        let mut runner = emu_runner(
+            DUMP_EXAMPLE,
+            SpiNor::new(flash.clone()),
+            FlashData::Dump(&mut dump),
+            params,
+        );
+        runner.run().unwrap();
+        assert_eq!(dump, flash[range]);
+    }
+
+    #[test]
+    fn emu_load_skips_chunks_and_sectors() {
+        const BUFFER_SIZE: usize = EXAMPLE_BUFFER_SIZE;
+        const SECTOR_SIZE: usize = EXAMPLE_SECTOR_SIZE;
+        let old = pattern(EXAMPLE_FLASH_SIZE, 1);
+        let range = 0x10_0000..0x10_0000 + 4 * BUFFER_SIZE;
+        let mut data = old[range.clone()].to_vec();
+        // Chunk 0 is unchanged, chunk 1 is erased, chunk 2 has one changed
+        // sector, and chunk 3 is new.
+        data[BUFFER_SIZE..2 * BUFFER_SIZE].fill(ERASED);
+        data[2 * BUFFER_SIZE + SECTOR_SIZE] ^= 0xff;
+        data[3 * BUFFER_SIZE..].copy_from_slice(&pattern(BUFFER_SIZE, 7));
+
+        let params = Params {
+            offset: range.start,
+            length: range.len(),
+            flags: 0,
+            erase: Erase::Chunk,
+        };
+        let mut source = data.as_slice();
+        let mut runner = emu_runner(
+            LOAD_EXAMPLE,
+            SpiNor::new(old.clone()),
+            FlashData::Load(&mut source),
+            params,
+        );
+        runner.skip.diff_against = Some(temp_file("emu", &old[range.clone()]));
+        runner.run().unwrap();
+
+        let flash = runner.target.flash();
+        assert_eq!(flash.data[range.clone()], data);
+        assert_eq!(flash.data[..range.start], old[..range.start]);
+        assert_eq!(flash.data[range.end..], old[range.end..]);
+        // The erased chunk is only erased, and only the changed sector of the
+        // next chunk is erased and written.
+        let sector = |i: usize| range.start + i * SECTOR_SIZE..range.start + (i + 1) * SECTOR_SIZE;
+        let erases: Vec<_> = (8..16).chain([17]).chain(24..32).map(sector).collect();
+        assert_eq!(flash.erases, erases);
+        assert!(flash
+            .writes
+            .iter()
+            .all(|write| sector(17).contains(&write.start)
+                || write.start >= range.start + 3 * BUFFER_SIZE));
+    }
+
+    #[test]
+    fn emu_reports_errors() {
+        // The write enable latch is still set, so the flash driver fails to
+        // initialize.
+        let mut flash = SpiNor::new(vec![ERASED; EXAMPLE_FLASH_SIZE]);
+        flash.write_enabled = true;
+        let params = Params {
+            offset: 0,
+            length: EXAMPLE_BUFFER_SIZE,
+            flags: 0,
+            erase: Erase::None,
+        };
+        let mut dump = Vec::new();
+        let mut runner = emu_runner(DUMP_EXAMPLE, flash, FlashData::Dump(&mut dump), params);
+        let err = runner.run().unwrap_err();
+        assert_eq!(err.to_string(), "flash init failed at 0x00000000");
+    }
+}
@@ -0,0 +1,342 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A simulated target, which runs the target side of the protocol over an
+//! in-memory flash image, to test the runner without hardware.
+//!
+//! The target doesn't run on its own. Instead, it makes as much progress as
+//! it can whenever the host reads from it. See [`device`] for a target
+//! running the real `rs_flash::run` instead, and [`emu`] for one running the
+//! example programs on an emulated core.
+
+pub(crate) mod cortex_m;
+pub(crate) mod device;
+pub(crate) mod emu;
+
+use crate::elf::FlashTable;
+use crate::target::Target;
+use color_eyre::eyre::{bail, Result};
+use rs_flash::crc::crc32;
+use rs_flash::protocol::{Control, Owner};
+use rs_flash::{table, Direction, Erase, ErrorCode, Params};
+use std::ops::Range;
+
+/// The start of the simulated RAM.
+const RAM_ADDR: u64 = 0x2000_0000;
+/// The size of the simulated RAM.
+const RAM_SIZE: usize = 0x1_0000;
+const CONTROL_ADDR: u64 = RAM_ADDR;
+const CHECKSUM_ADDR: u64 = RAM_ADDR + 0x100;
+const SECTORS_ADDR: u64 = RAM_ADDR + 0x200;
+const STATUS_ADDR: u64 = RAM_ADDR + 0x300;
+const PARAMS_ADDR: u64 = RAM_ADDR + 0x400;
+const BUFFER_ADDR: u64 = RAM_ADDR + 0x1000;
+
+/// The flash table of a simulated program.
+pub(crate) fn flash_table(
+    direction: Direction,
+    flash_size: usize,
+    buffer_size: usize,
+    sector_size: usize,
+    buffers: usize,
+) -> FlashTable {
+    assert!(buffer_size * buffers <= RAM_SIZE - (BUFFER_ADDR - RAM_ADDR) as usize);
+    FlashTable {
+        version: table::VERSION,
+        direction,
+        flash_size,
+        buffer_size,
+        buffer_addr: BUFFER_ADDR,
+        control_addr: CONTROL_ADDR,
+        params_addr: Some(PARAMS_ADDR),
+        status_addr: Some(STATUS_ADDR),
+        capabilities: table::CAPABILITIES,
+        sector_size,
+        sectors_addr: Some(SECTORS_ADDR),
+        checksum_addr: Some(CHECKSUM_ADDR),
+        buffers,
+    }
+}
+
+pub(crate) struct SimTarget {
+    /// The flash image.
+    pub(crate) flash: Vec<u8>,
+    ft: FlashTable,
+    params: Params,
+    ram: Vec<u8>,
+    /// The number of chunks the target has handled.
+    chunk: usize,
+    started: bool,
+    halted: bool,
+    /// Stop making progress at this chunk, so the host times out.
+    pub(crate) stall_at: Option<usize>,
+    /// Report this error at this chunk.
+    pub(crate) fail_at: Option<(usize, ErrorCode)>,
+    /// Halt at this chunk, e.g. because of a panic.
+    pub(crate) halt_at: Option<usize>,
+    /// Corrupt this many reads of a buffer.
+    pub(crate) corrupt_reads: usize,
+    /// Corrupt this many reads of a checksum.
+    pub(crate) corrupt_checksum_reads: usize,
+    /// Only make progress when the host checks whether the core is halted
+    /// right after reading a word, as if the target always raced ahead in
+    /// between.
+    pub(crate) lag: bool,
+    /// The host has read a word, and not written since.
+    read_since_write: bool,
+    /// The flash ranges written, in order.
+    pub(crate) writes: Vec<Range<usize>>,
+}
+
+impl SimTarget {
+    /// Start the program described by `ft` with `params` on `flash`.
+    pub(crate) fn new(flash: Vec<u8>, ft: FlashTable, params: Params) -> Self {
+        assert_eq!(flash.len(), ft.flash_size);
+        Self {
+            flash,
+            ft,
+            params,
+            ram: vec![0; RAM_SIZE],
+            chunk: 0,
+            started: false,
+            halted: false,
+            stall_at: None,
+            fail_at: None,
+            halt_at: None,
+            corrupt_reads: 0,
+            corrupt_checksum_reads: 0,
+            lag: false,
+            read_since_write: false,
+            writes: Vec::new(),
+        }
+    }
+
+    fn ram_range(&self, addr: u64, len: usize) -> Result<Range<usize>> {
+        let start = addr
+            .checked_sub(RAM_ADDR)
+            .filter(|start| *start as usize + len <= RAM_SIZE);
+        match start {
+            Some(start) => Ok(start as usize..start as usize + len),
+            None => bail!("access to 0x{:08x} ({} bytes) is out of RAM", addr, len),
+        }
+    }
+
+    fn word(&self, addr: u64) -> u32 {
+        let start = (addr - RAM_ADDR) as usize;
+        u32::from_le_bytes(self.ram[start..start + 4].try_into().unwrap())
+    }
+
+    fn set_word(&mut self, addr: u64, value: u32) {
+        let start = (addr - RAM_ADDR) as usize;
+        self.ram[start..start + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The state of buffer `slot`, if its control word is valid.
+    fn control(&self, slot: usize) -> Option<Control> {
+        Control::from_u32(self.word(self.ft.control(slot)))
+    }
+
+    fn set_control(&mut self, slot: usize, control: Control) {
+        self.set_word(self.ft.control(slot), control.as_u32());
+    }
+
+    fn buffer(&mut self, slot: usize) -> &mut [u8] {
+        let start = (self.ft.buffer(slot) - RAM_ADDR) as usize;
+        &mut self.ram[start..start + self.ft.buffer_size]
+    }
+
+    fn chunks(&self) -> usize {
+        self.params.length / self.ft.buffer_size
+    }
+
+    /// Report an error to the host, and stop.
+    fn fail(&mut self, code: ErrorCode, offset: usize) {
+        self.set_word(STATUS_ADDR, code.as_u32());
+        self.set_word(STATUS_ADDR + 4, offset as u32);
+        for slot in 0..self.ft.buffers {
+            self.set_control(slot, Control::Error);
+        }
+        self.halted = true;
+    }
+
+    fn erase(&mut self, range: Range<usize>) {
+        self.flash[range].fill(0xff);
+    }
+
+    /// Write to the flash. Like NOR flash, bits can only be cleared.
+    fn program(&mut self, offset: usize, data: &[u8]) {
+        for (flash, data) in self.flash[offset..offset + data.len()].iter_mut().zip(data) {
+            *flash &= data;
+        }
+        self.writes.push(offset..offset + data.len());
+    }
+
+    /// Make as much progress as possible, without waiting for the host.
+    fn step(&mut self) {
+        if !self.started {
+            self.started = true;
+            match self.params.erase {
+                Erase::Range => {
+                    let range = self.params.offset..self.params.offset + self.params.length;
+                    self.erase(range);
+                }
+                Erase::Chip => self.erase(0..self.flash.len()),
+                Erase::None | Erase::Chunk => {}
+            }
+        }
+
+        let dump = matches!(
+            self.ft.direction,
+            Direction::Dump | Direction::Verify | Direction::Hash
+        );
+        while !self.halted {
+            if self.chunk == self.chunks() {
+                // Wait until the host has read all buffers.
+                let busy = if dump {
+                    Control::Full
+                } else {
+                    Control::ReadBack
+                };
+                if (0..self.ft.buffers).all(|slot| self.control(slot) != Some(busy)) {
+                    self.halted = true;
+                }
+                return;
+            }
+            if self.stall_at == Some(self.chunk) {
+                return;
+            }
+            if self.halt_at == Some(self.chunk) {
+                self.halted = true;
+                return;
+            }
+
+            let slot = self.chunk % self.ft.buffers;
+            let offset = self.params.offset + self.chunk * self.ft.buffer_size;
+            // Wait for the host to read or write the buffer.
+            let Some(control) = self
+                .control(slot)
+                .filter(|control| control.owner(self.ft.direction) == Owner::Target)
+            else {
+                return;
+            };
+            if let Some((chunk, code)) = self.fail_at {
+                if chunk == self.chunk {
+                    self.fail(code, offset);
+                    return;
+                }
+            }
+
+            let len = self.ft.buffer_size;
+            match self.ft.direction {
+                Direction::Dump | Direction::Verify => {
+                    let data = self.flash[offset..offset + len].to_vec();
+                    self.buffer(slot).copy_from_slice(&data);
+                    self.set_word(self.ft.checksum(slot).unwrap(), crc32(&data));
+                    self.set_control(slot, Control::Full);
+                }
+                Direction::Hash => {
+                    let checksum = crc32(&self.flash[offset..offset + len]);
+                    self.buffer(slot)[..4].copy_from_slice(&checksum.to_le_bytes());
+                    self.set_control(slot, Control::Full);
+                }
+                Direction::Load => {
+                    self.commit(slot, offset, control);
+                    self.set_control(slot, Control::Free);
+                }
+                Direction::LoadVerify => {
+                    self.commit(slot, offset, control);
+                    let data = self.flash[offset..offset + len].to_vec();
+                    self.buffer(slot).copy_from_slice(&data);
+                    self.set_control(slot, Control::ReadBack);
+                }
+            }
+            self.chunk += 1;
+        }
+    }
+
+    /// Commit the chunk in buffer `slot` to `offset`, as told by `command`.
+    fn commit(&mut self, slot: usize, offset: usize, command: Control) {
+        let len = self.ft.buffer_size;
+        let sector_size = self.ft.sector_size;
+        let sectors = match command {
+            Control::SkipUnchanged => return,
+            Control::SkipErased => {
+                if self.params.erase == Erase::Chunk {
+                    self.erase(offset..offset + len);
+                }
+                return;
+            }
+            Control::WriteSectors => self.word(self.ft.sectors(slot).unwrap()),
+            _ => u32::MAX,
+        };
+        for i in 0..len / sector_size {
+            if sectors & (1 << i) != 0 {
+                let range = i * sector_size..(i + 1) * sector_size;
+                let start = offset + range.start;
+                if self.params.erase == Erase::Chunk {
+                    self.erase(start..start + sector_size);
+                }
+                let data = self.buffer(slot)[range].to_vec();
+                self.program(start, &data);
+            }
+        }
+    }
+}
+
+impl Target for SimTarget {
+    fn read_word_32(&mut self, addr: u64) -> Result<u32> {
+        if self.lag {
+            self.read_since_write = true;
+        } else {
+            self.step();
+        }
+        let range = self.ram_range(addr, 4)?;
+        let mut value = u32::from_le_bytes(self.ram[range].try_into().unwrap());
+        let checksums = CHECKSUM_ADDR..CHECKSUM_ADDR + 4 * self.ft.buffers as u64;
+        if self.corrupt_checksum_reads > 0 && checksums.contains(&addr) {
+            self.corrupt_checksum_reads -= 1;
+            value ^= 0x01;
+        }
+        Ok(value)
+    }
+
+    fn write_word_32(&mut self, addr: u64, value: u32) -> Result<()> {
+        self.read_since_write = false;
+        let range = self.ram_range(addr, 4)?;
+        self.ram[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let range = self.ram_range(addr, data.len())?;
+        data.copy_from_slice(&self.ram[range]);
+        if self.corrupt_reads > 0 && !data.is_empty() {
+            self.corrupt_reads -= 1;
+            data[0] ^= 0x01;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let range = self.ram_range(addr, data.len())?;
+        self.ram[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn core_halted(&mut self) -> Result<bool> {
+        if !self.lag || self.read_since_write {
+            self.step();
+        }
+        Ok(self.halted)
+    }
+
+    fn pump(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn halt_error(&mut self) -> Result<String> {
+        Ok(format!(
+            "target halted unexpectedly at chunk {}",
+            self.chunk
+        ))
+    }
+}
@@ -0,0 +1,1937 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! An ARMv7-M instruction interpreter, to run RAM programs without hardware.
+//!
+//! Only the integer Thumb and Thumb-2 instructions are implemented, which is
+//! what programs built for `thumbv7em-none-eabihf` use unless they do
+//! floating point. There are no exceptions. Like a core with vector catch
+//! enabled, faults and breakpoints halt the core instead.
+
+/// The memory and peripherals the core accesses.
+pub(crate) trait Bus {
+    /// Read `size` (1, 2 or 4) bytes at `addr`, or `None` on a bus fault.
+    fn read(&mut self, addr: u32, size: u32) -> Option<u32>;
+
+    /// Write the low `size` (1, 2 or 4) bytes of `value` to `addr`, or
+    /// `None` on a bus fault.
+    fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()>;
+}
+
+/// Why the core halted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Halt {
+    Breakpoint,
+    /// A fault, or an instruction the interpreter doesn't implement.
+    Fault(String),
+}
+
+const SP: usize = 13;
+const LR: usize = 14;
+const PC: usize = 15;
+
+/// Shift types, as encoded in instructions.
+const LSL: u32 = 0;
+const LSR: u32 = 1;
+const ASR: u32 = 2;
+const ROR: u32 = 3;
+/// Not encoded directly, but as `ROR #0`.
+const RRX: u32 = 4;
+
+/// Data processing operations, as encoded in 32-bit instructions.
+const AND: u32 = 0b0000;
+const BIC: u32 = 0b0001;
+const ORR: u32 = 0b0010;
+const ORN: u32 = 0b0011;
+const EOR: u32 = 0b0100;
+const ADD: u32 = 0b1000;
+const ADC: u32 = 0b1010;
+const SBC: u32 = 0b1011;
+const SUB: u32 = 0b1101;
+const RSB: u32 = 0b1110;
+
+/// Special registers, as encoded in `MRS` and `MSR`.
+const SYSM_MSP: u32 = 8;
+const SYSM_PSP: u32 = 9;
+const SYSM_PRIMASK: u32 = 16;
+
+/// A Cortex-M core.
+pub(crate) struct Core {
+    regs: [u32; 16],
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    /// The condition and mask of the current IT block.
+    itstate: u32,
+    primask: bool,
+    /// An exclusive load hasn't been followed by a store yet.
+    exclusive: bool,
+    /// The address of the next instruction.
+    next_pc: u32,
+    halt: Option<Halt>,
+}
+
+impl Core {
+    /// A core about to execute `reset` with stack pointer `sp`, as if
+    /// started from a vector table.
+    pub(crate) fn new(sp: u32, reset: u32) -> Self {
+        let mut regs = [0; 16];
+        regs[SP] = sp;
+        regs[LR] = u32::MAX;
+        regs[PC] = reset & !1;
+        Self {
+            regs,
+            n: false,
+            z: false,
+            c: false,
+            v: false,
+            itstate: 0,
+            primask: false,
+            exclusive: false,
+            next_pc: 0,
+            halt: None,
+        }
+    }
+
+    /// The address of the next instruction, or of the instruction the core
+    /// halted on.
+    pub(crate) fn pc(&self) -> u32 {
+        self.regs[PC]
+    }
+
+    pub(crate) fn halt(&self) -> Option<&Halt> {
+        self.halt.as_ref()
+    }
+
+    /// Execute up to `steps` instructions, unless the core halts.
+    pub(crate) fn run(&mut self, bus: &mut impl Bus, steps: usize) {
+        for _ in 0..steps {
+            if self.halt.is_some() {
+                return;
+            }
+            if let Err(halt) = self.step(bus) {
+                self.halt = Some(halt);
+            }
+        }
+    }
+
+    fn step(&mut self, bus: &mut impl Bus) -> Result<(), Halt> {
+        let pc = self.regs[PC];
+        let hw1 = self.fetch(bus, pc)?;
+        let wide = hw1 >> 11 >= 0b11101;
+        let hw2 = if wide { self.fetch(bus, pc + 2)? } else { 0 };
+        self.next_pc = pc + if wide { 4 } else { 2 };
+
+        let in_it = self.in_it_block();
+        if !in_it || self.passed(self.itstate >> 4) {
+            if wide {
+                self.exec32(bus, hw1, hw2)?;
+            } else {
+                self.exec16(bus, hw1)?;
+            }
+        }
+        // Advance the IT block, unless the instruction started it.
+        if in_it {
+            self.itstate = if self.itstate & 0x7 == 0 {
+                0
+            } else {
+                (self.itstate & 0xe0) | ((self.itstate << 1) & 0x1f)
+            };
+        }
+        self.regs[PC] = self.next_pc;
+        Ok(())
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus, addr: u32) -> Result<u32, Halt> {
+        bus.read(addr, 2)
+            .ok_or_else(|| fault(format!("bus fault fetching 0x{:08x}", addr)))
+    }
+
+    fn load(&mut self, bus: &mut impl Bus, addr: u32, size: u32) -> Result<u32, Halt> {
+        bus.read(addr, size)
+            .ok_or_else(|| fault(format!("bus fault reading 0x{:08x}", addr)))
+    }
+
+    fn store(&mut self, bus: &mut impl Bus, addr: u32, size: u32, value: u32) -> Result<(), Halt> {
+        bus.write(addr, size, value)
+            .ok_or_else(|| fault(format!("bus fault writing 0x{:08x}", addr)))
+    }
+
+    fn in_it_block(&self) -> bool {
+        self.itstate & 0xf != 0
+    }
+
+    /// Read a register. The PC reads as the current instruction plus 4.
+    fn reg(&self, n: u32) -> u32 {
+        match n as usize {
+            PC => self.regs[PC].wrapping_add(4),
+            n => self.regs[n],
+        }
+    }
+
+    /// Write a register. Writing the PC branches.
+    fn set_reg(&mut self, n: u32, value: u32) {
+        match n as usize {
+            PC => self.next_pc = value & !1,
+            n => self.regs[n] = value,
+        }
+    }
+
+    /// Branch to `addr`, which must have the Thumb bit set.
+    fn branch_exchange(&mut self, addr: u32) -> Result<(), Halt> {
+        if addr & 1 == 0 {
+            return Err(fault(format!("branch to ARM state at 0x{:08x}", addr)));
+        }
+        self.next_pc = addr & !1;
+        Ok(())
+    }
+
+    /// The base address of PC relative literals.
+    fn literal_base(&self) -> u32 {
+        self.reg(PC as u32) & !3
+    }
+
+    fn passed(&self, cond: u32) -> bool {
+        let result = match cond >> 1 {
+            0b000 => self.z,
+            0b001 => self.c,
+            0b010 => self.n,
+            0b011 => self.v,
+            0b100 => self.c && !self.z,
+            0b101 => self.n == self.v,
+            0b110 => self.n == self.v && !self.z,
+            _ => return true,
+        };
+        // Odd conditions are the inverse, except for "always".
+        result != (cond & 1 == 1)
+    }
+
+    fn set_nz(&mut self, result: u32) {
+        self.n = result >> 31 != 0;
+        self.z = result == 0;
+    }
+
+    fn set_nzc(&mut self, result: u32, carry: bool) {
+        self.set_nz(result);
+        self.c = carry;
+    }
+
+    fn set_nzcv(&mut self, result: u32, carry: bool, overflow: bool) {
+        self.set_nz(result);
+        self.c = carry;
+        self.v = overflow;
+    }
+
+    /// `x + y + carry`, setting the flags if `setflags`.
+    fn add_flags(&mut self, x: u32, y: u32, carry: bool, setflags: bool) -> u32 {
+        let (result, carry, overflow) = add_with_carry(x, y, carry);
+        if setflags {
+            self.set_nzcv(result, carry, overflow);
+        }
+        result
+    }
+
+    /// The data processing operations shared by the 32-bit shifted register
+    /// and modified immediate encodings.
+    ///
+    /// Test and compare operations (with `rd` 15 and `setflags`) only set
+    /// the flags.
+    fn data_processing(
+        &mut self,
+        op: u32,
+        setflags: bool,
+        rn: u32,
+        rd: u32,
+        operand: u32,
+        carry: bool,
+    ) -> Result<(), Halt> {
+        // `ORR` and `ORN` without a register are `MOV` and `MVN`.
+        let x = if rn == 15 && matches!(op, ORR | ORN) {
+            0
+        } else {
+            self.reg(rn)
+        };
+        let result = match op {
+            AND | BIC | ORR | ORN | EOR => {
+                let result = match op {
+                    AND => x & operand,
+                    BIC => x & !operand,
+                    ORR => x | operand,
+                    ORN => x | !operand,
+                    _ => x ^ operand,
+                };
+                if setflags {
+                    self.set_nzc(result, carry);
+                }
+                result
+            }
+            ADD => self.add_flags(x, operand, false, setflags),
+            ADC => self.add_flags(x, operand, self.c, setflags),
+            SBC => self.add_flags(x, !operand, self.c, setflags),
+            SUB => self.add_flags(x, !operand, true, setflags),
+            RSB => self.add_flags(!x, operand, true, setflags),
+            _ => return Err(undefined32(op, 0)),
+        };
+        let test = setflags && rd == 15 && matches!(op, AND | EOR | ADD | SUB);
+        if !test {
+            self.set_reg(rd, result);
+        }
+        Ok(())
+    }
+
+    fn exec16(&mut self, bus: &mut impl Bus, op: u32) -> Result<(), Halt> {
+        let setflags = !self.in_it_block();
+        let low = |shift: u32| (op >> shift) & 0x7;
+        match op >> 11 {
+            // Shift by immediate.
+            0b000..=0b010 => {
+                let shift = op >> 11;
+                let (rd, rm, imm5) = (low(0), low(3), (op >> 6) & 0x1f);
+                let (shift, amount) = decode_imm_shift(shift, imm5);
+                let (result, carry) = shift_c(self.reg(rm), shift, amount, self.c);
+                if setflags {
+                    self.set_nzc(result, carry);
+                }
+                self.set_reg(rd, result);
+            }
+            // Add and subtract registers or 3-bit immediates.
+            0b011 => {
+                let (rd, rn) = (low(0), low(3));
+                let operand = if op & 0x400 != 0 {
+                    low(6)
+                } else {
+                    self.reg(low(6))
+                };
+                let x = self.reg(rn);
+                let result = if op & 0x200 != 0 {
+                    self.add_flags(x, !operand, true, setflags)
+                } else {
+                    self.add_flags(x, operand, false, setflags)
+                };
+                self.set_reg(rd, result);
+            }
+            // Move, compare, add and subtract 8-bit immediates.
+            0b100..=0b111 => {
+                let rdn = low(8);
+                let imm8 = op & 0xff;
+                let x = self.reg(rdn);
+                match (op >> 11) & 0x3 {
+                    0b00 => {
+                        if setflags {
+                            self.set_nz(imm8);
+                        }
+                        self.set_reg(rdn, imm8);
+                    }
+                    0b01 => {
+                        self.add_flags(x, !imm8, true, true);
+                    }
+                    0b10 => {
+                        let result = self.add_flags(x, imm8, false, setflags);
+                        self.set_reg(rdn, result);
+                    }
+                    _ => {
+                        let result = self.add_flags(x, !imm8, true, setflags);
+                        self.set_reg(rdn, result);
+                    }
+                }
+            }
+            0b01000 if op & 0x400 == 0 => self.exec16_data_processing(op, setflags)?,
+            0b01000 => self.exec16_special(op)?,
+            // Load literal.
+            0b01001 => {
+                let addr = self.literal_base() + ((op & 0xff) << 2);
+                let value = self.load(bus, addr, 4)?;
+                self.set_reg(low(8), value);
+            }
+            // Load and store with a register offset.
+            0b01010 | 0b01011 => {
+                let (rt, rn, rm) = (low(0), low(3), low(6));
+                let addr = self.reg(rn).wrapping_add(self.reg(rm));
+                let (size, signed, is_load) = match low(9) {
+                    0b000 => (4, false, false),
+                    0b001 => (2, false, false),
+                    0b010 => (1, false, false),
+                    0b011 => (1, true, true),
+                    0b100 => (4, false, true),
+                    0b101 => (2, false, true),
+                    0b110 => (1, false, true),
+                    _ => (2, true, true),
+                };
+                self.load_store(bus, rt, addr, size, signed, is_load)?;
+            }
+            // Load and store with an immediate offset.
+            0b01100..=0b10011 => {
+                let (rt, rn, imm5) = (low(0), low(3), (op >> 6) & 0x1f);
+                let is_load = op & 0x800 != 0;
+                let (size, rn, offset) = match op >> 12 {
+                    0b0110 => (4, rn, imm5 << 2),
+                    0b0111 => (1, rn, imm5),
+                    0b1000 => (2, rn, imm5 << 1),
+                    _ => (4, SP as u32, (op & 0xff) << 2),
+                };
+                let rt = if op >> 12 == 0b1001 { low(8) } else { rt };
+                let addr = self.reg(rn).wrapping_add(offset);
+                self.load_store(bus, rt, addr, size, false, is_load)?;
+            }
+            // Generate a PC or SP relative address.
+            0b10100 => self.set_reg(low(8), self.literal_base() + ((op & 0xff) << 2)),
+            0b10101 => self.set_reg(low(8), self.regs[SP] + ((op & 0xff) << 2)),
+            0b10110 | 0b10111 => self.exec16_misc(bus, op)?,
+            // Store and load multiple, incrementing after.
+            0b11000 | 0b11001 => {
+                let rn = low(8);
+                let list = op & 0xff;
+                let is_load = op & 0x800 != 0;
+                let writeback = !is_load || list & (1 << rn) == 0;
+                self.load_store_multiple(bus, rn, list, false, writeback, is_load)?;
+            }
+            0b11010 | 0b11011 => {
+                let cond = (op >> 8) & 0xf;
+                match cond {
+                    0b1110 => return Err(undefined16(op)),
+                    0b1111 => return Err(fault(format!("supervisor call 0x{:02x}", op & 0xff))),
+                    _ => {
+                        if self.passed(cond) {
+                            let imm = sign_extend((op & 0xff) << 1, 9);
+                            self.next_pc = self.reg(PC as u32).wrapping_add(imm);
+                        }
+                    }
+                }
+            }
+            0b11100 => {
+                let imm = sign_extend((op & 0x7ff) << 1, 12);
+                self.next_pc = self.reg(PC as u32).wrapping_add(imm);
+            }
+            _ => return Err(undefined16(op)),
+        }
+        Ok(())
+    }
+
+    fn exec16_data_processing(&mut self, op: u32, setflags: bool) -> Result<(), Halt> {
+        let (rdn, rm) = (op & 0x7, (op >> 3) & 0x7);
+        let (x, y) = (self.reg(rdn), self.reg(rm));
+        let opcode = (op >> 6) & 0xf;
+        let result = match opcode {
+            0b0000 | 0b0001 | 0b1000 | 0b1100 | 0b1110 | 0b1111 => {
+                let result = match opcode {
+                    0b0000 | 0b1000 => x & y,
+                    0b0001 => x ^ y,
+                    0b1100 => x | y,
+                    0b1110 => x & !y,
+                    _ => !y,
+                };
+                if setflags || opcode == 0b1000 {
+                    self.set_nz(result);
+                }
+                result
+            }
+            // Shift by register.
+            0b0010 | 0b0011 | 0b0100 | 0b0111 => {
+                let shift = match opcode {
+                    0b0010 => LSL,
+                    0b0011 => LSR,
+                    0b0100 => ASR,
+                    _ => ROR,
+                };
+                let (result, carry) = shift_c(x, shift, y & 0xff, self.c);
+                if setflags {
+                    self.set_nzc(result, carry);
+                }
+                result
+            }
+            0b0101 => self.add_flags(x, y, self.c, setflags),
+            0b0110 => self.add_flags(x, !y, self.c, setflags),
+            0b1001 => self.add_flags(!y, 0, true, setflags),
+            0b1010 => self.add_flags(x, !y, true, true),
+            0b1011 => self.add_flags(x, y, false, true),
+            _ => {
+                let result = x.wrapping_mul(y);
+                if setflags {
+                    self.set_nz(result);
+                }
+                result
+            }
+        };
+        // `TST`, `CMP` and `CMN` only set the flags.
+        if !matches!(opcode, 0b1000 | 0b1010 | 0b1011) {
+            self.set_reg(rdn, result);
+        }
+        Ok(())
+    }
+
+    /// Operations on any register, and branch and exchange.
+    fn exec16_special(&mut self, op: u32) -> Result<(), Halt> {
+        let rdn = ((op >> 4) & 0x8) | (op & 0x7);
+        let rm = (op >> 3) & 0xf;
+        match (op >> 8) & 0x3 {
+            0b00 => {
+                let result = self.reg(rdn).wrapping_add(self.reg(rm));
+                self.set_reg(rdn, result);
+            }
+            0b01 => {
+                self.add_flags(self.reg(rdn), !self.reg(rm), true, true);
+            }
+            0b10 => self.set_reg(rdn, self.reg(rm)),
+            _ => {
+                let addr = self.reg(rm);
+                if op & 0x80 != 0 {
+                    self.regs[LR] = self.next_pc | 1;
+                }
+                self.branch_exchange(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn exec16_misc(&mut self, bus: &mut impl Bus, op: u32) -> Result<(), Halt> {
+        match op {
+            // Adjust the SP.
+            _ if op & 0xff00 == 0xb000 => {
+                let imm = (op & 0x7f) << 2;
+                self.regs[SP] = if op & 0x80 != 0 {
+                    self.regs[SP].wrapping_sub(imm)
+                } else {
+                    self.regs[SP].wrapping_add(imm)
+                };
+            }
+            // Compare and branch on (non-)zero.
+            _ if op & 0xf500 == 0xb100 => {
+                let imm = ((op >> 3) & 0x40) | ((op >> 2) & 0x3e);
+                let zero = self.reg(op & 0x7) == 0;
+                if zero != (op & 0x800 != 0) {
+                    self.next_pc = self.reg(PC as u32) + imm;
+                }
+            }
+            // Extend.
+            _ if op & 0xff00 == 0xb200 => {
+                let value = self.reg((op >> 3) & 0x7);
+                let result = match (op >> 6) & 0x3 {
+                    0b00 => value as i16 as i32 as u32,
+                    0b01 => value as i8 as i32 as u32,
+                    0b10 => value & 0xffff,
+                    _ => value & 0xff,
+                };
+                self.set_reg(op & 0x7, result);
+            }
+            // Push, with the LR.
+            _ if op & 0xfe00 == 0xb400 => {
+                let list = (op & 0xff) | ((op & 0x100) << 6);
+                self.load_store_multiple(bus, SP as u32, list, true, true, false)?;
+            }
+            // Change the interrupt mask. Faults can't be masked here.
+            _ if op & 0xffef == 0xb662 => self.primask = op & 0x10 != 0,
+            _ if op & 0xffec == 0xb660 => {}
+            // Reverse bytes.
+            _ if op & 0xff00 == 0xba00 && op & 0xc0 != 0x80 => {
+                let value = self.reg((op >> 3) & 0x7);
+                let result = match (op >> 6) & 0x3 {
+                    0b00 => value.swap_bytes(),
+                    0b01 => rev16(value),
+                    _ => (value as u16).swap_bytes() as i16 as i32 as u32,
+                };
+                self.set_reg(op & 0x7, result);
+            }
+            // Pop, with the PC.
+            _ if op & 0xfe00 == 0xbc00 => {
+                let list = (op & 0xff) | ((op & 0x100) << 7);
+                self.load_store_multiple(bus, SP as u32, list, false, true, true)?;
+            }
+            _ if op & 0xff00 == 0xbe00 => return Err(Halt::Breakpoint),
+            // If-then, and hints.
+            _ if op & 0xff00 == 0xbf00 => {
+                if op & 0xf != 0 {
+                    self.itstate = op & 0xff;
+                }
+            }
+            _ => return Err(undefined16(op)),
+        }
+        Ok(())
+    }
+
+    fn exec32(&mut self, bus: &mut impl Bus, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let op2 = (hw1 >> 4) & 0x7f;
+        match (hw1 >> 11) & 0x3 {
+            0b01 if op2 & 0b1100100 == 0 => self.exec32_multiple(bus, hw1, hw2),
+            0b01 if op2 & 0b1100100 == 0b0000100 => self.exec32_dual(bus, hw1, hw2),
+            0b01 if op2 & 0b1100000 == 0b0100000 => {
+                let (rn, rd, rm) = (hw1 & 0xf, (hw2 >> 8) & 0xf, hw2 & 0xf);
+                let setflags = hw1 & 0x10 != 0;
+                let imm5 = ((hw2 >> 10) & 0x1c) | ((hw2 >> 6) & 0x3);
+                let (shift, amount) = decode_imm_shift((hw2 >> 4) & 0x3, imm5);
+                let (operand, carry) = shift_c(self.reg(rm), shift, amount, self.c);
+                self.data_processing((hw1 >> 5) & 0xf, setflags, rn, rd, operand, carry)
+            }
+            0b10 if hw2 & 0x8000 == 0 && op2 & 0b0100000 == 0 => {
+                let (rn, rd) = (hw1 & 0xf, (hw2 >> 8) & 0xf);
+                let setflags = hw1 & 0x10 != 0;
+                let imm12 = ((hw1 & 0x400) << 1) | ((hw2 >> 4) & 0x700) | (hw2 & 0xff);
+                let (operand, carry) = thumb_expand_imm_c(imm12, self.c);
+                self.data_processing((hw1 >> 5) & 0xf, setflags, rn, rd, operand, carry)
+            }
+            0b10 if hw2 & 0x8000 == 0 => self.exec32_plain_immediate(hw1, hw2),
+            0b10 => self.exec32_branch_misc(hw1, hw2),
+            0b11 if op2 & 0b1110001 == 0 || op2 & 0b1100001 == 0b0000001 => {
+                self.exec32_load_store(bus, hw1, hw2)
+            }
+            0b11 if op2 & 0b1110000 == 0b0100000 => self.exec32_register(hw1, hw2),
+            0b11 if op2 & 0b1111000 == 0b0110000 => {
+                let (rn, rd, rm, ra) = (hw1 & 0xf, (hw2 >> 8) & 0xf, hw2 & 0xf, hw2 >> 12);
+                let product = self.reg(rn).wrapping_mul(self.reg(rm));
+                let result = match ((hw1 >> 4) & 0x7, (hw2 >> 4) & 0x3) {
+                    (0b000, 0b00) if ra == 15 => product,
+                    (0b000, 0b00) => self.reg(ra).wrapping_add(product),
+                    (0b000, 0b01) => self.reg(ra).wrapping_sub(product),
+                    _ => return Err(undefined32(hw1, hw2)),
+                };
+                self.set_reg(rd, result);
+                Ok(())
+            }
+            0b11 if op2 & 0b1111000 == 0b0111000 => self.exec32_long_multiply(hw1, hw2),
+            _ => Err(undefined32(hw1, hw2)),
+        }
+    }
+
+    fn exec32_multiple(&mut self, bus: &mut impl Bus, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let rn = hw1 & 0xf;
+        let writeback = hw1 & 0x20 != 0;
+        let is_load = hw1 & 0x10 != 0;
+        match (hw1 >> 7) & 0x3 {
+            0b01 => self.load_store_multiple(bus, rn, hw2, false, writeback, is_load),
+            0b10 => self.load_store_multiple(bus, rn, hw2, true, writeback, is_load),
+            _ => Err(undefined32(hw1, hw2)),
+        }
+    }
+
+    /// Load and store dual or exclusive, and table branch.
+    fn exec32_dual(&mut self, bus: &mut impl Bus, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let (rn, rt) = (hw1 & 0xf, hw2 >> 12);
+        let op1 = (hw1 >> 7) & 0x3;
+        let op2 = (hw1 >> 4) & 0x3;
+        let op3 = (hw2 >> 4) & 0xf;
+        match (op1, op2) {
+            (0b00, 0b00) | (0b00, 0b01) => {
+                let addr = self.reg(rn).wrapping_add((hw2 & 0xff) << 2);
+                self.exclusive(bus, op2 == 0b01, addr, 4, rt, (hw2 >> 8) & 0xf)
+            }
+            (0b01, 0b00) if op3 == 0b0100 || op3 == 0b0101 => {
+                let size = if op3 == 0b0100 { 1 } else { 2 };
+                self.exclusive(bus, false, self.reg(rn), size, rt, hw2 & 0xf)
+            }
+            (0b01, 0b01) if op3 == 0b0100 || op3 == 0b0101 => {
+                let size = if op3 == 0b0100 { 1 } else { 2 };
+                self.exclusive(bus, true, self.reg(rn), size, rt, 0)
+            }
+            (0b01, 0b01) if op3 == 0b0000 || op3 == 0b0001 => {
+                let (base, index) = (self.reg(rn), self.reg(hw2 & 0xf));
+                let offset = if op3 == 0b0000 {
+                    self.load(bus, base.wrapping_add(index), 1)?
+                } else {
+                    self.load(bus, base.wrapping_add(index << 1), 2)?
+                };
+                self.next_pc = self.reg(PC as u32).wrapping_add(offset << 1);
+                Ok(())
+            }
+            _ if op1 & 0b10 != 0 || op2 & 0b10 != 0 => {
+                let rt2 = (hw2 >> 8) & 0xf;
+                let imm = (hw2 & 0xff) << 2;
+                let index = hw1 & 0x100 != 0;
+                let add = hw1 & 0x80 != 0;
+                let writeback = hw1 & 0x20 != 0;
+                let base = if rn == 15 {
+                    self.literal_base()
+                } else {
+                    self.reg(rn)
+                };
+                let offset_addr = if add {
+                    base.wrapping_add(imm)
+                } else {
+                    base.wrapping_sub(imm)
+                };
+                let addr = if index { offset_addr } else { base };
+                if hw1 & 0x10 != 0 {
+                    let first = self.load(bus, addr, 4)?;
+                    let second = self.load(bus, addr.wrapping_add(4), 4)?;
+                    self.set_reg(rt, first);
+                    self.set_reg(rt2, second);
+                } else {
+                    self.store(bus, addr, 4, self.reg(rt))?;
+                    self.store(bus, addr.wrapping_add(4), 4, self.reg(rt2))?;
+                }
+                if writeback {
+                    self.set_reg(rn, offset_addr);
+                }
+                Ok(())
+            }
+            _ => Err(undefined32(hw1, hw2)),
+        }
+    }
+
+    /// An exclusive load into `rt`, or store from `rt` with the status in
+    /// `rd`. There is only one core, so stores only fail without a load.
+    fn exclusive(
+        &mut self,
+        bus: &mut impl Bus,
+        is_load: bool,
+        addr: u32,
+        size: u32,
+        rt: u32,
+        rd: u32,
+    ) -> Result<(), Halt> {
+        if is_load {
+            let value = self.load(bus, addr, size)?;
+            self.set_reg(rt, value);
+            self.exclusive = true;
+        } else if self.exclusive {
+            self.store(bus, addr, size, self.reg(rt))?;
+            self.exclusive = false;
+            self.set_reg(rd, 0);
+        } else {
+            self.set_reg(rd, 1);
+        }
+        Ok(())
+    }
+
+    fn exec32_plain_immediate(&mut self, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let (rn, rd) = (hw1 & 0xf, (hw2 >> 8) & 0xf);
+        let imm12 = ((hw1 & 0x400) << 1) | ((hw2 >> 4) & 0x700) | (hw2 & 0xff);
+        let imm16 = ((hw1 & 0xf) << 12) | imm12;
+        let lsb = ((hw2 >> 10) & 0x1c) | ((hw2 >> 6) & 0x3);
+        let msb = hw2 & 0x1f;
+        let x = self.reg(rn);
+        let result = match (hw1 >> 4) & 0x1f {
+            0b00000 if rn == 15 => self.literal_base().wrapping_add(imm12),
+            0b00000 => x.wrapping_add(imm12),
+            0b01010 if rn == 15 => self.literal_base().wrapping_sub(imm12),
+            0b01010 => x.wrapping_sub(imm12),
+            0b00100 => imm16,
+            0b01100 => (self.reg(rd) & 0xffff) | (imm16 << 16),
+            // Bit field extract, with `msb` as the width minus one.
+            0b10100 => sign_extend(x.checked_shr(lsb).unwrap_or(0), msb + 1),
+            0b11100 => (x >> lsb) & mask(msb + 1),
+            // Bit field insert or clear.
+            0b10110 if msb >= lsb => {
+                let bits = mask(msb - lsb + 1) << lsb;
+                let insert = if rn == 15 { 0 } else { x << lsb };
+                (self.reg(rd) & !bits) | (insert & bits)
+            }
+            _ => return Err(undefined32(hw1, hw2)),
+        };
+        self.set_reg(rd, result);
+        Ok(())
+    }
+
+    fn exec32_branch_misc(&mut self, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let s = (hw1 >> 10) & 1;
+        let j1 = (hw2 >> 13) & 1;
+        let j2 = (hw2 >> 11) & 1;
+        let op = (hw1 >> 4) & 0x7f;
+        match (hw2 >> 12) & 0x5 {
+            0b000 if op & 0b0111000 != 0b0111000 => {
+                let imm = (s << 20) | (j2 << 19) | (j1 << 18) | ((hw1 & 0x3f) << 12);
+                let imm = sign_extend(imm | ((hw2 & 0x7ff) << 1), 21);
+                if self.passed((hw1 >> 6) & 0xf) {
+                    self.next_pc = self.reg(PC as u32).wrapping_add(imm);
+                }
+                Ok(())
+            }
+            0b000 => self.exec32_system(hw1, hw2),
+            0b001 | 0b101 => {
+                let i1 = !(j1 ^ s) & 1;
+                let i2 = !(j2 ^ s) & 1;
+                let imm = (s << 24) | (i1 << 23) | (i2 << 22) | ((hw1 & 0x3ff) << 12);
+                let imm = sign_extend(imm | ((hw2 & 0x7ff) << 1), 25);
+                if hw2 & 0x4000 != 0 {
+                    self.regs[LR] = self.next_pc | 1;
+                }
+                self.next_pc = self.reg(PC as u32).wrapping_add(imm);
+                Ok(())
+            }
+            _ => Err(undefined32(hw1, hw2)),
+        }
+    }
+
+    /// Special register access, hints and barriers.
+    fn exec32_system(&mut self, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let sysm = hw2 & 0xff;
+        match hw1 & 0xfff0 {
+            0xf380 => match sysm {
+                SYSM_MSP | SYSM_PSP => self.regs[SP] = self.reg(hw1 & 0xf),
+                SYSM_PRIMASK => self.primask = self.reg(hw1 & 0xf) & 1 != 0,
+                // Writes to other registers are ignored.
+                _ => {}
+            },
+            // Hints, e.g. `NOP` and `YIELD`. Waiting for events or
+            // interrupts doesn't wait, as there are none.
+            0xf3a0 => {}
+            // Barriers, and clear exclusive.
+            0xf3b0 => {
+                if (hw2 >> 4) & 0xf == 0b0010 {
+                    self.exclusive = false;
+                }
+            }
+            0xf3e0 => {
+                let value = match sysm {
+                    // The xPSR, with only the flags.
+                    0..=7 => {
+                        (self.n as u32) << 31
+                            | (self.z as u32) << 30
+                            | (self.c as u32) << 29
+                            | (self.v as u32) << 28
+                    }
+                    SYSM_MSP | SYSM_PSP => self.regs[SP],
+                    SYSM_PRIMASK => self.primask as u32,
+                    _ => 0,
+                };
+                self.set_reg((hw2 >> 8) & 0xf, value);
+            }
+            _ => return Err(undefined32(hw1, hw2)),
+        }
+        Ok(())
+    }
+
+    /// Load and store single values.
+    fn exec32_load_store(&mut self, bus: &mut impl Bus, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let (rn, rt) = (hw1 & 0xf, hw2 >> 12);
+        let signed = hw1 & 0x100 != 0;
+        let size = 1 << ((hw1 >> 5) & 0x3);
+        let is_load = hw1 & 0x10 != 0;
+        if size == 8 {
+            return Err(undefined32(hw1, hw2));
+        }
+
+        let (addr, writeback) = if rn == 15 {
+            if !is_load {
+                return Err(undefined32(hw1, hw2));
+            }
+            let imm12 = hw2 & 0xfff;
+            let base = self.literal_base();
+            if hw1 & 0x80 != 0 {
+                (base.wrapping_add(imm12), None)
+            } else {
+                (base.wrapping_sub(imm12), None)
+            }
+        } else if hw1 & 0x80 != 0 {
+            (self.reg(rn).wrapping_add(hw2 & 0xfff), None)
+        } else if hw2 & 0x800 != 0 {
+            let imm8 = hw2 & 0xff;
+            let index = hw2 & 0x400 != 0;
+            let add = hw2 & 0x200 != 0;
+            let base = self.reg(rn);
+            let offset_addr = if add {
+                base.wrapping_add(imm8)
+            } else {
+                base.wrapping_sub(imm8)
+            };
+            let addr = if index { offset_addr } else { base };
+            (addr, (hw2 & 0x100 != 0).then_some(offset_addr))
+        } else if hw2 & 0xfc0 == 0 {
+            let offset = self.reg(hw2 & 0xf) << ((hw2 >> 4) & 0x3);
+            (self.reg(rn).wrapping_add(offset), None)
+        } else {
+            return Err(undefined32(hw1, hw2));
+        };
+
+        // Byte and halfword loads into the PC are preload hints.
+        if is_load && rt == 15 && size != 4 {
+            return Ok(());
+        }
+        self.load_store(bus, rt, addr, size, signed, is_load)?;
+        if let Some(offset_addr) = writeback {
+            self.set_reg(rn, offset_addr);
+        }
+        Ok(())
+    }
+
+    /// Shifts by register, extends, and miscellaneous operations.
+    fn exec32_register(&mut self, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        if hw2 & 0xf000 != 0xf000 {
+            return Err(undefined32(hw1, hw2));
+        }
+        let (rn, rd, rm) = (hw1 & 0xf, (hw2 >> 8) & 0xf, hw2 & 0xf);
+        let op1 = (hw1 >> 4) & 0xf;
+        let op2 = (hw2 >> 4) & 0xf;
+        let result = match (op1, op2) {
+            (0b0000..=0b0111, 0b0000) => {
+                let shift = (op1 >> 1) & 0x3;
+                let amount = self.reg(rm) & 0xff;
+                let (result, carry) = shift_c(self.reg(rn), shift, amount, self.c);
+                if op1 & 1 != 0 {
+                    self.set_nzc(result, carry);
+                }
+                result
+            }
+            (0b0000..=0b0101, 0b1000..=0b1011) => {
+                let value = self.reg(rm).rotate_right((op2 & 0x3) * 8);
+                let add = if rn == 15 { 0 } else { self.reg(rn) };
+                match op1 {
+                    0b0000 => add.wrapping_add(value as i16 as i32 as u32),
+                    0b0001 => add.wrapping_add(value & 0xffff),
+                    0b0011 => {
+                        let low = (add & 0xffff).wrapping_add(value & 0xff) & 0xffff;
+                        let high = (add >> 16).wrapping_add((value >> 16) & 0xff) & 0xffff;
+                        (high << 16) | low
+                    }
+                    0b0100 => add.wrapping_add(value as i8 as i32 as u32),
+                    0b0101 => add.wrapping_add(value & 0xff),
+                    _ => return Err(undefined32(hw1, hw2)),
+                }
+            }
+            (0b1001, 0b1000) => self.reg(rm).swap_bytes(),
+            (0b1001, 0b1001) => rev16(self.reg(rm)),
+            (0b1001, 0b1010) => self.reg(rm).reverse_bits(),
+            (0b1001, 0b1011) => (self.reg(rm) as u16).swap_bytes() as i16 as i32 as u32,
+            (0b1011, 0b1000) => self.reg(rm).leading_zeros(),
+            _ => return Err(undefined32(hw1, hw2)),
+        };
+        self.set_reg(rd, result);
+        Ok(())
+    }
+
+    /// Long multiplies, and divides.
+    fn exec32_long_multiply(&mut self, hw1: u32, hw2: u32) -> Result<(), Halt> {
+        let (rn, rm) = (hw1 & 0xf, hw2 & 0xf);
+        let (rd_lo, rd_hi) = (hw2 >> 12, (hw2 >> 8) & 0xf);
+        let (x, y) = (self.reg(rn), self.reg(rm));
+        let acc = ((self.reg(rd_hi) as u64) << 32) | self.reg(rd_lo) as u64;
+        let result = match ((hw1 >> 4) & 0x7, (hw2 >> 4) & 0xf) {
+            // Divides by zero result in zero, as division by zero isn't
+            // trapped by default.
+            (0b001, 0b1111) => {
+                let (x, y) = (x as i32, y as i32);
+                let result = if y == 0 { 0 } else { x.wrapping_div(y) };
+                self.set_reg(rd_hi, result as u32);
+                return Ok(());
+            }
+            (0b011, 0b1111) => {
+                self.set_reg(rd_hi, x.checked_div(y).unwrap_or(0));
+                return Ok(());
+            }
+            (0b000, 0b0000) => (x as i32 as i64 * y as i32 as i64) as u64,
+            (0b010, 0b0000) => x as u64 * y as u64,
+            (0b100, 0b0000) => acc.wrapping_add((x as i32 as i64 * y as i32 as i64) as u64),
+            (0b110, 0b0000) => acc.wrapping_add(x as u64 * y as u64),
+            (0b110, 0b0110) => {
+                x as u64 * y as u64 + self.reg(rd_hi) as u64 + self.reg(rd_lo) as u64
+            }
+            _ => return Err(undefined32(hw1, hw2)),
+        };
+        self.set_reg(rd_lo, result as u32);
+        self.set_reg(rd_hi, (result >> 32) as u32);
+        Ok(())
+    }
+
+    fn load_store(
+        &mut self,
+        bus: &mut impl Bus,
+        rt: u32,
+        addr: u32,
+        size: u32,
+        signed: bool,
+        is_load: bool,
+    ) -> Result<(), Halt> {
+        if !is_load {
+            return self.store(bus, addr, size, self.reg(rt));
+        }
+        let value = self.load(bus, addr, size)?;
+        let value = if signed {
+            sign_extend(value, size * 8)
+        } else {
+            value
+        };
+        if rt == 15 {
+            self.branch_exchange(value)
+        } else {
+            self.set_reg(rt, value);
+            Ok(())
+        }
+    }
+
+    /// Load or store the registers in `list` at `rn`, incrementing after or
+    /// decrementing before.
+    fn load_store_multiple(
+        &mut self,
+        bus: &mut impl Bus,
+        rn: u32,
+        list: u32,
+        decrement: bool,
+        writeback: bool,
+        is_load: bool,
+    ) -> Result<(), Halt> {
+        let base = self.reg(rn);
+        let len = list.count_ones() * 4;
+        let start = if decrement {
+            base.wrapping_sub(len)
+        } else {
+            base
+        };
+        let mut addr = start;
+        let mut branch = None;
+        for reg in (0..16).filter(|reg| list & (1 << reg) != 0) {
+            if is_load {
+                let value = self.load(bus, addr, 4)?;
+                if reg == 15 {
+                    branch = Some(value);
+                } else {
+                    self.set_reg(reg, value);
+                }
+            } else {
+                self.store(bus, addr, 4, self.reg(reg))?;
+            }
+            addr = addr.wrapping_add(4);
+        }
+        if writeback {
+            let end = if decrement {
+                start
+            } else {
+                base.wrapping_add(len)
+            };
+            self.set_reg(rn, end);
+        }
+        match branch {
+            Some(addr) => self.branch_exchange(addr),
+            None => Ok(()),
+        }
+    }
+}
+
+fn fault(message: String) -> Halt {
+    Halt::Fault(message)
+}
+
+fn undefined16(op: u32) -> Halt {
+    fault(format!("undefined instruction 0x{:04x}", op))
+}
+
+fn undefined32(hw1: u32, hw2: u32) -> Halt {
+    fault(format!("undefined instruction 0x{:04x} 0x{:04x}", hw1, hw2))
+}
+
+/// The low `bits` bits set.
+fn mask(bits: u32) -> u32 {
+    u32::MAX.checked_shr(32 - bits).unwrap_or(0)
+}
+
+/// Sign extend the low `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+fn rev16(value: u32) -> u32 {
+    ((value & 0x00ff_00ff) << 8) | ((value & 0xff00_ff00) >> 8)
+}
+
+fn add_with_carry(x: u32, y: u32, carry: bool) -> (u32, bool, bool) {
+    let sum = x as u64 + y as u64 + carry as u64;
+    let result = sum as u32;
+    let overflow = ((x ^ result) & (y ^ result)) >> 31 != 0;
+    (result, sum >> 32 != 0, overflow)
+}
+
+/// The shift and amount encoded as `shift` and `imm5`.
+fn decode_imm_shift(shift: u32, imm5: u32) -> (u32, u32) {
+    match (shift, imm5) {
+        (LSR | ASR, 0) => (shift, 32),
+        (ROR, 0) => (RRX, 1),
+        _ => (shift, imm5),
+    }
+}
+
+/// Shift `value`, returning the result and carry out.
+fn shift_c(value: u32, shift: u32, amount: u32, carry: bool) -> (u32, bool) {
+    if amount == 0 {
+        return (value, carry);
+    }
+    let bit = |n: u32| value.checked_shr(n).unwrap_or(0) & 1 != 0;
+    match shift {
+        LSL => (
+            value.checked_shl(amount).unwrap_or(0),
+            amount <= 32 && bit(32 - amount),
+        ),
+        LSR => (value.checked_shr(amount).unwrap_or(0), bit(amount - 1)),
+        ASR if amount >= 32 => {
+            let negative = value >> 31 != 0;
+            (if negative { u32::MAX } else { 0 }, negative)
+        }
+        ASR => (((value as i32) >> amount) as u32, bit(amount - 1)),
+        ROR => {
+            let result = value.rotate_right(amount % 32);
+            (result, result >> 31 != 0)
+        }
+        _ => (((carry as u32) << 31) | (value >> 1), value & 1 != 0),
+    }
+}
+
+/// Expand a modified immediate constant, returning it and the carry out.
+fn thumb_expand_imm_c(imm12: u32, carry: bool) -> (u32, bool) {
+    if imm12 >> 10 == 0 {
+        let imm8 = imm12 & 0xff;
+        let value = match (imm12 >> 8) & 0x3 {
+            0b00 => imm8,
+            0b01 => (imm8 << 16) | imm8,
+            0b10 => (imm8 << 24) | (imm8 << 8),
+            _ => imm8 * 0x0101_0101,
+        };
+        (value, carry)
+    } else {
+        let value = (0x80 | (imm12 & 0x7f)).rotate_right(imm12 >> 7);
+        (value, value >> 31 != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RAM at address 0, with the program at the start.
+    struct Ram(Vec<u8>);
+
+    impl Bus for Ram {
+        fn read(&mut self, addr: u32, size: u32) -> Option<u32> {
+            let bytes = self.0.get(addr as usize..(addr + size) as usize)?;
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| value << 8 | *byte as u32),
+            )
+        }
+
+        fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+            let bytes = self.0.get_mut(addr as usize..(addr + size) as usize)?;
+            bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+            Some(())
+        }
+    }
+
+    /// Run `program` until it hits a breakpoint, and return the registers.
+    fn run(program: &[u16]) -> [u32; 16] {
+        let mut ram = Ram(vec![0; 0x1000]);
+        for (i, op) in program.iter().enumerate() {
+            ram.write(i as u32 * 2, 2, *op as u32).unwrap();
+        }
+        let mut core = Core::new(0x1000, 1);
+        core.run(&mut ram, 1000);
+        assert_eq!(core.halt(), Some(&Halt::Breakpoint));
+        core.regs
+    }
+
+    #[test]
+    fn it_block_skips_and_keeps_flags() {
+        let regs = run(&[
+            0x2005, // movs r0, #5
+            0x2805, // cmp r0, #5
+            0xbf0c, // ite eq
+            0x2101, // moveq r1, #1
+            0x2102, // movne r1, #2
+            0xbf18, // it ne
+            0x3001, // addne r0, #1
+            0xbe00, // bkpt
+        ]);
+        assert_eq!(regs[0..2], [5, 1]);
+    }
+
+    #[test]
+    fn divides_and_multiplies_long() {
+        let regs = run(&[
+            0xf04f, 0x30ff, // mov.w r0, #0xffffffff
+            0x2103, // movs r1, #3
+            0xfbb0, 0xf2f1, // udiv r2, r0, r1
+            0xfba0, 0x3401, // umull r3, r4, r0, r1
+            0x2500, // movs r5, #0
+            0xfbb0, 0xf6f5, // udiv r6, r0, r5
+            0xbe00, // bkpt
+        ]);
+        assert_eq!(regs[2..7], [0x5555_5555, 0xffff_fffd, 2, 0, 0]);
+    }
+
+    #[test]
+    fn bit_fields() {
+        let regs = run(&[
+            0xf240, 0x1034, // movw r0, #0x134
+            0xf3c0, 0x1103, // ubfx r1, r0, #4, #4
+            0xf04f, 0x32ff, // mov.w r2, #0xffffffff
+            0xf360, 0x120b, // bfi r2, r0, #4, #8
+            0xf36f, 0x0203, // bfc r2, #0, #4
+            0xfab0, 0xf380, // clz r3, r0
+            0xbe00, // bkpt
+        ]);
+        assert_eq!(regs[0..4], [0x134, 0x3, 0xffff_f340, 23]);
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let regs = run(&[
+            0xf000, 0xf803, // bl 0x0a
+            0x1c40, // adds r0, r0, #1
+            0xbe00, // bkpt
+            0xbf00, // nop
+            0xb500, // push {lr}
+            0x2029, // movs r0, #41
+            0xbd00, // pop {pc}
+        ]);
+        assert_eq!(regs[0], 42);
+        assert_eq!(regs[SP], 0x1000);
+    }
+
+    #[test]
+    fn halts_on_undefined_instructions() {
+        let mut ram = Ram(vec![0; 0x10]);
+        ram.write(0, 2, 0x2001).unwrap(); // movs r0, #1
+        ram.write(2, 2, 0xdefe).unwrap(); // udf #254
+        let mut core = Core::new(0x10, 1);
+        core.run(&mut ram, 10);
+        assert_eq!(
+            core.halt(),
+            Some(&Halt::Fault("undefined instruction 0xdefe".to_string()))
+        );
+        assert_eq!(core.pc(), 2);
+    }
+
+    const N: u32 = 0b1000;
+    const Z: u32 = 0b0100;
+    const C: u32 = 0b0010;
+    const V: u32 = 0b0001;
+    /// The address single instructions run at, and the stack pointer.
+    const PROGRAM: u32 = 0x100;
+    const STACK: u32 = 0x800;
+
+    fn nzcv(core: &Core) -> u32 {
+        (core.n as u32) << 3 | (core.z as u32) << 2 | (core.c as u32) << 1 | core.v as u32
+    }
+
+    /// Run the single instruction `op` at `PROGRAM`, with `regs` and the flags
+    /// `nzcv`. The rest of the RAM holds the low byte of its address.
+    fn step(op: &[u16], regs: &[(usize, u32)], nzcv: u32) -> (Core, Ram) {
+        let mut ram = Ram((0..0x1000).map(|addr| addr as u8).collect());
+        for (i, hw) in op.iter().enumerate() {
+            ram.write(PROGRAM + i as u32 * 2, 2, *hw as u32).unwrap();
+        }
+        let mut core = Core::new(STACK, PROGRAM);
+        for &(n, value) in regs {
+            core.regs[n] = value;
+        }
+        core.n = nzcv & N != 0;
+        core.z = nzcv & Z != 0;
+        core.c = nzcv & C != 0;
+        core.v = nzcv & V != 0;
+        core.run(&mut ram, 1);
+        assert_eq!(core.halt(), None, "{:04x?}", op);
+        (core, ram)
+    }
+
+    /// Check that `op` sets the registers and flags as `expected`.
+    fn check(
+        op: &[u16],
+        regs: &[(usize, u32)],
+        flags: u32,
+        expected: &[(usize, u32)],
+        nzcv_after: u32,
+    ) {
+        let (core, _) = step(op, regs, flags);
+        for &(n, value) in expected {
+            assert_eq!(core.regs[n], value, "{:04x?}: r{}", op, n);
+        }
+        assert_eq!(nzcv(&core), nzcv_after, "{:04x?}: flags", op);
+    }
+
+    /// Check that `op` stores the `words`, and sets the registers as
+    /// `expected`.
+    fn check_store(
+        op: &[u16],
+        regs: &[(usize, u32)],
+        words: &[(u32, u32)],
+        expected: &[(usize, u32)],
+    ) {
+        let (core, mut ram) = step(op, regs, 0);
+        for &(addr, value) in words {
+            assert_eq!(ram.read(addr, 4), Some(value), "{:04x?}: 0x{:x}", op, addr);
+        }
+        for &(n, value) in expected {
+            assert_eq!(core.regs[n], value, "{:04x?}: r{}", op, n);
+        }
+    }
+
+    #[test]
+    fn decodes_shifts_adds_and_compares() {
+        // lsls r0, r1, #4
+        check(
+            &[0x0108],
+            &[(1, 0x1800_0001)],
+            0,
+            &[(0, 0x8000_0010)],
+            N | C,
+        );
+        // lsrs r0, r1, #1
+        check(&[0x0848], &[(1, 3)], 0, &[(0, 1)], C);
+        // asrs r0, r1, #31
+        check(&[0x17c8], &[(1, 0x8000_0000)], 0, &[(0, u32::MAX)], N);
+        // adds r0, r1, r2
+        check(
+            &[0x1888],
+            &[(1, 0x7fff_ffff), (2, 1)],
+            0,
+            &[(0, 0x8000_0000)],
+            N | V,
+        );
+        // subs r0, r1, #1
+        check(&[0x1e48], &[(1, 0)], 0, &[(0, u32::MAX)], N);
+        // movs r0, #255
+        check(&[0x20ff], &[], Z | C, &[(0, 255)], C);
+        // cmp r0, #1
+        check(&[0x2801], &[(0, 1)], 0, &[(0, 1)], Z | C);
+        // adds r0, #200
+        check(
+            &[0x30c8],
+            &[(0, 200u32.wrapping_neg())],
+            0,
+            &[(0, 0)],
+            Z | C,
+        );
+    }
+
+    #[test]
+    fn decodes_data_processing() {
+        // ands r0, r1
+        check(&[0x4008], &[(0, 0xf0f0), (1, 0xff00)], 0, &[(0, 0xf000)], 0);
+        // eors r0, r1
+        check(&[0x4048], &[(0, 0xf0f0), (1, 0xf0f0)], 0, &[(0, 0)], Z);
+        // lsls r0, r1
+        check(&[0x4088], &[(0, 1), (1, 32)], 0, &[(0, 0)], Z | C);
+        // lsrs r0, r1
+        check(&[0x40c8], &[(0, 0x8000_0000), (1, 33)], C, &[(0, 0)], Z);
+        // asrs r0, r1
+        check(
+            &[0x4108],
+            &[(0, 0x8000_0000), (1, 40)],
+            0,
+            &[(0, u32::MAX)],
+            N | C,
+        );
+        // adcs r0, r1
+        check(&[0x4148], &[(0, 1), (1, 2)], C, &[(0, 4)], 0);
+        // sbcs r0, r1
+        check(&[0x4188], &[(0, 5), (1, 2)], 0, &[(0, 2)], C);
+        // rors r0, r1
+        check(&[0x41c8], &[(0, 0xf1), (1, 4)], 0, &[(0, 0x1000_000f)], 0);
+        // tst r0, r1
+        check(&[0x4208], &[(0, 1), (1, 2)], 0, &[(0, 1)], Z);
+        // rsbs r0, r1, #0
+        check(&[0x4248], &[(1, 1)], 0, &[(0, u32::MAX)], N);
+        // cmn r0, r1
+        check(
+            &[0x42c8],
+            &[(0, u32::MAX), (1, 1)],
+            0,
+            &[(0, u32::MAX)],
+            Z | C,
+        );
+        // orrs r0, r1
+        check(&[0x4308], &[(0, 0x0f), (1, 0xf0)], 0, &[(0, 0xff)], 0);
+        // muls r0, r1, r0
+        check(
+            &[0x4348],
+            &[(0, 3), (1, 0x8000_0000)],
+            C,
+            &[(0, 0x8000_0000)],
+            N | C,
+        );
+        // bics r0, r1
+        check(&[0x4388], &[(0, 0xff), (1, 0x0f)], 0, &[(0, 0xf0)], 0);
+        // mvns r0, r1
+        check(&[0x43c8], &[(1, 0)], 0, &[(0, u32::MAX)], N);
+        // add r8, r1
+        check(&[0x4488], &[(8, 5), (1, 6)], 0, &[(8, 11)], 0);
+        // cmp r8, r1
+        check(&[0x4588], &[(8, 1), (1, 2)], 0, &[(8, 1)], N);
+        // mov r9, r2
+        check(&[0x4691], &[(2, 7)], 0, &[(9, 7)], 0);
+        // mov r0, r1
+        check(&[0x4608], &[(1, 7)], Z, &[(0, 7)], Z);
+        // bx lr
+        check(&[0x4770], &[(LR, 0x201)], 0, &[(PC, 0x200)], 0);
+        // blx r1
+        check(&[0x4788], &[(1, 0x301)], 0, &[(PC, 0x300), (LR, 0x103)], 0);
+    }
+
+    #[test]
+    fn decodes_loads() {
+        // ldr r0, [pc, #4]
+        check(&[0x4801], &[], 0, &[(0, 0x0b0a_0908)], 0);
+        // ldr r0, [r1, r2]
+        check(&[0x5888], &[(1, 0x800), (2, 4)], 0, &[(0, 0x0706_0504)], 0);
+        // ldrh r0, [r1, r2]
+        check(&[0x5a88], &[(1, 0x8fe)], 0, &[(0, 0xfffe)], 0);
+        // ldrb r0, [r1, r2]
+        check(&[0x5c88], &[(1, 0x880), (2, 1)], 0, &[(0, 0x81)], 0);
+        // ldrsb r0, [r1, r2]
+        check(&[0x5688], &[(1, 0x880), (2, 1)], 0, &[(0, 0xffff_ff81)], 0);
+        // ldrsh r0, [r1, r2]
+        check(&[0x5e88], &[(1, 0x8fe)], 0, &[(0, 0xffff_fffe)], 0);
+        // ldr r0, [r1, #4]
+        check(&[0x6848], &[(1, 0x810)], 0, &[(0, 0x1716_1514)], 0);
+        // ldrb r0, [r1, #1]
+        check(&[0x7848], &[(1, 0x810)], 0, &[(0, 0x11)], 0);
+        // ldrh r0, [r1, #2]
+        check(&[0x8848], &[(1, 0x810)], 0, &[(0, 0x1312)], 0);
+        // ldr r0, [sp, #4]
+        check(&[0x9801], &[], 0, &[(0, 0x0706_0504)], 0);
+        // adr r0, #8
+        check(&[0xa002], &[], 0, &[(0, 0x10c)], 0);
+        // add r0, sp, #8
+        check(&[0xa802], &[], 0, &[(0, 0x808)], 0);
+        // add sp, #16
+        check(&[0xb004], &[], 0, &[(SP, 0x810)], 0);
+        // sub sp, #16
+        check(&[0xb084], &[], 0, &[(SP, 0x7f0)], 0);
+        // pop {r0, r1}
+        check(
+            &[0xbc03],
+            &[],
+            0,
+            &[(0, 0x0302_0100), (1, 0x0706_0504), (SP, 0x808)],
+            0,
+        );
+        // ldm r0!, {r1, r2}
+        check(
+            &[0xc806],
+            &[(0, 0x800)],
+            0,
+            &[(0, 0x808), (1, 0x0302_0100), (2, 0x0706_0504)],
+            0,
+        );
+        // ldm r0, {r0, r1}
+        check(
+            &[0xc803],
+            &[(0, 0x800)],
+            0,
+            &[(0, 0x0302_0100), (1, 0x0706_0504)],
+            0,
+        );
+        // ldm.w r0, {r1, r2, r3}
+        check(
+            &[0xe890, 0x000e],
+            &[(0, 0x800)],
+            0,
+            &[
+                (0, 0x800),
+                (1, 0x0302_0100),
+                (2, 0x0706_0504),
+                (3, 0x0b0a_0908),
+            ],
+            0,
+        );
+        // pop.w {r4, r5}
+        check(
+            &[0xe8bd, 0x0030],
+            &[],
+            0,
+            &[(4, 0x0302_0100), (5, 0x0706_0504), (SP, 0x808)],
+            0,
+        );
+        // ldrd r2, r3, [r0, #8]
+        check(
+            &[0xe9d0, 0x2302],
+            &[(0, 0x800)],
+            0,
+            &[(0, 0x800), (2, 0x0b0a_0908), (3, 0x0f0e_0d0c)],
+            0,
+        );
+        // ldrd r2, r3, [r0], #8
+        check(
+            &[0xe8f0, 0x2302],
+            &[(0, 0x800)],
+            0,
+            &[(0, 0x808), (2, 0x0302_0100), (3, 0x0706_0504)],
+            0,
+        );
+        // ldrex r2, [r0]
+        check(&[0xe850, 0x2f00], &[(0, 0x800)], 0, &[(2, 0x0302_0100)], 0);
+        // ldrexb r2, [r0]
+        check(&[0xe8d0, 0x2f4f], &[(0, 0x881)], 0, &[(2, 0x81)], 0);
+        // ldr.w r0, [r1, #0x804]
+        check(&[0xf8d1, 0x0804], &[], 0, &[(0, 0x0706_0504)], 0);
+        // ldr r0, [r1, #-4]
+        check(
+            &[0xf851, 0x0c04],
+            &[(1, 0x808)],
+            0,
+            &[(0, 0x0706_0504), (1, 0x808)],
+            0,
+        );
+        // ldr r0, [r1, #4]!
+        check(
+            &[0xf851, 0x0f04],
+            &[(1, 0x800)],
+            0,
+            &[(0, 0x0706_0504), (1, 0x804)],
+            0,
+        );
+        // ldr r0, [r1], #-4
+        check(
+            &[0xf851, 0x0904],
+            &[(1, 0x800)],
+            0,
+            &[(0, 0x0302_0100), (1, 0x7fc)],
+            0,
+        );
+        // ldrb r0, [r1, #-1]
+        check(&[0xf811, 0x0c01], &[(1, 0x881)], 0, &[(0, 0x80)], 0);
+        // ldrsb.w r0, [r1, #1]
+        check(&[0xf991, 0x0001], &[(1, 0x87f)], 0, &[(0, 0xffff_ff80)], 0);
+        // ldrsh.w r0, [r1, #2]
+        check(&[0xf9b1, 0x0002], &[(1, 0x8fc)], 0, &[(0, 0xffff_fffe)], 0);
+        // ldrb.w r0, [r1, r2, lsl #2]
+        check(&[0xf811, 0x0022], &[(1, 0x800), (2, 4)], 0, &[(0, 0x10)], 0);
+        // ldr.w r0, [pc, #-4]
+        check(&[0xf85f, 0x0004], &[], 0, &[(0, 0x0004_f85f)], 0);
+    }
+
+    #[test]
+    fn decodes_stores() {
+        const VALUE: u32 = 0xaabb_ccdd;
+        // str r0, [r1, r2]
+        check_store(
+            &[0x5088],
+            &[(0, VALUE), (1, 0x800), (2, 4)],
+            &[(0x804, VALUE)],
+            &[],
+        );
+        // strh r0, [r1, r2]
+        check_store(
+            &[0x5288],
+            &[(0, VALUE), (1, 0x800), (2, 4)],
+            &[(0x804, 0x0706_ccdd)],
+            &[],
+        );
+        // strb r0, [r1, r2]
+        check_store(
+            &[0x5488],
+            &[(0, VALUE), (1, 0x800), (2, 4)],
+            &[(0x804, 0x0706_05dd)],
+            &[],
+        );
+        // str r0, [r1, #4]
+        check_store(&[0x6048], &[(0, VALUE), (1, 0x800)], &[(0x804, VALUE)], &[]);
+        // strb r0, [r1, #1]
+        check_store(
+            &[0x7048],
+            &[(0, VALUE), (1, 0x800)],
+            &[(0x800, 0x0302_dd00)],
+            &[],
+        );
+        // strh r0, [r1, #2]
+        check_store(
+            &[0x8048],
+            &[(0, VALUE), (1, 0x800)],
+            &[(0x800, 0xccdd_0100)],
+            &[],
+        );
+        // str r0, [sp, #4]
+        check_store(&[0x9001], &[(0, VALUE)], &[(0x804, VALUE)], &[]);
+        // push {r0, r1, lr}
+        check_store(
+            &[0xb503],
+            &[(0, 1), (1, 2), (LR, 3)],
+            &[(0x7f4, 1), (0x7f8, 2), (0x7fc, 3)],
+            &[(SP, 0x7f4)],
+        );
+        // stm r0!, {r1, r2}
+        check_store(
+            &[0xc006],
+            &[(0, 0x800), (1, 1), (2, 2)],
+            &[(0x800, 1), (0x804, 2)],
+            &[(0, 0x808)],
+        );
+        // push.w {r4, r5}
+        check_store(
+            &[0xe92d, 0x0030],
+            &[(4, 4), (5, 5)],
+            &[(0x7f8, 4), (0x7fc, 5)],
+            &[(SP, 0x7f8)],
+        );
+        // strd r2, r3, [r0, #-8]!
+        check_store(
+            &[0xe960, 0x2302],
+            &[(0, 0x808), (2, 1), (3, 2)],
+            &[(0x800, 1), (0x804, 2)],
+            &[(0, 0x800)],
+        );
+        // str.w r0, [r1, #0x804]
+        check_store(&[0xf8c1, 0x0804], &[(0, VALUE)], &[(0x804, VALUE)], &[]);
+        // strh r0, [r1, #2]!
+        check_store(
+            &[0xf821, 0x0f02],
+            &[(0, VALUE), (1, 0x800)],
+            &[(0x800, 0xccdd_0100)],
+            &[(1, 0x802)],
+        );
+        // strb r0, [r1, #-1]
+        check_store(
+            &[0xf801, 0x0c01],
+            &[(0, VALUE), (1, 0x801)],
+            &[(0x800, 0x0302_01dd)],
+            &[(1, 0x801)],
+        );
+        // Without an exclusive load first, the store fails.
+        // strex r3, r2, [r0]
+        check_store(
+            &[0xe840, 0x2300],
+            &[(0, 0x800), (2, 1)],
+            &[(0x800, 0x0302_0100)],
+            &[(3, 1)],
+        );
+    }
+
+    #[test]
+    fn decodes_branches() {
+        // cbz r0, #4
+        check(&[0xb110], &[], 0, &[(PC, 0x108)], 0);
+        // cbz r0, #4
+        check(&[0xb110], &[(0, 1)], 0, &[(PC, 0x102)], 0);
+        // cbnz r0, #4
+        check(&[0xb910], &[(0, 1)], 0, &[(PC, 0x108)], 0);
+        // beq #8
+        check(&[0xd004], &[], Z, &[(PC, 0x10c)], Z);
+        // beq #8
+        check(&[0xd004], &[], 0, &[(PC, 0x102)], 0);
+        // bne #8
+        check(&[0xd104], &[], 0, &[(PC, 0x10c)], 0);
+        // b #8
+        check(&[0xe004], &[], 0, &[(PC, 0x10c)], 0);
+        // beq.w #0x1000
+        check(&[0xf001, 0x8000], &[], Z, &[(PC, 0x1104)], Z);
+        // beq.w #0x1000
+        check(&[0xf001, 0x8000], &[], 0, &[(PC, 0x104)], 0);
+        // b.w #0x1000
+        check(&[0xf001, 0xb800], &[], 0, &[(PC, 0x1104)], 0);
+        // bl #0x1000
+        check(&[0xf001, 0xf800], &[], 0, &[(PC, 0x1104), (LR, 0x105)], 0);
+        // tbb [r0, r1]
+        check(
+            &[0xe8d0, 0xf001],
+            &[(0, 0x810), (1, 2)],
+            0,
+            &[(PC, 0x128)],
+            0,
+        );
+        // tbh [r0, r1, lsl #1]
+        check(
+            &[0xe8d0, 0xf011],
+            &[(0, 0x810), (1, 1)],
+            0,
+            &[(PC, 0x2728)],
+            0,
+        );
+    }
+
+    #[test]
+    fn decodes_wide_data_processing() {
+        // and.w r0, r1, r2, lsl #4
+        check(
+            &[0xea01, 0x1002],
+            &[(1, u32::MAX), (2, 0x123)],
+            0,
+            &[(0, 0x1230)],
+            0,
+        );
+        // bics.w r0, r1, r2, lsr #4
+        check(
+            &[0xea31, 0x1012],
+            &[(1, u32::MAX), (2, 0xf8)],
+            0,
+            &[(0, 0xffff_fff0)],
+            N | C,
+        );
+        // orr.w r0, r1, r2, asr #4
+        check(
+            &[0xea41, 0x1022],
+            &[(2, 0x8000_0000)],
+            0,
+            &[(0, 0xf800_0000)],
+            0,
+        );
+        // orn r0, r1, r2, ror #4
+        check(&[0xea61, 0x1032], &[(2, 0xf)], 0, &[(0, 0x0fff_ffff)], 0);
+        // eors.w r0, r1, r2, rrx
+        check(&[0xea91, 0x0032], &[(2, 1)], C, &[(0, 0x8000_0000)], N | C);
+        // adds.w r0, r1, r2, lsl #1
+        check(
+            &[0xeb11, 0x0042],
+            &[(1, 0xffff_fffe), (2, 1)],
+            0,
+            &[(0, 0)],
+            Z | C,
+        );
+        // adc.w r0, r1, r2
+        check(&[0xeb41, 0x0002], &[(1, 1), (2, 2)], C, &[(0, 4)], C);
+        // sbc.w r0, r1, r2
+        check(&[0xeb61, 0x0002], &[(1, 5), (2, 2)], 0, &[(0, 2)], 0);
+        // sub.w r0, r1, r2, lsr #1
+        check(&[0xeba1, 0x0052], &[(1, 10), (2, 8)], 0, &[(0, 6)], 0);
+        // rsb r0, r1, r2, lsl #2
+        check(&[0xebc1, 0x0082], &[(1, 1), (2, 1)], 0, &[(0, 3)], 0);
+        // teq.w r0, r1
+        check(&[0xea90, 0x0f01], &[(0, 5), (1, 5)], 0, &[(0, 5)], Z);
+        // cmp.w r0, r1, lsl #1
+        check(&[0xebb0, 0x0f41], &[(0, 2), (1, 1)], 0, &[(0, 2)], Z | C);
+        // mvn.w r0, r1, lsl #1
+        check(&[0xea6f, 0x0041], &[(1, 1)], 0, &[(0, 0xffff_fffd)], 0);
+        // ror.w r0, r1, #8
+        check(
+            &[0xea4f, 0x2031],
+            &[(1, 0x1234_5678)],
+            0,
+            &[(0, 0x7812_3456)],
+            0,
+        );
+        // and r0, r1, #0xff00ff00
+        check(
+            &[0xf001, 0x20ff],
+            &[(1, 0x1234_5678)],
+            0,
+            &[(0, 0x1200_5600)],
+            0,
+        );
+        // ands r0, r1, #0x80000000
+        check(
+            &[0xf011, 0x4000],
+            &[(1, 0x8000_0000)],
+            0,
+            &[(0, 0x8000_0000)],
+            N | C,
+        );
+        // orr r0, r1, #0x3fc
+        check(&[0xf441, 0x707f], &[], 0, &[(0, 0x3fc)], 0);
+        // eor r0, r1, #0x80000000
+        check(&[0xf081, 0x4000], &[(1, 1)], 0, &[(0, 0x8000_0001)], 0);
+        // adds.w r0, r1, #0xab00ab00
+        check(
+            &[0xf111, 0x20ab],
+            &[(1, 0x5500_5500)],
+            0,
+            &[(0, 0x1_0000)],
+            C,
+        );
+        // sub.w r0, r1, #0x10000
+        check(&[0xf5a1, 0x3080], &[(1, 0x1_0005)], 0, &[(0, 5)], 0);
+        // cmp.w r0, #0x12000
+        check(&[0xf5b0, 0x3f90], &[(0, 0x12000)], 0, &[], Z | C);
+        // tst.w r0, #0xff000000
+        check(&[0xf010, 0x4f7f], &[(0, 0x100_0000)], 0, &[], C);
+        // rsb.w r0, r1, #0x100
+        check(&[0xf5c1, 0x7080], &[(1, 1)], 0, &[(0, 0xff)], 0);
+        // mvn r0, #0xff
+        check(&[0xf06f, 0x00ff], &[], 0, &[(0, 0xffff_ff00)], 0);
+        // sub.w sp, sp, #0x400
+        check(&[0xf5ad, 0x6d80], &[], 0, &[(SP, 0x400)], 0);
+        // addw r0, r1, #0xfff
+        check(&[0xf601, 0x70ff], &[(1, 1)], 0, &[(0, 0x1000)], 0);
+        // subw r0, r1, #0xfff
+        check(&[0xf6a1, 0x70ff], &[(1, 0x1000)], 0, &[(0, 1)], 0);
+        // movw r0, #0xbeef
+        check(&[0xf64b, 0x60ef], &[(0, u32::MAX)], 0, &[(0, 0xbeef)], 0);
+        // movt r0, #0xdead
+        check(&[0xf6cd, 0x60ad], &[(0, 0xbeef)], 0, &[(0, 0xdead_beef)], 0);
+        // sbfx r0, r1, #4, #8
+        check(&[0xf341, 0x1007], &[(1, 0xf80)], 0, &[(0, 0xffff_fff8)], 0);
+        // ubfx r0, r1, #4, #8
+        check(&[0xf3c1, 0x1007], &[(1, 0xf80)], 0, &[(0, 0xf8)], 0);
+        // bfi r0, r1, #8, #4
+        check(
+            &[0xf361, 0x200b],
+            &[(0, u32::MAX), (1, 5)],
+            0,
+            &[(0, 0xffff_f5ff)],
+            0,
+        );
+        // bfc r0, #8, #4
+        check(
+            &[0xf36f, 0x200b],
+            &[(0, u32::MAX)],
+            0,
+            &[(0, 0xffff_f0ff)],
+            0,
+        );
+    }
+
+    #[test]
+    fn decodes_wide_register_and_multiply() {
+        // lsl.w r0, r1, r2
+        check(
+            &[0xfa01, 0xf002],
+            &[(1, 1), (2, 31)],
+            0,
+            &[(0, 0x8000_0000)],
+            0,
+        );
+        // lsrs.w r0, r1, r2
+        check(
+            &[0xfa31, 0xf002],
+            &[(1, 0x8000_0000), (2, 32)],
+            0,
+            &[(0, 0)],
+            Z | C,
+        );
+        // asr.w r0, r1, r2
+        check(
+            &[0xfa41, 0xf002],
+            &[(1, 0x8000_0000), (2, 4)],
+            0,
+            &[(0, 0xf800_0000)],
+            0,
+        );
+        // ror.w r0, r1, r2
+        check(
+            &[0xfa61, 0xf002],
+            &[(1, 0x1234_5678), (2, 8)],
+            0,
+            &[(0, 0x7812_3456)],
+            0,
+        );
+        // sxtb r0, r1
+        check(&[0xb248], &[(1, 0x80)], 0, &[(0, 0xffff_ff80)], 0);
+        // sxth r0, r1
+        check(&[0xb208], &[(1, 0x1234_8000)], 0, &[(0, 0xffff_8000)], 0);
+        // uxtb r0, r1
+        check(&[0xb2c8], &[(1, u32::MAX)], 0, &[(0, 0xff)], 0);
+        // uxth r0, r1
+        check(&[0xb288], &[(1, u32::MAX)], 0, &[(0, 0xffff)], 0);
+        // sxtb.w r0, r1
+        check(&[0xfa4f, 0xf081], &[(1, 0x80)], 0, &[(0, 0xffff_ff80)], 0);
+        // uxth.w r0, r1, ror #8
+        check(&[0xfa1f, 0xf091], &[(1, 0x1234_5678)], 0, &[(0, 0x3456)], 0);
+        // uxtb16 r0, r1
+        check(
+            &[0xfa3f, 0xf081],
+            &[(1, 0x1234_5678)],
+            0,
+            &[(0, 0x0034_0078)],
+            0,
+        );
+        // sxtah r0, r1, r2
+        check(&[0xfa01, 0xf082], &[(1, 1), (2, 0xffff)], 0, &[(0, 0)], 0);
+        // uxtab r0, r1, r2
+        check(
+            &[0xfa51, 0xf082],
+            &[(1, 0x100), (2, 0x1ff)],
+            0,
+            &[(0, 0x1ff)],
+            0,
+        );
+        // rev r0, r1
+        check(&[0xba08], &[(1, 0x1122_3344)], 0, &[(0, 0x4433_2211)], 0);
+        // rev16 r0, r1
+        check(&[0xba48], &[(1, 0x1122_3344)], 0, &[(0, 0x2211_4433)], 0);
+        // revsh r0, r1
+        check(&[0xbac8], &[(1, 0x1234_0080)], 0, &[(0, 0xffff_8000)], 0);
+        // rev.w r0, r1
+        check(
+            &[0xfa91, 0xf081],
+            &[(1, 0x1122_3344)],
+            0,
+            &[(0, 0x4433_2211)],
+            0,
+        );
+        // rbit r0, r1
+        check(&[0xfa91, 0xf0a1], &[(1, 1)], 0, &[(0, 0x8000_0000)], 0);
+        // clz r0, r1
+        check(&[0xfab1, 0xf081], &[(1, 0x1_0000)], 0, &[(0, 15)], 0);
+        // mul r0, r1, r2
+        check(&[0xfb01, 0xf002], &[(1, 6), (2, 7)], 0, &[(0, 42)], 0);
+        // mla r0, r1, r2, r3
+        check(
+            &[0xfb01, 0x3002],
+            &[(1, 6), (2, 7), (3, 1)],
+            0,
+            &[(0, 43)],
+            0,
+        );
+        // mls r0, r1, r2, r3
+        check(
+            &[0xfb01, 0x3012],
+            &[(1, 6), (2, 7), (3, 43)],
+            0,
+            &[(0, 1)],
+            0,
+        );
+        // smull r0, r1, r2, r3
+        check(
+            &[0xfb82, 0x0103],
+            &[(2, 0xffff_fffe), (3, 3)],
+            0,
+            &[(0, 0xffff_fffa), (1, u32::MAX)],
+            0,
+        );
+        // umull r0, r1, r2, r3
+        check(
+            &[0xfba2, 0x0103],
+            &[(2, u32::MAX), (3, 2)],
+            0,
+            &[(0, 0xffff_fffe), (1, 1)],
+            0,
+        );
+        // umlal r0, r1, r2, r3
+        check(
+            &[0xfbe2, 0x0103],
+            &[(0, 2), (2, u32::MAX), (3, 2)],
+            0,
+            &[(0, 0), (1, 2)],
+            0,
+        );
+        // smlal r0, r1, r2, r3
+        check(
+            &[0xfbc2, 0x0103],
+            &[(0, 6), (2, 0xffff_fffe), (3, 3)],
+            0,
+            &[(0, 0), (1, 0)],
+            0,
+        );
+        // sdiv r0, r1, r2
+        check(
+            &[0xfb91, 0xf0f2],
+            &[(1, 0xffff_fff9), (2, 2)],
+            0,
+            &[(0, 0xffff_fffd)],
+            0,
+        );
+        // udiv r0, r1, r2
+        check(&[0xfbb1, 0xf0f2], &[(1, 7), (2, 2)], 0, &[(0, 3)], 0);
+    }
+
+    #[test]
+    fn decodes_system_instructions() {
+        let (core, _) = step(&[0xb672], &[], 0); // cpsid i
+        assert!(core.primask);
+        let (core, _) = step(&[0xf380, 0x8810], &[(0, 1)], 0); // msr primask, r0
+        assert!(core.primask);
+        let (core, _) = step(&[0xf3ef, 0x8010], &[(0, 1)], 0); // mrs r0, primask
+        assert_eq!(core.regs[0], 0);
+        let (core, _) = step(&[0xf3bf, 0x8f5f], &[], 0); // dmb sy
+        assert_eq!((core.halt(), core.pc()), (None, 0x104));
+    }
+
+    #[test]
+    fn exclusive_stores_need_an_exclusive_load() {
+        let mut ram = Ram(vec![0; 0x100]);
+        for (i, op) in [
+            0xe850, 0x2f00, // ldrex r2, [r0]
+            0x3201, // adds r2, #1
+            0xe840, 0x2300, // strex r3, r2, [r0]
+            0xe840, 0x2400, // strex r4, r2, [r0]
+            0xbe00, // bkpt
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            ram.write(i as u32 * 2, 2, op).unwrap();
+        }
+        ram.write(0x80, 4, 41).unwrap();
+        let mut core = Core::new(0x100, 1);
+        core.regs[0] = 0x80;
+        core.run(&mut ram, 10);
+        assert_eq!(core.halt(), Some(&Halt::Breakpoint));
+        assert_eq!(core.regs[2..5], [42, 0, 1]);
+        assert_eq!(ram.read(0x80, 4), Some(42));
+    }
+}
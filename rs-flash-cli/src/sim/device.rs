@@ -0,0 +1,309 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A target running the real [`rs_flash::run`] on a thread, over an in-memory
+//! flash image, to test both sides of the protocol together.
+//!
+//! Like a probe, the host side accesses the buffers and words behind the
+//! program's back. The thread finishing stands for the core halting, e.g. on
+//! the breakpoint after `rs_flash::run` returns, or on a panic.
+
+use super::{BUFFER_ADDR, CHECKSUM_ADDR, CONTROL_ADDR, SECTORS_ADDR, STATUS_ADDR};
+use crate::elf::FlashTable;
+use crate::target::Target;
+use color_eyre::eyre::{bail, Result};
+use rs_flash::protocol::Control;
+use rs_flash::{Chunks, FlashDevice, Interface, Params, ReadFlashDevice};
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// An in-memory NOR flash.
+pub(crate) struct MemFlash {
+    /// The flash image.
+    pub(crate) data: Vec<u8>,
+    /// The flash ranges written, in order.
+    pub(crate) writes: Vec<Range<usize>>,
+    /// The flash ranges erased, in order.
+    pub(crate) erases: Vec<Range<usize>>,
+    /// Fail any operation on this offset.
+    pub(crate) fail_at: Option<usize>,
+    /// Panic on any operation on this offset.
+    pub(crate) panic_at: Option<usize>,
+}
+
+impl MemFlash {
+    pub(crate) fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            writes: Vec::new(),
+            erases: Vec::new(),
+            fail_at: None,
+            panic_at: None,
+        }
+    }
+}
+
+/// The [`MemFlash`] of a [`DeviceTarget`], as seen by the program.
+struct SharedFlash {
+    flash: Arc<Mutex<MemFlash>>,
+    /// The host has gone away, so fail instead of waiting for it.
+    abort: Arc<AtomicBool>,
+}
+
+impl SharedFlash {
+    /// Lock the flash for an operation on `range`, and inject faults.
+    fn lock(&self, range: Range<usize>) -> Result<MutexGuard<'_, MemFlash>, &'static str> {
+        if self.abort.load(Ordering::SeqCst) {
+            return Err("aborted");
+        }
+        let flash = self.flash.lock().unwrap();
+        if flash.panic_at.is_some_and(|offset| range.contains(&offset)) {
+            drop(flash);
+            panic!("injected panic at 0x{:08x}", range.start);
+        }
+        if flash.fail_at.is_some_and(|offset| range.contains(&offset)) {
+            return Err("injected fault");
+        }
+        Ok(flash)
+    }
+}
+
+impl ReadFlashDevice for SharedFlash {
+    type Error = &'static str;
+
+    fn size(&self) -> usize {
+        self.flash.lock().unwrap().data.len()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let range = offset..offset + buf.len();
+        let flash = self.lock(range.clone())?;
+        buf.copy_from_slice(&flash.data[range]);
+        Ok(())
+    }
+}
+
+impl FlashDevice for SharedFlash {
+    /// Like NOR flash, bits can only be cleared.
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let range = offset..offset + buf.len();
+        let mut flash = self.lock(range.clone())?;
+        for (byte, data) in flash.data[range.clone()].iter_mut().zip(buf.iter()) {
+            *byte &= data;
+        }
+        flash.writes.push(range);
+        Ok(())
+    }
+
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error> {
+        let mut flash = self.lock(range.clone())?;
+        flash.data[range.clone()].fill(0xff);
+        flash.erases.push(range);
+        Ok(())
+    }
+}
+
+/// The memory shared with the program, like the target's RAM.
+struct Shared {
+    control: Box<[AtomicUsize]>,
+    checksum: Box<[AtomicUsize]>,
+    sectors: Box<[AtomicUsize]>,
+    status: [AtomicUsize; 2],
+    /// The buffers, which the program accesses through a pointer while it
+    /// owns them.
+    buffer: Box<[AtomicU8]>,
+}
+
+impl Shared {
+    fn new(ft: &FlashTable) -> Self {
+        let words = || (0..ft.buffers).map(|_| AtomicUsize::new(0)).collect();
+        Self {
+            control: words(),
+            checksum: words(),
+            sectors: words(),
+            status: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            buffer: (0..ft.buffer_size * ft.buffers)
+                .map(|_| AtomicU8::new(0))
+                .collect(),
+        }
+    }
+
+    /// The word at `addr`, if any.
+    fn word(&self, addr: u64) -> Option<&AtomicUsize> {
+        [
+            (CONTROL_ADDR, &self.control[..]),
+            (CHECKSUM_ADDR, &self.checksum[..]),
+            (SECTORS_ADDR, &self.sectors[..]),
+            (STATUS_ADDR, &self.status[..]),
+        ]
+        .into_iter()
+        .find_map(|(base, words)| {
+            let offset = addr.checked_sub(base).filter(|offset| offset % 4 == 0)?;
+            words.get((offset / 4) as usize)
+        })
+    }
+}
+
+pub(crate) struct DeviceTarget {
+    flash: Arc<Mutex<MemFlash>>,
+    abort: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<(), rs_flash::Error<&'static str>>>>,
+    /// Why the program has halted, once it has.
+    halted: Option<String>,
+}
+
+impl DeviceTarget {
+    /// Start `rs_flash::run` for the program described by `ft` with `params`
+    /// on `flash`.
+    pub(crate) fn new(flash: MemFlash, ft: FlashTable, params: Params) -> Self {
+        assert_eq!(flash.data.len(), ft.flash_size);
+        let flash = Arc::new(Mutex::new(flash));
+        let abort = Arc::new(AtomicBool::new(false));
+        let shared = Arc::new(Shared::new(&ft));
+        let chunks = Chunks::new(params.offset, params.length, ft.buffer_size, ft.flash_size);
+        let mut device = SharedFlash {
+            flash: flash.clone(),
+            abort: abort.clone(),
+        };
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("target".to_string())
+                .spawn(move || {
+                    let buffer = NonNull::from(&*shared.buffer).cast::<u8>();
+                    let buffer = NonNull::slice_from_raw_parts(buffer, shared.buffer.len());
+                    // SAFETY: `AtomicU8` has the same layout as `u8`, and
+                    // allows mutation through a shared reference. Like on a
+                    // real target, the host only accesses a buffer while it
+                    // owns it, and the `SeqCst` control words order its
+                    // accesses with the program's.
+                    let interface = unsafe {
+                        Interface::new(
+                            ft.direction,
+                            buffer,
+                            &shared.control,
+                            &shared.status,
+                            &shared.checksum,
+                            &shared.sectors,
+                            ft.sector_size,
+                            params,
+                            chunks,
+                        )
+                    };
+                    rs_flash::run(interface, &mut device)
+                })
+                .unwrap()
+        };
+
+        Self {
+            flash,
+            abort,
+            shared,
+            thread: Some(thread),
+            halted: None,
+        }
+    }
+
+    /// The flash, once the program has halted.
+    pub(crate) fn flash(&self) -> MutexGuard<'_, MemFlash> {
+        assert!(self.halted.is_some(), "program is still running");
+        self.flash.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The offset of the `len` bytes at `addr` in the buffers.
+    fn buffer_offset(&self, addr: u64, len: usize) -> Result<usize> {
+        let start = addr
+            .checked_sub(BUFFER_ADDR)
+            .filter(|start| *start as usize + len <= self.shared.buffer.len());
+        match start {
+            Some(start) => Ok(start as usize),
+            None => bail!("access to 0x{:08x} ({} bytes) is out of RAM", addr, len),
+        }
+    }
+}
+
+impl Drop for DeviceTarget {
+    /// Stop the program, if the host has gone away while it's waiting.
+    fn drop(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        self.abort.store(true, Ordering::SeqCst);
+        while !thread.is_finished() {
+            for control in self.shared.control.iter() {
+                control.store(Control::Error.as_u32() as _, Ordering::SeqCst);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let _ = thread.join();
+    }
+}
+
+impl Target for DeviceTarget {
+    fn read_word_32(&mut self, addr: u64) -> Result<u32> {
+        match self.shared.word(addr) {
+            Some(word) => Ok(word.load(Ordering::SeqCst) as u32),
+            None => {
+                let mut word = [0; 4];
+                self.read(addr, &mut word)?;
+                Ok(u32::from_le_bytes(word))
+            }
+        }
+    }
+
+    fn write_word_32(&mut self, addr: u64, value: u32) -> Result<()> {
+        match self.shared.word(addr) {
+            Some(word) => word.store(value as _, Ordering::SeqCst),
+            None => self.write(addr, &value.to_le_bytes())?,
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let offset = self.buffer_offset(addr, data.len())?;
+        // The protocol hands the buffer over with the control word.
+        for (byte, shared) in data.iter_mut().zip(&self.shared.buffer[offset..]) {
+            *byte = shared.load(Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let offset = self.buffer_offset(addr, data.len())?;
+        // The protocol hands the buffer over with the control word.
+        for (byte, shared) in data.iter().zip(&self.shared.buffer[offset..]) {
+            shared.store(*byte, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn core_halted(&mut self) -> Result<bool> {
+        if self.thread.as_ref().is_some_and(JoinHandle::is_finished) {
+            let halted = match self.thread.take().unwrap().join() {
+                Ok(Ok(())) => "program finished".to_string(),
+                Ok(Err(e)) => format!("program failed ({})", e.error.unwrap_or(e.code.as_str())),
+                Err(panic) => match panic.downcast::<String>() {
+                    Ok(message) => format!("program panicked ({})", message),
+                    Err(_) => "program panicked".to_string(),
+                },
+            };
+            self.halted = Some(halted);
+        }
+        Ok(self.halted.is_some())
+    }
+
+    fn pump(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn halt_error(&mut self) -> Result<String> {
+        Ok(format!(
+            "target halted unexpectedly: {}",
+            self.halted.as_deref().unwrap_or("running")
+        ))
+    }
+}
@@ -0,0 +1,480 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A target running a real RAM program on an emulated Cortex-M, over a
+//! simulated SPI NOR flash, to test the example programs end-to-end.
+//!
+//! The board is the one the examples are written for: an STM32F1 compatible
+//! microcontroller, with a 25-series SPI NOR flash on SPI2 and its chip
+//! select on PB12. Only the registers the programs wait on or use to talk to
+//! the flash do anything. Other peripheral and system registers read back
+//! what was written to them.
+//!
+//! Like [`SimTarget`](super::SimTarget), the program doesn't run on its own.
+//! Instead, it runs for a slice of instructions whenever the host reads a
+//! word or checks whether the core has halted.
+
+use super::cortex_m::{Bus, Core, Halt};
+use crate::elf::{parse_flash_table, FlashTable};
+use crate::image::parse_elf;
+use crate::target::Target;
+use color_eyre::eyre::{bail, eyre, OptionExt as _, Result};
+use ram_probe_rs::elf::object::{File, Object as _, ObjectSection as _, ObjectSymbol as _};
+use rs_flash::Params;
+use std::collections::HashMap;
+use std::ops::Range;
+
+const RAM_ADDR: u32 = 0x2000_0000;
+/// The RAM size, as in the examples' `memory.x`.
+const RAM_SIZE: usize = 96 * 1024;
+
+/// The peripheral, and the system control space registers.
+const PERIPHERALS: Range<u32> = 0x4000_0000..0x4003_0000;
+const SYSTEM: Range<u32> = 0xe000_0000..0xe010_0000;
+/// The bit-band alias of the peripherals, with a word for each bit.
+const PERIPHERAL_BIT_BAND: Range<u32> = 0x4200_0000..0x4400_0000;
+
+const RCC_CR: u32 = 0x4002_1000;
+const RCC_CR_HSEON: u32 = 1 << 16;
+const RCC_CR_HSERDY: u32 = 1 << 17;
+const RCC_CR_PLLON: u32 = 1 << 24;
+const RCC_CR_PLLRDY: u32 = 1 << 25;
+const RCC_CFGR: u32 = 0x4002_1004;
+const RCC_CFGR_SW: u32 = 0b11;
+const RCC_CFGR_SWS: u32 = 0b11 << 2;
+
+const GPIOB_ODR: u32 = 0x4001_0c0c;
+const GPIOB_BSRR: u32 = 0x4001_0c10;
+const GPIOB_BRR: u32 = 0x4001_0c14;
+/// The flash chip select, which is active low.
+const CS: u32 = 1 << 12;
+
+const SPI2_SR: u32 = 0x4000_3808;
+const SPI2_DR: u32 = 0x4000_380c;
+const SPI_SR_RXNE: u32 = 1 << 0;
+const SPI_SR_TXE: u32 = 1 << 1;
+
+/// The number of instructions to run whenever the host reads a word or
+/// checks whether the core has halted.
+const SLICE: usize = 10_000;
+/// The most instructions the startup code may run before `main`.
+const STARTUP: usize = 1_000_000;
+
+const JEDEC_ID: [u8; 3] = [0xef, 0x40, 0x18];
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const WRITE_DISABLE: u8 = 0x04;
+const READ_JEDEC_ID: u8 = 0x9f;
+const READ: u8 = 0x03;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE: u8 = 0xc7;
+const STATUS_BUSY: u8 = 1 << 0;
+const STATUS_WEL: u8 = 1 << 1;
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: usize = 4 * 1024;
+/// The number of status reads a program or erase stays busy for.
+const BUSY_READS: usize = 2;
+
+/// A 25-series SPI NOR flash, e.g. a W25Q128.
+pub(crate) struct SpiNor {
+    /// The flash image.
+    pub(crate) data: Vec<u8>,
+    /// The flash ranges written, in order.
+    pub(crate) writes: Vec<Range<usize>>,
+    /// The flash ranges erased, in order.
+    pub(crate) erases: Vec<Range<usize>>,
+    /// The write enable latch, which a program or erase needs.
+    pub(crate) write_enabled: bool,
+    /// The bytes received since the chip was selected.
+    command: Vec<u8>,
+    /// The number of status reads until the program or erase completes.
+    busy: usize,
+}
+
+impl SpiNor {
+    pub(crate) fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            writes: Vec::new(),
+            erases: Vec::new(),
+            write_enabled: false,
+            command: Vec::new(),
+            busy: 0,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.busy > 0 {
+            status |= STATUS_BUSY;
+        }
+        if self.write_enabled {
+            status |= STATUS_WEL;
+        }
+        status
+    }
+
+    /// The 24-bit address of the command.
+    fn address(&self) -> usize {
+        let address = self.command[1..4]
+            .iter()
+            .fold(0, |address, b| address << 8 | *b as usize);
+        address % self.data.len()
+    }
+
+    /// Receive `byte` while selected, and return the byte sent back.
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.command.push(byte);
+        let i = self.command.len() - 1;
+        match self.command[0] {
+            READ_STATUS if i > 0 => {
+                let status = self.status();
+                self.busy = self.busy.saturating_sub(1);
+                status
+            }
+            // Other commands are ignored while busy.
+            _ if self.busy > 0 => 0xff,
+            READ_JEDEC_ID if i > 0 => JEDEC_ID.get(i - 1).copied().unwrap_or(0),
+            READ if i > 3 => self.data[(self.address() + i - 4) % self.data.len()],
+            _ => 0xff,
+        }
+    }
+
+    /// Execute the command when deselected.
+    fn deselect(&mut self) {
+        if self.busy > 0 || self.command.is_empty() {
+            self.command.clear();
+            return;
+        }
+        match (self.command[0], self.command.len()) {
+            (WRITE_ENABLE, 1) => self.write_enabled = true,
+            (WRITE_DISABLE, 1) => self.write_enabled = false,
+            (PAGE_PROGRAM, 5..) if self.write_enabled => {
+                // Programming wraps around within the page, and like all NOR
+                // flash, can only clear bits.
+                let address = self.address();
+                let page = address - address % PAGE_SIZE;
+                for (i, byte) in self.command[4..].iter().enumerate() {
+                    self.data[page + (address + i) % PAGE_SIZE] &= byte;
+                }
+                let len = (self.command.len() - 4).min(PAGE_SIZE);
+                self.writes.push(address..address + len);
+                self.start_write();
+            }
+            (SECTOR_ERASE, 4) if self.write_enabled => {
+                let start = self.address() - self.address() % SECTOR_SIZE;
+                self.erase(start..start + SECTOR_SIZE);
+            }
+            (CHIP_ERASE, 1) if self.write_enabled => self.erase(0..self.data.len()),
+            _ => {}
+        }
+        self.command.clear();
+    }
+
+    fn erase(&mut self, range: Range<usize>) {
+        self.data[range.clone()].fill(0xff);
+        self.erases.push(range);
+        self.start_write();
+    }
+
+    /// Stay busy for a while, as if programming or erasing.
+    fn start_write(&mut self) {
+        self.write_enabled = false;
+        self.busy = BUSY_READS;
+    }
+}
+
+/// The memory and peripherals of the board.
+struct Board {
+    ram: Vec<u8>,
+    /// The peripheral and system registers, which aren't modelled.
+    registers: HashMap<u32, u32>,
+    flash: SpiNor,
+    /// The byte received by SPI2, until it's read.
+    received: Option<u8>,
+}
+
+impl Board {
+    fn ram_offset(&self, addr: u32, size: u32) -> Option<usize> {
+        let offset = addr.checked_sub(RAM_ADDR)? as usize;
+        (offset + size as usize <= self.ram.len()).then_some(offset)
+    }
+
+    fn is_register(addr: u32) -> bool {
+        PERIPHERALS.contains(&addr) || SYSTEM.contains(&addr)
+    }
+
+    /// The register and bit aliased by `addr` in the bit-band region.
+    fn bit_band(addr: u32) -> Option<(u32, u32)> {
+        if !PERIPHERAL_BIT_BAND.contains(&addr) {
+            return None;
+        }
+        let offset = addr - PERIPHERAL_BIT_BAND.start;
+        let byte = PERIPHERALS.start + offset / 32;
+        let bit = (byte % 4) * 8 + (offset % 32) / 4;
+        Some((byte - byte % 4, bit))
+    }
+
+    fn read_register(&mut self, addr: u32) -> u32 {
+        match addr {
+            // Transfers complete right away.
+            SPI2_SR => {
+                let mut sr = SPI_SR_TXE;
+                if self.received.is_some() {
+                    sr |= SPI_SR_RXNE;
+                }
+                sr
+            }
+            SPI2_DR => self.received.take().unwrap_or(0) as u32,
+            _ => self.registers.get(&addr).copied().unwrap_or(0),
+        }
+    }
+
+    fn write_register(&mut self, addr: u32, value: u32) {
+        let odr = self.registers.get(&GPIOB_ODR).copied().unwrap_or(0);
+        let (addr, value) = match addr {
+            // The oscillator and PLL are ready as soon as they are enabled,
+            // and the system clock switches right away.
+            RCC_CR => {
+                let ready = (value & (RCC_CR_HSEON | RCC_CR_PLLON)) << 1;
+                (addr, (value & !(RCC_CR_HSERDY | RCC_CR_PLLRDY)) | ready)
+            }
+            RCC_CFGR => (addr, (value & !RCC_CFGR_SWS) | (value & RCC_CFGR_SW) << 2),
+            GPIOB_BSRR => (GPIOB_ODR, (odr & !(value >> 16)) | (value & 0xffff)),
+            GPIOB_BRR => (GPIOB_ODR, odr & !(value & 0xffff)),
+            SPI2_DR => {
+                // Nothing answers while the flash isn't selected.
+                self.received = Some(if odr & CS == 0 {
+                    self.flash.transfer(value as u8)
+                } else {
+                    0xff
+                });
+                return;
+            }
+            _ => (addr, value),
+        };
+        self.registers.insert(addr, value);
+
+        if addr == GPIOB_ODR {
+            match (odr & CS != 0, value & CS != 0) {
+                (true, false) => self.flash.command.clear(),
+                (false, true) => self.flash.deselect(),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Bus for Board {
+    fn read(&mut self, addr: u32, size: u32) -> Option<u32> {
+        if let Some(offset) = self.ram_offset(addr, size) {
+            let bytes = &self.ram[offset..offset + size as usize];
+            return Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, b| value << 8 | *b as u32),
+            );
+        }
+        if let Some((addr, bit)) = Self::bit_band(addr) {
+            return Some((self.read_register(addr) >> bit) & 1);
+        }
+        if !Self::is_register(addr) {
+            return None;
+        }
+        let shift = (addr % 4) * 8;
+        let value = self.read_register(addr - addr % 4) >> shift;
+        Some(value & (u32::MAX >> (32 - size * 8)))
+    }
+
+    fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        if let Some(offset) = self.ram_offset(addr, size) {
+            let bytes = &value.to_le_bytes()[..size as usize];
+            self.ram[offset..offset + size as usize].copy_from_slice(bytes);
+            return Some(());
+        }
+        if let Some((addr, bit)) = Self::bit_band(addr) {
+            let old = self.registers.get(&addr).copied().unwrap_or(0);
+            self.write_register(addr, (old & !(1 << bit)) | (value & 1) << bit);
+            return Some(());
+        }
+        if !Self::is_register(addr) {
+            return None;
+        }
+        // Merge narrower writes into the rest of the register.
+        let (addr, shift) = (addr - addr % 4, (addr % 4) * 8);
+        let mask = (u32::MAX >> (32 - size * 8)) << shift;
+        let old = self.registers.get(&addr).copied().unwrap_or(0);
+        self.write_register(addr, (old & !mask) | ((value << shift) & mask));
+        Some(())
+    }
+}
+
+pub(crate) struct EmuTarget {
+    core: Core,
+    board: Board,
+    rtt_addr: u64,
+}
+
+impl EmuTarget {
+    /// Load the RAM program in the ELF file `elf`, and start it with
+    /// `params` on `flash`.
+    ///
+    /// Returns the target, and the flash table of the program.
+    pub(crate) fn new(elf: &[u8], flash: SpiNor, params: &Params) -> Result<(Self, FlashTable)> {
+        let file = File::parse(elf)?;
+        let symbol = |name: &str| {
+            file.symbols()
+                .find(|symbol| symbol.name() == Ok(name))
+                .map(|symbol| symbol.address() as u32)
+        };
+        let section = |name: &str| {
+            file.section_by_name(name)
+                .ok_or_else(|| eyre!("section `{}` not found", name))
+        };
+
+        let flash_table = parse_flash_table(
+            section(".rs-flash")?.data()?,
+            symbol("_RS_FLASH_BUFFER").ok_or_eyre("Flash buffer symbol not found")?,
+            symbol("_RS_FLASH_CONTROL").ok_or_eyre("Flash control symbol not found")?,
+            symbol("_RS_FLASH_PARAMS"),
+            symbol("_RS_FLASH_STATUS"),
+            symbol("_RS_FLASH_SECTORS"),
+            symbol("_RS_FLASH_CHECKSUM"),
+        )?;
+        let rtt_addr = symbol("_SEGGER_RTT").ok_or_eyre("RTT symbol not found")?;
+
+        let mut board = Board {
+            ram: vec![0; RAM_SIZE],
+            registers: HashMap::new(),
+            flash,
+            received: None,
+        };
+        for segment in parse_elf(elf)? {
+            let offset = board
+                .ram_offset(segment.address as u32, segment.data.len() as u32)
+                .ok_or_eyre("load segment is outside of RAM")?;
+            board.ram[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        // The parameters are not initialized by the program, so they must be
+        // written before it starts.
+        if let Some(params_addr) = flash_table.params_addr {
+            let words = params
+                .to_words()
+                .ok_or_eyre("offset or length doesn't fit in 32 bits")?;
+            for (i, word) in words.into_iter().enumerate() {
+                board.write(params_addr as u32 + i as u32 * 4, 4, word);
+            }
+        }
+
+        let vector_table = section(".vector_table")?.address() as u32;
+        let sp = board
+            .read(vector_table, 4)
+            .ok_or_eyre("vector table not in RAM")?;
+        let reset = board
+            .read(vector_table + 4, 4)
+            .ok_or_eyre("vector table not in RAM")?;
+        let mut core = Core::new(sp, reset);
+
+        // A real core has initialized RAM long before the host first accesses
+        // it, so it doesn't see the host's first command zeroed.
+        let main = symbol("main").ok_or_eyre("main symbol not found")? & !1;
+        for _ in 0..STARTUP {
+            if core.pc() == main || core.halt().is_some() {
+                break;
+            }
+            core.run(&mut board, 1);
+        }
+        if core.pc() != main {
+            bail!("program didn't reach main (PC 0x{:08x})", core.pc());
+        }
+
+        let target = Self {
+            core,
+            board,
+            rtt_addr: rtt_addr as u64,
+        };
+        Ok((target, flash_table))
+    }
+
+    pub(crate) fn flash(&self) -> &SpiNor {
+        &self.board.flash
+    }
+
+    fn ram_range(&self, addr: u64, len: usize) -> Result<Range<usize>> {
+        let start = u32::try_from(addr)
+            .ok()
+            .and_then(|addr| self.board.ram_offset(addr, len as u32));
+        match start {
+            Some(start) => Ok(start..start + len),
+            None => bail!("access to 0x{:08x} ({} bytes) is out of RAM", addr, len),
+        }
+    }
+
+    fn ram_word(&self, addr: u64) -> Result<u32> {
+        let range = self.ram_range(addr, 4)?;
+        Ok(u32::from_le_bytes(
+            self.board.ram[range].try_into().unwrap(),
+        ))
+    }
+}
+
+impl Target for EmuTarget {
+    fn read_word_32(&mut self, addr: u64) -> Result<u32> {
+        self.core.run(&mut self.board, SLICE);
+        self.ram_word(addr)
+    }
+
+    fn write_word_32(&mut self, addr: u64, value: u32) -> Result<()> {
+        let range = self.ram_range(addr, 4)?;
+        self.board.ram[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let range = self.ram_range(addr, data.len())?;
+        data.copy_from_slice(&self.board.ram[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let range = self.ram_range(addr, data.len())?;
+        self.board.ram[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn core_halted(&mut self) -> Result<bool> {
+        self.core.run(&mut self.board, SLICE);
+        Ok(self.core.halt().is_some())
+    }
+
+    /// Discard the defmt output from RTT up channel 0, as it isn't decoded.
+    fn pump(&mut self) -> Result<usize> {
+        // Up channel 0 follows the 16 byte ID and the number of channels,
+        // and starts with the name and buffer addresses.
+        let channel = self.rtt_addr + 24;
+        let size = self.ram_word(channel + 8)?;
+        let write = self.ram_word(channel + 12)?;
+        let read = self.ram_word(channel + 16)?;
+        if size == 0 {
+            return Ok(0);
+        }
+        let n = (write + size - read) % size;
+        self.write_word_32(channel + 16, write)?;
+        log::trace!("defmt bytes: {}", n);
+        Ok(n as usize)
+    }
+
+    fn halt_error(&mut self) -> Result<String> {
+        let reason = match self.core.halt() {
+            Some(Halt::Breakpoint) => "breakpoint",
+            Some(Halt::Fault(fault)) => fault,
+            None => "running",
+        };
+        Ok(format!(
+            "target halted unexpectedly ({}) at PC 0x{:08x}",
+            reason,
+            self.core.pc()
+        ))
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The target the runner talks to, either a probe or a simulated target.
+
+use crate::elf::FlashTable;
+use color_eyre::eyre::{eyre, Result};
+use ram_probe_rs::defmt::DefmtDecoder;
+use ram_probe_rs::probe_rs::rtt::UpChannel;
+use ram_probe_rs::probe_rs::{Core, MemoryInterface as _, Session, VectorCatchCondition};
+use ram_probe_rs::run::{init_cpu, setup_rtt, DefmtOpts};
+use rs_flash::Params;
+use std::time::Duration;
+
+/// The memory, halt status and log output of a running RAM program.
+pub trait Target {
+    fn read_word_32(&mut self, addr: u64) -> Result<u32>;
+
+    fn write_word_32(&mut self, addr: u64, value: u32) -> Result<()>;
+
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> Result<()>;
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()>;
+
+    fn core_halted(&mut self) -> Result<bool>;
+
+    /// Read and decode any pending log output.
+    ///
+    /// Returns the number of bytes read.
+    fn pump(&mut self) -> Result<usize>;
+
+    /// Describe why the target has halted.
+    fn halt_error(&mut self) -> Result<String>;
+
+    /// Read and decode all pending log output.
+    fn drain(&mut self) -> Result<()> {
+        while self.pump()? > 0 {}
+        Ok(())
+    }
+}
+
+/// Cortex-M fault status and address registers.
+const FAULT_REGISTERS: [(&str, u64); 4] = [
+    ("CFSR", 0xe000_ed28),
+    ("HFSR", 0xe000_ed2c),
+    ("MMFAR", 0xe000_ed34),
+    ("BFAR", 0xe000_ed38),
+];
+
+/// A target connected through a probe, with defmt output over RTT.
+pub struct ProbeTarget<'session, 'opts> {
+    core: Core<'session>,
+    channel: UpChannel,
+    decoder: DefmtDecoder<'opts>,
+}
+
+impl<'session, 'opts> ProbeTarget<'session, 'opts> {
+    /// Download the RAM program, and start it with `params`.
+    pub fn new(
+        session: &'session mut Session,
+        opts: &'opts DefmtOpts<'_>,
+        flash_table: &FlashTable,
+        params: &Params,
+    ) -> Result<Self> {
+        // The parameters are not initialized by the target, so they must be
+        // written before the target starts. Halt the core first, so the
+        // program that is currently running doesn't overwrite them.
+        if let Some(params_addr) = flash_table.params_addr {
+            let mut core = session.core(0)?;
+            core.halt(Duration::from_millis(100))?;
+            let words = params
+                .to_words()
+                .ok_or_else(|| eyre!("offset or length doesn't fit in 32 bits"))?;
+            core.write_32(params_addr, &words)?;
+        }
+
+        init_cpu(session, &opts.segments, &opts.vector_table, opts.timeout)?;
+        // Halt on a HardFault, instead of spinning in the default handler.
+        session
+            .core(0)?
+            .enable_vector_catch(VectorCatchCondition::HardFault)?;
+
+        let mut rtt = setup_rtt(session, opts.rtt_addr, opts.retries)?;
+
+        let channel = rtt
+            .up_channels()
+            .take(0)
+            .ok_or_else(|| eyre!("RTT up channel 0 not found"))?;
+
+        let decoder = DefmtDecoder::new(&opts.defmt, "target");
+
+        Ok(Self {
+            core: session.core(0)?,
+            channel,
+            decoder,
+        })
+    }
+}
+
+impl Target for ProbeTarget<'_, '_> {
+    fn read_word_32(&mut self, addr: u64) -> Result<u32> {
+        Ok(self.core.read_word_32(addr)?)
+    }
+
+    fn write_word_32(&mut self, addr: u64, value: u32) -> Result<()> {
+        Ok(self.core.write_word_32(addr, value)?)
+    }
+
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> Result<()> {
+        Ok(self.core.read(addr, data)?)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        Ok(self.core.write(addr, data)?)
+    }
+
+    fn core_halted(&mut self) -> Result<bool> {
+        Ok(self.core.core_halted()?)
+    }
+
+    fn pump(&mut self) -> Result<usize> {
+        let mut read_buf = [0; 1024];
+        let n = self.channel.read(&mut self.core, &mut read_buf)?;
+        log::trace!("defmt bytes: {}", n);
+        if n > 0 {
+            self.decoder.decode(&read_buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    /// Describe why the target has halted, and log the fault registers.
+    fn halt_error(&mut self) -> Result<String> {
+        let status = self.core.status()?;
+        let pc: u32 = self.core.read_core_reg(self.core.program_counter().id())?;
+        for (name, addr) in FAULT_REGISTERS {
+            let value = self.core.read_word_32(addr)?;
+            log::error!("{:<5} = 0x{:08x}", name, value);
+        }
+        Ok(format!(
+            "target halted unexpectedly ({:?}) at PC 0x{:08x}",
+            status, pc
+        ))
+    }
+}
@@ -0,0 +1,451 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A safe, typed implementation of the target side of the protocol.
+
+use crate::crc::crc32;
+use crate::protocol::{Control, Owner};
+use crate::{Chunk, Chunks, Direction, Erase, ErrorCode, Params, CONTROL_ERROR};
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Log via defmt, if the `defmt` feature is enabled.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::info!($($arg)*);
+    };
+}
+
+/// A flash device that can be dumped, verified or hashed.
+///
+/// Implement this for a chip, and call [`run_read_only`]. To load the chip
+/// too, also implement [`FlashDevice`].
+pub trait ReadFlashDevice {
+    type Error;
+
+    /// The size of the flash in bytes.
+    fn size(&self) -> usize;
+
+    /// Read `buf.len()` bytes at `offset`.
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// A flash device that can be dumped or loaded.
+///
+/// Implement this and [`ReadFlashDevice`] for a chip, and call [`run`].
+pub trait FlashDevice: ReadFlashDevice {
+    /// Write `buf` at `offset`. The flash has been erased before.
+    ///
+    /// The buffer is mutable for drivers that transfer in place, its contents
+    /// don't need to be preserved.
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erase `range`. The range is aligned to the sector size.
+    ///
+    /// This is either the requested range, a single chunk, or a single sector.
+    /// The sector size is the buffer size, unless passed to
+    /// [`flash_interface!`](crate::flash_interface).
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error>;
+
+    /// Erase the entire chip.
+    fn erase_all(&mut self) -> Result<(), Self::Error> {
+        self.erase(0..self.size())
+    }
+}
+
+/// A failed operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error<E> {
+    /// The failed operation, as reported to the host.
+    pub code: ErrorCode,
+    /// The failing offset, as reported to the host.
+    pub offset: usize,
+    /// The device error, or `None` if the host broke the protocol.
+    pub error: Option<E>,
+}
+
+/// The host/target interface, set up by [`flash_interface!`](crate::flash_interface).
+///
+/// Use `rs_flash_interface()` to get it.
+pub struct Interface<'a> {
+    direction: Direction,
+    /// The buffers, one after the other.
+    ///
+    /// The host accesses them behind the program's back, so a buffer is only
+    /// borrowed while the target owns it.
+    buffer: NonNull<[u8]>,
+    control: &'a [AtomicUsize],
+    status: &'a [AtomicUsize; 2],
+    checksum: &'a [AtomicUsize],
+    sectors: &'a [AtomicUsize],
+    sector_size: usize,
+    params: Params,
+    chunks: Chunks,
+}
+
+// SAFETY: The interface has exclusive use of the buffers on the target side.
+unsafe impl Send for Interface<'_> {}
+
+impl<'a> Interface<'a> {
+    /// # Safety
+    ///
+    /// `buffer` must be valid for reads and writes for `'a`, and only be
+    /// accessed by the host otherwise, while the control words hand it over.
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn new(
+        direction: Direction,
+        buffer: NonNull<[u8]>,
+        control: &'a [AtomicUsize],
+        status: &'a [AtomicUsize; 2],
+        checksum: &'a [AtomicUsize],
+        sectors: &'a [AtomicUsize],
+        sector_size: usize,
+        params: Params,
+        chunks: Chunks,
+    ) -> Self {
+        Self {
+            direction,
+            buffer,
+            control,
+            status,
+            checksum,
+            sectors,
+            sector_size,
+            params,
+            chunks,
+        }
+    }
+
+    /// The size of each buffer.
+    fn buffer_size(&self) -> usize {
+        self.buffer.len() / self.control.len()
+    }
+
+    /// The `range` of the buffers, which must be in a buffer the target owns.
+    fn buffer(&mut self, range: Range<usize>) -> &mut [u8] {
+        assert!(range.start <= range.end && range.end <= self.buffer.len());
+        // SAFETY: The range is in bounds, and the host doesn't access the
+        // buffer until the target hands it over, which ends the borrow.
+        unsafe {
+            let start = self.buffer.cast::<u8>().as_ptr().add(range.start);
+            core::slice::from_raw_parts_mut(start, range.len())
+        }
+    }
+
+    /// The buffer the chunk is transferred through.
+    fn slot(&self, chunk: &Chunk) -> usize {
+        (chunk.number - 1) % self.control.len()
+    }
+
+    /// The range of buffer `slot` in `buffer`.
+    fn buffer_range(&self, slot: usize) -> Range<usize> {
+        let size = self.buffer_size();
+        slot * size..(slot + 1) * size
+    }
+
+    /// Spin until the host hands buffer `slot` over to the target, and return
+    /// its state.
+    ///
+    /// Returns `None` if the control word isn't a valid state, or one the
+    /// target can't continue from, e.g. an error.
+    fn acquire(&self, slot: usize) -> Option<Control> {
+        loop {
+            let state = Control::from_u32(self.control[slot].load(Ordering::SeqCst) as u32)?;
+            match state.owner(self.direction) {
+                Owner::Target => return Some(state),
+                Owner::Host => core::hint::spin_loop(),
+                Owner::Nobody => return None,
+            }
+        }
+    }
+
+    /// Hand buffer `slot` over to the host, by changing its state from `state`
+    /// to `next`.
+    fn signal(&self, slot: usize, state: Control, next: Control) {
+        debug_assert!(
+            state.can_become(next, self.direction),
+            "invalid control transition"
+        );
+        self.control[slot].store(next.as_u32() as _, Ordering::SeqCst);
+    }
+
+    /// Spin while buffer `slot` is in `state`.
+    fn spin_while(&self, slot: usize, state: Control) {
+        while self.control[slot].load(Ordering::SeqCst) == state.as_u32() as _ {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Report an error to the host.
+    fn fail<E>(&self, code: ErrorCode, offset: usize, error: E) -> Error<E> {
+        report_error(self.status, self.control, code, offset);
+        Error {
+            code,
+            offset,
+            error: Some(error),
+        }
+    }
+
+    /// Report to the host that it broke the protocol, while transferring the
+    /// chunk at `offset`.
+    fn fail_protocol<E>(&self, offset: usize) -> Error<E> {
+        report_error(self.status, self.control, ErrorCode::Protocol, offset);
+        Error {
+            code: ErrorCode::Protocol,
+            offset,
+            error: None,
+        }
+    }
+}
+
+/// Report an error to the host, by setting `status` to the error code and
+/// offset, and every control word to [`CONTROL_ERROR`].
+#[doc(hidden)]
+pub fn report_error(
+    status: &[AtomicUsize; 2],
+    control: &[AtomicUsize],
+    code: ErrorCode,
+    offset: usize,
+) {
+    status[0].store(code.as_u32() as _, Ordering::SeqCst);
+    status[1].store(offset, Ordering::SeqCst);
+    // The host may be waiting on any buffer.
+    for control in control {
+        control.store(CONTROL_ERROR as _, Ordering::SeqCst);
+    }
+}
+
+/// Dump, load, verify, or hash the device, as requested by the host.
+///
+/// On failure, the error is reported to the host before it is returned.
+pub fn run<D: FlashDevice>(
+    mut interface: Interface<'_>,
+    device: &mut D,
+) -> Result<(), Error<D::Error>> {
+    assert!(
+        interface.chunks.range().end <= device.size(),
+        "range exceeds device size"
+    );
+
+    match interface.direction {
+        Direction::Dump | Direction::Verify | Direction::Hash => run_read_only(interface, device),
+        Direction::Load => {
+            erase(&mut interface, device)?;
+            info!("loading...");
+            load(&mut interface, device, false)
+        }
+        Direction::LoadVerify => {
+            erase(&mut interface, device)?;
+            info!("loading...");
+            load(&mut interface, device, true)
+        }
+    }
+}
+
+/// Dump, verify, or hash the device, as requested by the host.
+///
+/// Like [`run`], but only reads the device, for programs that never load.
+///
+/// # Panics
+///
+/// If the program loads.
+pub fn run_read_only<D: ReadFlashDevice>(
+    mut interface: Interface<'_>,
+    device: &mut D,
+) -> Result<(), Error<D::Error>> {
+    assert!(
+        interface.chunks.range().end <= device.size(),
+        "range exceeds device size"
+    );
+
+    match interface.direction {
+        Direction::Dump | Direction::Verify => {
+            info!("dumping...");
+            dump(&mut interface, device)
+        }
+        Direction::Hash => {
+            info!("hashing...");
+            hash(&mut interface, device)
+        }
+        Direction::Load | Direction::LoadVerify => {
+            panic!("loading requires a `FlashDevice`, use `rs_flash::run`")
+        }
+    }
+}
+
+fn dump<D: ReadFlashDevice>(
+    interface: &mut Interface<'_>,
+    device: &mut D,
+) -> Result<(), Error<D::Error>> {
+    for chunk in interface.chunks.clone() {
+        info!(
+            "chunk {} / {} (at 0x{:08x})",
+            chunk.number, chunk.total, chunk.offset
+        );
+        let slot = interface.slot(&chunk);
+        let range = interface.buffer_range(slot);
+        // Spin until the host has read the buffer.
+        let Some(state) = interface.acquire(slot) else {
+            return Err(interface.fail_protocol(chunk.offset));
+        };
+        // Read the next chunk into the buffer.
+        if let Err(e) = device.read(chunk.offset, interface.buffer(range.clone())) {
+            return Err(interface.fail(ErrorCode::Read, chunk.offset, e));
+        }
+        // Publish the checksum, so the host can check its read of the buffer.
+        let crc = crc32(interface.buffer(range));
+        interface.checksum[slot].store(crc as _, Ordering::SeqCst);
+        // Signal buffer is ready to be read.
+        interface.signal(slot, state, Control::Full);
+    }
+    // Spin until the host has read all buffers.
+    release_all(interface)
+}
+
+fn hash<D: ReadFlashDevice>(
+    interface: &mut Interface<'_>,
+    device: &mut D,
+) -> Result<(), Error<D::Error>> {
+    for chunk in interface.chunks.clone() {
+        info!(
+            "chunk {} / {} (at 0x{:08x})",
+            chunk.number, chunk.total, chunk.offset
+        );
+        let slot = interface.slot(&chunk);
+        let range = interface.buffer_range(slot);
+        // Spin until the host has read the checksum.
+        let Some(state) = interface.acquire(slot) else {
+            return Err(interface.fail_protocol(chunk.offset));
+        };
+        // Read the next chunk into the buffer, and replace it by its checksum.
+        let buffer = interface.buffer(range);
+        if let Err(e) = device.read(chunk.offset, buffer) {
+            return Err(interface.fail(ErrorCode::Read, chunk.offset, e));
+        }
+        let crc = crc32(buffer);
+        buffer[..4].copy_from_slice(&crc.to_le_bytes());
+        // Signal checksum is ready to be read.
+        interface.signal(slot, state, Control::Full);
+    }
+    // Spin until the host has read all checksums.
+    release_all(interface)
+}
+
+/// Spin until the host has handed all buffers back to the target, after the
+/// last chunk when dumping, verifying or hashing.
+fn release_all<E>(interface: &Interface<'_>) -> Result<(), Error<E>> {
+    for slot in 0..interface.control.len() {
+        if interface.acquire(slot).is_none() {
+            return Err(interface.fail_protocol(interface.chunks.range().end));
+        }
+    }
+    Ok(())
+}
+
+fn erase<D: FlashDevice>(
+    interface: &mut Interface<'_>,
+    device: &mut D,
+) -> Result<(), Error<D::Error>> {
+    match interface.params.erase {
+        Erase::None | Erase::Chunk => Ok(()),
+        Erase::Range => {
+            let range = interface.chunks.range();
+            info!("erasing 0x{:08x}..0x{:08x}...", range.start, range.end);
+            let offset = range.start;
+            device
+                .erase(range)
+                .map_err(|e| interface.fail(ErrorCode::Erase, offset, e))
+        }
+        Erase::Chip => {
+            info!("erasing chip...");
+            device
+                .erase_all()
+                .map_err(|e| interface.fail(ErrorCode::Erase, 0, e))
+        }
+    }
+}
+
+fn load<D: FlashDevice>(
+    interface: &mut Interface<'_>,
+    device: &mut D,
+    verify: bool,
+) -> Result<(), Error<D::Error>> {
+    for chunk in interface.chunks.clone() {
+        info!(
+            "chunk {} / {} (at 0x{:08x})",
+            chunk.number, chunk.total, chunk.offset
+        );
+        let slot = interface.slot(&chunk);
+        // Spin until the host has read back the previous chunk in the buffer,
+        // and written the buffer, or skipped the chunk.
+        let Some(command) = interface.acquire(slot) else {
+            return Err(interface.fail_protocol(chunk.offset));
+        };
+        let len = interface.buffer_size();
+        match command {
+            Control::SkipUnchanged => {}
+            Control::SkipErased => program(interface, device, slot, chunk.offset, 0..len, false)?,
+            Control::WriteSectors => {
+                // Only program the changed sectors.
+                let sectors = interface.sectors[slot].load(Ordering::SeqCst);
+                let sector_size = interface.sector_size;
+                for i in 0..len / sector_size {
+                    if sectors & (1 << i) != 0 {
+                        let range = i * sector_size..(i + 1) * sector_size;
+                        program(interface, device, slot, chunk.offset, range, true)?;
+                    }
+                }
+            }
+            Control::Full => program(interface, device, slot, chunk.offset, 0..len, true)?,
+            // The target only owns buffers with a command.
+            Control::Free | Control::ReadBack | Control::Error => {
+                return Err(interface.fail_protocol(chunk.offset))
+            }
+        }
+        if verify {
+            // Read the committed chunk back into the buffer.
+            let range = interface.buffer_range(slot);
+            if let Err(e) = device.read(chunk.offset, interface.buffer(range)) {
+                return Err(interface.fail(ErrorCode::Read, chunk.offset, e));
+            }
+            // Signal buffer is ready to be read.
+            interface.signal(slot, command, Control::ReadBack);
+        } else {
+            // Signal buffer is ready to be written.
+            interface.signal(slot, command, Control::Free);
+        }
+    }
+    // Spin until the host has read back all buffers.
+    for slot in 0..interface.control.len() {
+        interface.spin_while(slot, Control::ReadBack);
+    }
+    Ok(())
+}
+
+/// Erase (if erasing per chunk) and write (if `write`) the `range` of buffer
+/// `slot`, into the chunk at `offset`.
+fn program<D: FlashDevice>(
+    interface: &mut Interface<'_>,
+    device: &mut D,
+    slot: usize,
+    offset: usize,
+    range: Range<usize>,
+    write: bool,
+) -> Result<(), Error<D::Error>> {
+    let start = offset + range.start;
+    if interface.params.erase == Erase::Chunk {
+        if let Err(e) = device.erase(start..offset + range.end) {
+            return Err(interface.fail(ErrorCode::Erase, start, e));
+        }
+    }
+    if write {
+        let base = interface.buffer_range(slot).start;
+        let buffer = interface.buffer(base + range.start..base + range.end);
+        if let Err(e) = device.write(start, buffer) {
+            return Err(interface.fail(ErrorCode::Write, start, e));
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,31 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod crc;
+mod device;
+pub mod protocol;
+#[cfg(feature = "embedded-storage")]
+mod storage;
+
+pub use device::{
+    report_error, run, run_read_only, Error, FlashDevice, Interface, ReadFlashDevice,
+};
+pub use protocol::table;
+pub use protocol::{
+    CONTROL_ERROR, CONTROL_FREE, CONTROL_FULL, CONTROL_READ_BACK, CONTROL_SKIP_ERASED,
+    CONTROL_SKIP_UNCHANGED, CONTROL_WRITE_SECTORS,
+};
+#[cfg(feature = "embedded-storage")]
+pub use storage::{NorFlashDevice, NorFlashDeviceError};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     Dump,
     Load,
+    Verify,
+    LoadVerify,
+    Hash,
 }
 
 impl Direction {
@@ -14,6 +34,9 @@ impl Direction {
         match self {
             Self::Dump => 1,
             Self::Load => 2,
+            Self::Verify => 3,
+            Self::LoadVerify => 4,
+            Self::Hash => 5,
         }
     }
 
@@ -22,35 +45,329 @@ impl Direction {
         match value {
             1 => Some(Self::Dump),
             2 => Some(Self::Load),
+            3 => Some(Self::Verify),
+            4 => Some(Self::LoadVerify),
+            5 => Some(Self::Hash),
             _ => None,
         }
     }
 }
 
+/// The reason the target has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Initializing the flash failed.
+    Init,
+    /// Reading the flash failed.
+    Read,
+    /// Writing the flash failed.
+    Write,
+    /// Erasing the flash failed.
+    Erase,
+    /// Any other, program-specific failure.
+    Other,
+    /// The host broke the protocol, e.g. with an invalid control word.
+    Protocol,
+}
+
+impl ErrorCode {
+    #[inline]
+    pub const fn as_u32(&self) -> u32 {
+        match self {
+            Self::Init => 1,
+            Self::Read => 2,
+            Self::Write => 3,
+            Self::Erase => 4,
+            Self::Other => 5,
+            Self::Protocol => 6,
+        }
+    }
+
+    #[inline]
+    pub const fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Init),
+            2 => Some(Self::Read),
+            3 => Some(Self::Write),
+            4 => Some(Self::Erase),
+            5 => Some(Self::Other),
+            6 => Some(Self::Protocol),
+            _ => None,
+        }
+    }
+
+    /// A description of the failed operation.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Init => "flash init",
+            Self::Read => "flash read",
+            Self::Write => "flash write",
+            Self::Erase => "flash erase",
+            Self::Other => "target",
+            Self::Protocol => "protocol",
+        }
+    }
+}
+
+/// How the target should erase flash before loading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// Don't erase, the flash is already erased.
+    None,
+    /// Erase only the requested range, before loading.
+    Range,
+    /// Erase the entire chip, before loading.
+    Chip,
+    /// Erase only the sectors of each chunk, right before writing it.
+    Chunk,
+}
+
+impl Erase {
+    #[inline]
+    pub const fn as_u32(&self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Range => 1,
+            Self::Chip => 2,
+            Self::Chunk => 3,
+        }
+    }
+
+    #[inline]
+    pub const fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::None),
+            1 => Some(Self::Range),
+            2 => Some(Self::Chip),
+            3 => Some(Self::Chunk),
+            _ => None,
+        }
+    }
+}
+
+/// Runtime parameters, written by the host before the target starts.
+///
+/// This allows one compiled program to serve many jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    /// The offset into the flash to start at, in bytes.
+    pub offset: usize,
+    /// The length to dump or load, in bytes.
+    pub length: usize,
+    /// Program-specific operation flags, passed through from the host.
+    pub flags: u32,
+    /// How to erase flash before loading.
+    pub erase: Erase,
+}
+
+impl Params {
+    /// The size of the parameters in words.
+    pub const WORDS: usize = 4;
+
+    /// The parameters as written by the host, or `None` if the offset or
+    /// length doesn't fit in 32 bits.
+    #[inline]
+    pub const fn to_words(&self) -> Option<[u32; Self::WORDS]> {
+        if self.offset > u32::MAX as usize || self.length > u32::MAX as usize {
+            return None;
+        }
+        Some([
+            self.offset as u32,
+            self.length as u32,
+            self.flags,
+            self.erase.as_u32(),
+        ])
+    }
+
+    #[inline]
+    pub const fn from_words(words: [u32; Self::WORDS]) -> Option<Self> {
+        let [offset, length, flags, erase] = words;
+        let erase = match Erase::from_u32(erase) {
+            Some(erase) => erase,
+            None => return None,
+        };
+        Some(Self {
+            offset: offset as usize,
+            length: length as usize,
+            flags,
+            erase,
+        })
+    }
+}
+
+/// A chunk of flash to dump or load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// The chunk number, starting at 1.
+    pub number: usize,
+    /// The total number of chunks in the range.
+    pub total: usize,
+    /// The offset of the chunk in flash, in bytes.
+    pub offset: usize,
+}
+
+/// An iterator over the chunks of a flash range.
+#[derive(Debug, Clone)]
+pub struct Chunks {
+    offset: usize,
+    end: usize,
+    buffer_size: usize,
+    number: usize,
+    total: usize,
+}
+
+impl Chunks {
+    /// Create an iterator over the chunks of `offset..offset + length`.
+    ///
+    /// # Panics
+    ///
+    /// If the offset or length are not a multiple of the buffer size, or the
+    /// range exceeds the flash size.
+    pub fn new(offset: usize, length: usize, buffer_size: usize, flash_size: usize) -> Self {
+        assert!(
+            offset % buffer_size == 0,
+            "offset must be a multiple of buffer size"
+        );
+        assert!(
+            length % buffer_size == 0,
+            "length must be a multiple of buffer size"
+        );
+        assert!(
+            offset <= flash_size && length <= flash_size - offset,
+            "range exceeds flash size"
+        );
+        Self {
+            offset,
+            end: offset + length,
+            buffer_size,
+            number: 1,
+            total: length / buffer_size,
+        }
+    }
+}
+
+impl Chunks {
+    /// The remaining range of flash, in bytes.
+    pub fn range(&self) -> core::ops::Range<usize> {
+        self.offset..self.end
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let chunk = Chunk {
+            number: self.number,
+            total: self.total,
+            offset: self.offset,
+        };
+        self.offset += self.buffer_size;
+        self.number += 1;
+        Some(chunk)
+    }
+}
+
 /// Sets up the flash interface.
 ///
 /// This exports the necessary information, and provides `RS_FLASH_BUFFER` and
-/// `RS_FLASH_CONTROL` for communicating with the host.
+/// `RS_FLASH_CONTROL` (one word per buffer) for communicating with the host.
+///
+/// Implementing [`FlashDevice`] for the chip and calling
+/// `rs_flash::run(rs_flash_interface(), &mut device)` implements the entire
+/// protocol. Programs that never load only need to implement
+/// [`ReadFlashDevice`], and call [`run_read_only`]. Alternatively, programs
+/// can use the items below directly, and follow the buffer states in
+/// [`protocol`].
+///
+/// When dumping or verifying, the target writes the [`crc::crc32`] of each
+/// chunk to `RS_FLASH_CHECKSUM` before setting `RS_FLASH_CONTROL` to `1`, so
+/// the host can detect corrupted reads of the buffer.
+///
+/// The flash table declares support for these features (see
+/// [`table::CAPABILITIES`]). Programs that don't use `rs_flash::run` must
+/// write the checksum when dumping, and handle [`CONTROL_SKIP_ERASED`],
+/// [`CONTROL_SKIP_UNCHANGED`] and [`CONTROL_WRITE_SECTORS`] when loading.
+///
+/// If an operation fails, call `rs_flash_error()` with the [`ErrorCode`] and
+/// the failing offset. This sets `RS_FLASH_STATUS` and `RS_FLASH_CONTROL` to
+/// [`CONTROL_ERROR`], so the host can stop right away.
+///
+/// The host writes the runtime [`Params`] to `RS_FLASH_PARAMS` before the
+/// target starts. Use `rs_flash_params()` to read them, and
+/// `rs_flash_chunks()` to iterate over the chunks of the requested range.
 ///
 /// # Usage
 ///
 /// For programs dumping flash, use:
 /// ```
+/// # use rs_flash::flash_interface;
 /// const FLASH_SIZE: usize = 16 * 1024 * 1024;
 /// const BUFFER_SIZE: usize = 32 * 1024;
 /// flash_interface!(FLASH_SIZE, BUFFER_SIZE, dump);
 /// ```
 ///
-/// For programs loading flash, use `load` instead of `dump`.
+/// For programs loading flash, use `load` instead of `dump`. Optionally, pass
+/// the sector size of the flash before the direction, so the host can write
+/// only the changed sectors of a chunk (otherwise, this is the buffer size):
+/// ```
+/// # use rs_flash::flash_interface;
+/// const FLASH_SIZE: usize = 16 * 1024 * 1024;
+/// const BUFFER_SIZE: usize = 32 * 1024;
+/// const SECTOR_SIZE: usize = 4 * 1024;
+/// flash_interface!(FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, load);
+/// ```
+///
+/// For programs verifying flash, use `verify` instead of `dump`. The target
+/// side of verifying is identical to dumping, but the host compares each chunk
+/// against the data file instead of writing it out.
+///
+/// For programs loading and then verifying flash in a single run, use
+/// `load_verify` instead of `dump`. After the host has written a chunk and set
+/// `RS_FLASH_CONTROL` to `1`, the target commits the chunk, reads it back into
+/// the buffer, and sets `RS_FLASH_CONTROL` to `2`. The host compares the read
+/// back chunk, and sets `RS_FLASH_CONTROL` to `0` to continue.
+///
+/// To overlap transferring chunks over the probe with reading or writing the
+/// flash, pass the number of buffers after the direction. The chunks rotate
+/// through the buffers, and each buffer has its own word in
+/// `RS_FLASH_CONTROL`, `RS_FLASH_CHECKSUM` and `RS_FLASH_SECTORS`. So while
+/// the host transfers one buffer, the target can read or write another:
+/// ```
+/// # use rs_flash::flash_interface;
+/// const FLASH_SIZE: usize = 16 * 1024 * 1024;
+/// const BUFFER_SIZE: usize = 32 * 1024;
+/// const SECTOR_SIZE: usize = 4 * 1024;
+/// const BUFFERS: usize = 2;
+/// flash_interface!(FLASH_SIZE, BUFFER_SIZE, SECTOR_SIZE, dump, BUFFERS);
+/// ```
+///
+/// For programs checking the integrity of flash without transferring it, use
+/// `hash` instead of `dump`. For each chunk, the target reads the chunk, and
+/// writes its [`crc::crc32`] to the start of the buffer as a little-endian
+/// `u32`, then sets `RS_FLASH_CONTROL` to `1`. The host compares it against
+/// the checksum of the data file, and sets `RS_FLASH_CONTROL` to `0` to
+/// continue. The buffer size must be at least 4 bytes.
 #[macro_export]
 macro_rules! flash_interface {
-    ($flash_size:ident, $buffer_size:ident, dump) => {
-        flash_interface!(@ $flash_size, $buffer_size, $crate::Direction::Dump);
+    ($flash_size:ident, $buffer_size:ident, $direction:ident) => {
+        $crate::flash_interface!($flash_size, $buffer_size, $buffer_size, $direction);
     };
-    ($flash_size:ident, $buffer_size:ident, load) => {
-        flash_interface!(@ $flash_size, $buffer_size, $crate::Direction::Load);
+    ($flash_size:ident, $buffer_size:ident, $sector_size:ident, $direction:ident) => {
+        $crate::flash_interface!(@ $flash_size, $buffer_size, $sector_size, $crate::flash_interface!(@direction $direction), 1);
     };
-    (@ $flash_size:ident, $buffer_size:ident, $direction:path) => {
+    ($flash_size:ident, $buffer_size:ident, $sector_size:ident, $direction:ident, $buffers:ident) => {
+        $crate::flash_interface!(@ $flash_size, $buffer_size, $sector_size, $crate::flash_interface!(@direction $direction), $buffers);
+    };
+    (@direction dump) => { $crate::Direction::Dump };
+    (@direction load) => { $crate::Direction::Load };
+    (@direction verify) => { $crate::Direction::Verify };
+    (@direction load_verify) => { $crate::Direction::LoadVerify };
+    (@direction hash) => { $crate::Direction::Hash };
+    (@ $flash_size:ident, $buffer_size:ident, $sector_size:ident, $direction:expr, $buffers:expr) => {
         /// The number of chunks required to read or write the entire flash.
         const _CHUNKS: usize = $flash_size / $buffer_size;
         /// Assert that the flash size is a multiple of the buffer size.
@@ -60,22 +377,154 @@ macro_rules! flash_interface {
             }
         }
         const _ASSERT_BUFFER_SIZE: () = _assert_buffer_size();
+        /// Assert that the buffer size is a multiple of the sector size, and
+        /// the sectors of a chunk fit the sector mask.
+        const fn _assert_sector_size() {
+            if $buffer_size % $sector_size != 0 {
+                ::core::panic!("Invalid sector size, must divide buffer size without remainder");
+            }
+            if $buffer_size / $sector_size > 32 {
+                ::core::panic!("Invalid sector size, buffer must not exceed 32 sectors");
+            }
+        }
+        const _ASSERT_SECTOR_SIZE: () = _assert_sector_size();
+        /// Assert that there is at least one buffer.
+        const fn _assert_buffers() {
+            if $buffers == 0 {
+                ::core::panic!("Invalid number of buffers, must be at least one");
+            }
+        }
+        const _ASSERT_BUFFERS: () = _assert_buffers();
+        /// Assert that the buffer fits the checksum, when hashing.
+        const fn _assert_hash_buffer_size() {
+            if ::core::matches!($direction, $crate::Direction::Hash) && $buffer_size < 4 {
+                ::core::panic!("Invalid buffer size, must be at least 4 bytes when hashing");
+            }
+        }
+        const _ASSERT_HASH_BUFFER_SIZE: () = _assert_hash_buffer_size();
+        /// The initial value of the per-buffer words.
+        #[allow(clippy::declare_interior_mutable_const)]
+        const _ATOMIC_ZERO: ::core::sync::atomic::AtomicUsize = ::core::sync::atomic::AtomicUsize::new(0);
 
         #[link_section = ".rs-flash"]
         #[used]
         #[no_mangle]
         /// Exported flash information (for the host program).
-        static _RS_FLASH_TABLE: [u32; 3] = [
-            $flash_size as _,
-            $buffer_size as _,
-            $direction.as_u32(),
-        ];
+        static _RS_FLASH_TABLE: [u32; $crate::table::LEN] =
+            $crate::table::encode($flash_size, $buffer_size, $buffers, $sector_size, $direction);
 
         #[export_name = "_RS_FLASH_BUFFER"]
-        /// Buffer in RAM for dumping or loading flash contents.
-        static mut RS_FLASH_BUFFER: ::core::mem::MaybeUninit<[u8; $buffer_size]> = ::core::mem::MaybeUninit::uninit();
+        /// Buffers in RAM for dumping or loading flash contents, one after the other.
+        static mut RS_FLASH_BUFFER: ::core::mem::MaybeUninit<[u8; $buffer_size * $buffers]> = ::core::mem::MaybeUninit::uninit();
         #[export_name = "_RS_FLASH_CONTROL"]
-        /// Control signalling between the target and the host.
-        static mut RS_FLASH_CONTROL: ::core::sync::atomic::AtomicUsize = ::core::sync::atomic::AtomicUsize::new(0);
+        /// Control signalling between the target and the host, per buffer.
+        static mut RS_FLASH_CONTROL: [::core::sync::atomic::AtomicUsize; $buffers] = [_ATOMIC_ZERO; $buffers];
+        #[export_name = "_RS_FLASH_CHECKSUM"]
+        /// The checksum of the chunk in each buffer, when dumping.
+        static RS_FLASH_CHECKSUM: [::core::sync::atomic::AtomicUsize; $buffers] = [_ATOMIC_ZERO; $buffers];
+        #[export_name = "_RS_FLASH_SECTORS"]
+        /// The changed sectors of the chunk in each buffer, for `CONTROL_WRITE_SECTORS`.
+        static RS_FLASH_SECTORS: [::core::sync::atomic::AtomicUsize; $buffers] = [_ATOMIC_ZERO; $buffers];
+        #[export_name = "_RS_FLASH_STATUS"]
+        /// The error code and failing offset, when the target has failed.
+        static RS_FLASH_STATUS: [::core::sync::atomic::AtomicUsize; 2] = [
+            ::core::sync::atomic::AtomicUsize::new(0),
+            ::core::sync::atomic::AtomicUsize::new(0),
+        ];
+        #[link_section = ".uninit.rs-flash"]
+        #[export_name = "_RS_FLASH_PARAMS"]
+        /// Runtime parameters, written by the host.
+        ///
+        /// This is not initialized, so the host can write it before the target starts.
+        static mut RS_FLASH_PARAMS: ::core::mem::MaybeUninit<[u32; $crate::Params::WORDS]> = ::core::mem::MaybeUninit::uninit();
+
+        /// The runtime parameters written by the host.
+        #[allow(dead_code)]
+        fn rs_flash_params() -> $crate::Params {
+            let words = unsafe { ::core::ptr::read_volatile(RS_FLASH_PARAMS.as_ptr()) };
+            $crate::Params::from_words(words).expect("invalid flash parameters")
+        }
+
+        /// Report an error to the host, and stop.
+        #[allow(dead_code)]
+        fn rs_flash_error(code: $crate::ErrorCode, offset: usize) -> ! {
+            let control = unsafe { &*::core::ptr::addr_of!(RS_FLASH_CONTROL) };
+            $crate::report_error(&RS_FLASH_STATUS, control, code, offset);
+            loop {
+                ::core::hint::spin_loop();
+            }
+        }
+
+        /// The host/target interface, for `rs_flash::run`.
+        ///
+        /// # Panics
+        ///
+        /// If called more than once.
+        #[allow(dead_code)]
+        fn rs_flash_interface() -> $crate::Interface<'static> {
+            use ::core::sync::atomic::{AtomicBool, Ordering};
+            static TAKEN: AtomicBool = AtomicBool::new(false);
+            if TAKEN.swap(true, Ordering::SeqCst) {
+                ::core::panic!("flash interface already taken");
+            }
+            let buffer = unsafe { ::core::ptr::addr_of_mut!(RS_FLASH_BUFFER) } as *mut [u8; $buffer_size * $buffers];
+            let buffer = ::core::ptr::NonNull::new(buffer as *mut [u8]).unwrap();
+            let control = unsafe { &*::core::ptr::addr_of!(RS_FLASH_CONTROL) };
+            // SAFETY: The buffers are static, and only taken once.
+            unsafe { $crate::Interface::new(
+                $direction,
+                buffer,
+                control,
+                &RS_FLASH_STATUS,
+                &RS_FLASH_CHECKSUM,
+                &RS_FLASH_SECTORS,
+                $sector_size,
+                rs_flash_params(),
+                rs_flash_chunks(),
+            ) }
+        }
+
+        /// The chunks of the flash range requested by the host.
+        #[allow(dead_code)]
+        fn rs_flash_chunks() -> $crate::Chunks {
+            let params = rs_flash_params();
+            $crate::Chunks::new(params.offset, params.length, $buffer_size, $flash_size)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_round_trip() {
+        let params = Params {
+            offset: 0x1000,
+            length: 0xffff_f000,
+            flags: 0x8000_0001,
+            erase: Erase::Chunk,
+        };
+        let words = params.to_words().unwrap();
+        assert_eq!(words, [0x1000, 0xffff_f000, 0x8000_0001, 3]);
+        assert_eq!(Params::from_words(words), Some(params));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn params_reject_ranges_above_4_gib() {
+        let params = Params {
+            offset: 0x1_0000_0000,
+            length: 0x1000,
+            flags: 0,
+            erase: Erase::None,
+        };
+        assert_eq!(params.to_words(), None);
+        let params = Params {
+            offset: 0,
+            length: 0x1_0000_0000,
+            ..params
+        };
+        assert_eq!(params.to_words(), None);
     }
 }
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The protocol between the host and the target.
+//!
+//! Each buffer has a control word in `RS_FLASH_CONTROL`, which says who owns
+//! the buffer. Whoever owns a buffer may access it, and hands it over by
+//! setting the control word.
+//!
+//! When dumping, verifying or hashing, the target fills a [`Free`] buffer and
+//! sets it to [`Full`]. The host reads it, and sets it back to [`Free`].
+//!
+//! When loading, the host fills a [`Free`] buffer and sets it to [`Full`] (or
+//! one of the skip or sector commands). The target commits the chunk, and sets
+//! it back to [`Free`]. When loading and verifying, the target instead reads
+//! the chunk back into the buffer and sets it to [`ReadBack`], and the host
+//! compares it and sets it back to [`Free`].
+//!
+//! The target may set any buffer to [`Error`] at any time, after which the
+//! protocol stops.
+//!
+//! [`Free`]: Control::Free
+//! [`Full`]: Control::Full
+//! [`ReadBack`]: Control::ReadBack
+//! [`Error`]: Control::Error
+
+pub mod table;
+
+use crate::Direction;
+
+/// The value of `RS_FLASH_CONTROL` when the buffer is free.
+pub const CONTROL_FREE: u32 = 0;
+
+/// The value of `RS_FLASH_CONTROL` when the buffer is full.
+///
+/// When loading, the target erases (if erasing per chunk) and writes the whole
+/// chunk.
+pub const CONTROL_FULL: u32 = 1;
+
+/// The value of `RS_FLASH_CONTROL` when loading and verifying, once the target
+/// has read the written chunk back into the buffer.
+pub const CONTROL_READ_BACK: u32 = 2;
+
+/// The value of `RS_FLASH_CONTROL` when loading, if the chunk is entirely
+/// erased.
+///
+/// The host hasn't written the buffer. The target only erases the chunk, if
+/// erasing per chunk, but doesn't write it.
+pub const CONTROL_SKIP_ERASED: u32 = 3;
+
+/// The value of `RS_FLASH_CONTROL` when loading, if the chunk is unchanged.
+///
+/// The host hasn't written the buffer. The target neither erases nor writes
+/// the chunk.
+pub const CONTROL_SKIP_UNCHANGED: u32 = 4;
+
+/// The value of `RS_FLASH_CONTROL` when loading, if only some sectors of the
+/// chunk have changed.
+///
+/// `RS_FLASH_SECTORS` is a bit mask of the changed sectors, with bit `n` for
+/// the `n`-th sector of the chunk. The host has only written these sectors of
+/// the buffer. The target erases (if erasing per chunk) and writes only these
+/// sectors, and leaves the others untouched.
+pub const CONTROL_WRITE_SECTORS: u32 = 5;
+
+/// The value of `RS_FLASH_CONTROL` when the target has failed.
+///
+/// The error code and offset are in `RS_FLASH_STATUS`.
+pub const CONTROL_ERROR: u32 = u32::MAX;
+
+/// The state of a buffer, as stored in its control word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// See [`CONTROL_FREE`].
+    Free,
+    /// See [`CONTROL_FULL`].
+    Full,
+    /// See [`CONTROL_READ_BACK`].
+    ReadBack,
+    /// See [`CONTROL_SKIP_ERASED`].
+    SkipErased,
+    /// See [`CONTROL_SKIP_UNCHANGED`].
+    SkipUnchanged,
+    /// See [`CONTROL_WRITE_SECTORS`].
+    WriteSectors,
+    /// See [`CONTROL_ERROR`].
+    Error,
+}
+
+/// Who may access a buffer, and change its control word next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Host,
+    Target,
+    /// The protocol has stopped.
+    Nobody,
+}
+
+impl Control {
+    #[inline]
+    pub const fn as_u32(&self) -> u32 {
+        match self {
+            Self::Free => CONTROL_FREE,
+            Self::Full => CONTROL_FULL,
+            Self::ReadBack => CONTROL_READ_BACK,
+            Self::SkipErased => CONTROL_SKIP_ERASED,
+            Self::SkipUnchanged => CONTROL_SKIP_UNCHANGED,
+            Self::WriteSectors => CONTROL_WRITE_SECTORS,
+            Self::Error => CONTROL_ERROR,
+        }
+    }
+
+    #[inline]
+    pub const fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            CONTROL_FREE => Some(Self::Free),
+            CONTROL_FULL => Some(Self::Full),
+            CONTROL_READ_BACK => Some(Self::ReadBack),
+            CONTROL_SKIP_ERASED => Some(Self::SkipErased),
+            CONTROL_SKIP_UNCHANGED => Some(Self::SkipUnchanged),
+            CONTROL_WRITE_SECTORS => Some(Self::WriteSectors),
+            CONTROL_ERROR => Some(Self::Error),
+            _ => None,
+        }
+    }
+
+    /// Whether this state tells the target to commit a chunk when loading.
+    #[inline]
+    pub const fn is_command(&self) -> bool {
+        matches!(
+            self,
+            Self::Full | Self::SkipErased | Self::SkipUnchanged | Self::WriteSectors
+        )
+    }
+
+    /// Who owns a buffer in this state, when transferring in `direction`.
+    ///
+    /// States that aren't used in `direction` are owned by nobody.
+    pub const fn owner(&self, direction: Direction) -> Owner {
+        match direction {
+            Direction::Dump | Direction::Verify | Direction::Hash => match self {
+                Self::Free => Owner::Target,
+                Self::Full => Owner::Host,
+                _ => Owner::Nobody,
+            },
+            Direction::Load | Direction::LoadVerify => match self {
+                Self::Free => Owner::Host,
+                Self::ReadBack if matches!(direction, Direction::LoadVerify) => Owner::Host,
+                Self::ReadBack | Self::Error => Owner::Nobody,
+                _ => Owner::Target,
+            },
+        }
+    }
+
+    /// Whether the owner may change a buffer from this state to `next`, when
+    /// transferring in `direction`.
+    ///
+    /// The target may also fail in any state.
+    pub const fn can_become(&self, next: Self, direction: Direction) -> bool {
+        if matches!(next, Self::Error) {
+            return !matches!(self, Self::Error);
+        }
+        match direction {
+            Direction::Dump | Direction::Verify | Direction::Hash => {
+                matches!(
+                    (self, next),
+                    (Self::Free, Self::Full) | (Self::Full, Self::Free)
+                )
+            }
+            Direction::Load => match self {
+                Self::Free => next.is_command(),
+                _ => self.is_command() && matches!(next, Self::Free),
+            },
+            Direction::LoadVerify => match self {
+                Self::Free => next.is_command(),
+                Self::ReadBack => matches!(next, Self::Free),
+                _ => self.is_command() && matches!(next, Self::ReadBack),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const CONTROLS: [Control; 7] = [
+        Control::Free,
+        Control::Full,
+        Control::ReadBack,
+        Control::SkipErased,
+        Control::SkipUnchanged,
+        Control::WriteSectors,
+        Control::Error,
+    ];
+
+    const DIRECTIONS: [Direction; 5] = [
+        Direction::Dump,
+        Direction::Load,
+        Direction::Verify,
+        Direction::LoadVerify,
+        Direction::Hash,
+    ];
+
+    #[test]
+    fn control_round_trip() {
+        for control in CONTROLS {
+            assert_eq!(Control::from_u32(control.as_u32()), Some(control));
+        }
+    }
+
+    /// Whether changing from `from` to `to` hands the buffer over to the other
+    /// side. Only freeing a read back buffer doesn't, because the host then
+    /// fills it with the next chunk.
+    fn hands_over(from: Control, to: Control) -> bool {
+        (from, to) != (Control::ReadBack, Control::Free)
+    }
+
+    #[test]
+    fn transitions_hand_over_the_buffer() {
+        for direction in DIRECTIONS {
+            for from in CONTROLS {
+                for to in CONTROLS {
+                    if from.can_become(to, direction) && to != Control::Error {
+                        assert_ne!(from.owner(direction), Owner::Nobody);
+                        assert_ne!(to.owner(direction), Owner::Nobody);
+                        assert_eq!(
+                            from.owner(direction) != to.owner(direction),
+                            hands_over(from, to),
+                            "{:?}: {:?} -> {:?}",
+                            direction,
+                            from,
+                            to
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dump_cycle() {
+        let direction = Direction::Dump;
+        assert_eq!(Control::Free.owner(direction), Owner::Target);
+        assert!(Control::Free.can_become(Control::Full, direction));
+        assert!(Control::Full.can_become(Control::Free, direction));
+        assert!(!Control::Full.can_become(Control::ReadBack, direction));
+    }
+
+    #[test]
+    fn load_verify_cycle() {
+        let direction = Direction::LoadVerify;
+        assert!(Control::Free.can_become(Control::WriteSectors, direction));
+        assert!(Control::WriteSectors.can_become(Control::ReadBack, direction));
+        assert!(!Control::WriteSectors.can_become(Control::Free, direction));
+        assert!(Control::ReadBack.can_become(Control::Free, direction));
+        assert!(!Control::Free.can_become(Control::ReadBack, direction));
+    }
+
+    fn direction() -> impl Strategy<Value = Direction> {
+        prop::sample::select(&DIRECTIONS[..])
+    }
+
+    proptest! {
+        #[test]
+        fn unknown_values_are_rejected(value in any::<u32>()) {
+            if let Some(control) = Control::from_u32(value) {
+                prop_assert_eq!(control.as_u32(), value);
+            } else {
+                prop_assert!(CONTROLS.iter().all(|c| c.as_u32() != value));
+            }
+        }
+
+        /// Walk a buffer through random transitions by its owner, and check
+        /// it never gets stuck, and the target can always fail.
+        #[test]
+        fn random_walk(direction in direction(), picks in prop::collection::vec(any::<prop::sample::Index>(), 1..64)) {
+            let mut state = Control::Free;
+            for pick in picks {
+                let next: Vec<_> = CONTROLS
+                    .iter()
+                    .copied()
+                    .filter(|next| *next != Control::Error && state.can_become(*next, direction))
+                    .collect();
+                // A live buffer can always make progress.
+                prop_assert!(!next.is_empty(), "{:?} is stuck in {:?}", direction, state);
+                let next = *pick.get(&next);
+                prop_assert_eq!(
+                    state.owner(direction) != next.owner(direction),
+                    hands_over(state, next)
+                );
+                state = next;
+            }
+            prop_assert!(state.can_become(Control::Error, direction));
+            prop_assert_eq!(Control::Error.owner(direction), Owner::Nobody);
+        }
+    }
+}
@@ -0,0 +1,500 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The flash table format.
+//!
+//! The flash table is exported in the `.rs-flash` section, and describes the
+//! program to the host. It starts with [`MAGIC`] and the protocol [`VERSION`],
+//! followed by a list of fields. Each field is a header word with the field
+//! type in the upper 16 bits and the value length in bytes in the lower 16
+//! bits, followed by the value padded to whole words.
+//!
+//! Hosts skip fields of unknown types, so fields can be added without bumping
+//! the version. The version is only bumped for incompatible changes.
+//!
+//! Version 1 is the original table without magic or version, which consists
+//! only of the flash size, the buffer size, and the direction.
+
+use crate::Direction;
+
+/// The magic at the start of the table (`RSFL`).
+pub const MAGIC: u32 = u32::from_le_bytes(*b"RSFL");
+/// The protocol version.
+pub const VERSION: u32 = 2;
+
+/// The flash size in bytes (`u32`).
+pub const FIELD_FLASH_SIZE: u16 = 1;
+/// The buffer size in bytes (`u32`).
+pub const FIELD_BUFFER_SIZE: u16 = 2;
+/// The operation mode/direction (`u32`, see [`Direction::as_u32`]).
+pub const FIELD_DIRECTION: u16 = 3;
+/// The protocol features the program supports (`u32`, see `CAP_*`).
+pub const FIELD_CAPABILITIES: u16 = 4;
+/// The sector size in bytes (`u32`), the granularity of partial chunk writes.
+pub const FIELD_SECTOR_SIZE: u16 = 5;
+/// The number of buffers (`u32`), each the buffer size.
+pub const FIELD_BUFFERS: u16 = 6;
+
+/// The program handles [`CONTROL_SKIP_ERASED`] and [`CONTROL_SKIP_UNCHANGED`].
+///
+/// [`CONTROL_SKIP_ERASED`]: crate::CONTROL_SKIP_ERASED
+/// [`CONTROL_SKIP_UNCHANGED`]: crate::CONTROL_SKIP_UNCHANGED
+pub const CAP_SKIP: u32 = 1 << 0;
+/// The program handles [`CONTROL_WRITE_SECTORS`](crate::CONTROL_WRITE_SECTORS).
+pub const CAP_SECTORS: u32 = 1 << 1;
+
+/// The program writes the checksum of each dumped chunk to `RS_FLASH_CHECKSUM`.
+pub const CAP_CHECKSUM: u32 = 1 << 2;
+
+/// The capabilities of programs using [`flash_interface!`](crate::flash_interface).
+pub const CAPABILITIES: u32 = CAP_SKIP | CAP_SECTORS | CAP_CHECKSUM;
+
+/// The number of words in the table.
+pub const LEN: usize = 2 + 6 * 2;
+
+/// The header word of a field.
+#[inline]
+pub const fn header(field: u16, len: u16) -> u32 {
+    (field as u32) << 16 | len as u32
+}
+
+/// Encode the flash table.
+pub const fn encode(
+    flash_size: usize,
+    buffer_size: usize,
+    buffers: usize,
+    sector_size: usize,
+    direction: Direction,
+) -> [u32; LEN] {
+    [
+        MAGIC,
+        VERSION,
+        header(FIELD_FLASH_SIZE, 4),
+        flash_size as u32,
+        header(FIELD_BUFFER_SIZE, 4),
+        buffer_size as u32,
+        header(FIELD_DIRECTION, 4),
+        direction.as_u32(),
+        header(FIELD_CAPABILITIES, 4),
+        CAPABILITIES,
+        header(FIELD_SECTOR_SIZE, 4),
+        sector_size as u32,
+        header(FIELD_BUFFERS, 4),
+        buffers as u32,
+    ]
+}
+
+/// A decoded flash table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Table {
+    pub version: u32,
+    pub flash_size: u32,
+    pub buffer_size: u32,
+    pub direction: Direction,
+    /// The protocol features the program supports (see `CAP_*`).
+    pub capabilities: u32,
+    /// The buffer size, unless the program specifies a smaller sector size.
+    pub sector_size: u32,
+    pub buffers: u32,
+}
+
+/// Why a flash table couldn't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The table doesn't start with [`MAGIC`], and isn't a version 1 table.
+    NoMagic,
+    /// The table is newer than [`VERSION`].
+    Newer(u32),
+    /// The table version is invalid.
+    Version(u32),
+    /// The value of the field is cut off.
+    Truncated(u16),
+    /// The value of the field has the wrong length in bytes.
+    WrongSize(u16, u16),
+    /// A required field is missing.
+    Missing(u16),
+    /// The size in the field is zero.
+    ZeroSize(u16),
+    /// The direction is invalid.
+    Direction(u32),
+    /// The buffer size doesn't divide the flash size.
+    BufferSize(u32, u32),
+    /// The sector size doesn't divide the buffer size.
+    SectorSize(u32, u32),
+    /// There are more than 32 sectors per buffer.
+    TooManySectors(u32, u32),
+    /// The number of buffers is zero.
+    NoBuffers,
+    /// The buffer size is too small for the checksum, when hashing.
+    HashBufferSize(u32),
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoMagic => write!(f, "flash table has no magic"),
+            Self::Newer(version) => write!(
+                f,
+                "flash table version {} is newer than the supported version {}, update rs-flash",
+                version, VERSION
+            ),
+            Self::Version(version) => write!(f, "invalid flash table version {}", version),
+            Self::Truncated(field) => write!(f, "flash table field {} is truncated", field),
+            Self::WrongSize(field, len) => write!(
+                f,
+                "flash table field {} is wrong size ({} bytes)",
+                field, len
+            ),
+            Self::Missing(field) => match field_name(*field) {
+                Some(name) => write!(f, "flash table {} missing", name),
+                None => write!(f, "flash table field {} missing", field),
+            },
+            Self::ZeroSize(field) => match field_name(*field) {
+                Some(name) => write!(f, "flash table {} is zero", name),
+                None => write!(f, "flash table field {} is zero", field),
+            },
+            Self::Direction(direction) => {
+                write!(f, "invalid flash table direction 0x{:08x}", direction)
+            }
+            Self::BufferSize(buffer_size, flash_size) => write!(
+                f,
+                "flash table buffer size {} doesn't divide flash size {}",
+                buffer_size, flash_size
+            ),
+            Self::SectorSize(sector_size, buffer_size) => write!(
+                f,
+                "flash table sector size {} doesn't divide buffer size {}",
+                sector_size, buffer_size
+            ),
+            Self::TooManySectors(sector_size, buffer_size) => write!(
+                f,
+                "flash table sector size {} is too small for buffer size {}",
+                sector_size, buffer_size
+            ),
+            Self::NoBuffers => write!(f, "flash table has no buffers"),
+            Self::HashBufferSize(buffer_size) => write!(
+                f,
+                "flash table buffer size {} is too small to hash",
+                buffer_size
+            ),
+        }
+    }
+}
+
+/// The name of the field in error messages.
+fn field_name(field: u16) -> Option<&'static str> {
+    match field {
+        FIELD_FLASH_SIZE => Some("flash size"),
+        FIELD_BUFFER_SIZE => Some("buffer size"),
+        FIELD_DIRECTION => Some("direction"),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// Decode a flash table.
+///
+/// Older table versions are accepted, but newer ones are rejected. Fields of
+/// unknown types are skipped.
+pub fn decode(words: &[u32]) -> Result<Table, DecodeError> {
+    let (version, mut fields) = match words {
+        [MAGIC, version, fields @ ..] => (*version, fields),
+        [flash_size, buffer_size, direction] => {
+            // Version 1 programs only dump or load.
+            let direction = match Direction::from_u32(*direction) {
+                Some(direction @ (Direction::Dump | Direction::Load)) => direction,
+                _ => return Err(DecodeError::Direction(*direction)),
+            };
+            return validate(Table {
+                version: 1,
+                flash_size: *flash_size,
+                buffer_size: *buffer_size,
+                direction,
+                capabilities: 0,
+                sector_size: *buffer_size,
+                buffers: 1,
+            });
+        }
+        _ => return Err(DecodeError::NoMagic),
+    };
+    match version {
+        VERSION => {}
+        _ if version > VERSION => return Err(DecodeError::Newer(version)),
+        _ => return Err(DecodeError::Version(version)),
+    }
+
+    let mut flash_size = None;
+    let mut buffer_size = None;
+    let mut direction = None;
+    let mut capabilities = None;
+    let mut sector_size = None;
+    let mut buffers = None;
+
+    while let Some((&header, rest)) = fields.split_first() {
+        let field = (header >> 16) as u16;
+        let len = (header & 0xffff) as u16;
+        let value = rest
+            .get(..(len as usize).div_ceil(4))
+            .ok_or(DecodeError::Truncated(field))?;
+        fields = &rest[value.len()..];
+
+        let slot = match field {
+            FIELD_FLASH_SIZE => &mut flash_size,
+            FIELD_BUFFER_SIZE => &mut buffer_size,
+            FIELD_DIRECTION => &mut direction,
+            FIELD_CAPABILITIES => &mut capabilities,
+            FIELD_SECTOR_SIZE => &mut sector_size,
+            FIELD_BUFFERS => &mut buffers,
+            _ => continue,
+        };
+        if len != 4 {
+            return Err(DecodeError::WrongSize(field, len));
+        }
+        *slot = Some(value[0]);
+    }
+
+    let buffer_size = buffer_size.ok_or(DecodeError::Missing(FIELD_BUFFER_SIZE))?;
+    let direction = direction.ok_or(DecodeError::Missing(FIELD_DIRECTION))?;
+    validate(Table {
+        version,
+        flash_size: flash_size.ok_or(DecodeError::Missing(FIELD_FLASH_SIZE))?,
+        buffer_size,
+        direction: Direction::from_u32(direction).ok_or(DecodeError::Direction(direction))?,
+        // Programs built before capabilities were added support none.
+        capabilities: capabilities.unwrap_or(0),
+        // Programs built before sector sizes were added write whole chunks.
+        sector_size: sector_size.unwrap_or(buffer_size),
+        // Programs built before multiple buffers were added have one.
+        buffers: buffers.unwrap_or(1),
+    })
+}
+
+fn validate(table: Table) -> Result<Table, DecodeError> {
+    let Table {
+        flash_size,
+        sector_size,
+        buffer_size,
+        ..
+    } = table;
+    if flash_size == 0 {
+        return Err(DecodeError::ZeroSize(FIELD_FLASH_SIZE));
+    }
+    if buffer_size == 0 {
+        return Err(DecodeError::ZeroSize(FIELD_BUFFER_SIZE));
+    }
+    if flash_size % buffer_size != 0 {
+        return Err(DecodeError::BufferSize(buffer_size, flash_size));
+    }
+    if sector_size == 0 || buffer_size % sector_size != 0 {
+        return Err(DecodeError::SectorSize(sector_size, buffer_size));
+    }
+    if buffer_size / sector_size > 32 {
+        return Err(DecodeError::TooManySectors(sector_size, buffer_size));
+    }
+    if table.buffers == 0 {
+        return Err(DecodeError::NoBuffers);
+    }
+    if table.direction == Direction::Hash && buffer_size < 4 {
+        return Err(DecodeError::HashBufferSize(buffer_size));
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn direction() -> impl Strategy<Value = Direction> {
+        prop::sample::select(
+            &[
+                Direction::Dump,
+                Direction::Load,
+                Direction::Verify,
+                Direction::LoadVerify,
+                Direction::Hash,
+            ][..],
+        )
+    }
+
+    #[test]
+    fn decode_version_1() {
+        let table = decode(&[0x1000, 0x100, Direction::Load.as_u32()]).unwrap();
+        assert_eq!(table.version, 1);
+        assert_eq!(table.capabilities, 0);
+        assert_eq!(table.sector_size, 0x100);
+        assert_eq!(table.buffers, 1);
+    }
+
+    #[test]
+    fn decode_rejects_newer_versions() {
+        let mut words = encode(0x1000, 0x100, 1, 0x100, Direction::Dump);
+        words[1] = VERSION + 1;
+        assert_eq!(decode(&words), Err(DecodeError::Newer(VERSION + 1)));
+        words[1] = 0;
+        assert_eq!(decode(&words), Err(DecodeError::Version(0)));
+    }
+
+    #[test]
+    fn decode_rejects_bad_tables() {
+        assert_eq!(decode(&[]), Err(DecodeError::NoMagic));
+        assert_eq!(
+            decode(&[MAGIC, VERSION, header(FIELD_FLASH_SIZE, 8), 0]),
+            Err(DecodeError::Truncated(FIELD_FLASH_SIZE))
+        );
+        assert_eq!(
+            decode(&[MAGIC, VERSION, header(FIELD_FLASH_SIZE, 2), 0]),
+            Err(DecodeError::WrongSize(FIELD_FLASH_SIZE, 2))
+        );
+        assert_eq!(
+            decode(&[MAGIC, VERSION]),
+            Err(DecodeError::Missing(FIELD_BUFFER_SIZE))
+        );
+        assert_eq!(
+            decode(&encode(0x1000, 0x300, 1, 0x300, Direction::Load)),
+            Err(DecodeError::BufferSize(0x300, 0x1000))
+        );
+        assert_eq!(
+            decode(&[0x1000, 0x300, Direction::Dump.as_u32()]),
+            Err(DecodeError::BufferSize(0x300, 0x1000))
+        );
+        assert_eq!(
+            decode(&encode(0x1000, 0x100, 1, 0x30, Direction::Load)),
+            Err(DecodeError::SectorSize(0x30, 0x100))
+        );
+        assert_eq!(
+            decode(&encode(0x1000, 0x100, 1, 0x4, Direction::Load)),
+            Err(DecodeError::TooManySectors(0x4, 0x100))
+        );
+        assert_eq!(
+            decode(&encode(0x1000, 0x100, 0, 0x100, Direction::Load)),
+            Err(DecodeError::NoBuffers)
+        );
+        assert_eq!(
+            decode(&encode(0x1000, 0x2, 1, 0x2, Direction::Hash)),
+            Err(DecodeError::HashBufferSize(0x2))
+        );
+        assert!(decode(&encode(0x1000, 0x2, 1, 0x2, Direction::Dump)).is_ok());
+    }
+
+    #[test]
+    fn decode_rejects_zero_sizes() {
+        assert_eq!(
+            decode(&[0, 0x100, Direction::Dump.as_u32()]),
+            Err(DecodeError::ZeroSize(FIELD_FLASH_SIZE))
+        );
+        assert_eq!(
+            decode(&[0x1000, 0, Direction::Dump.as_u32()]),
+            Err(DecodeError::ZeroSize(FIELD_BUFFER_SIZE))
+        );
+        // An explicit sector size divides a zero buffer size.
+        assert_eq!(
+            decode(&encode(0x1000, 0, 1, 0x100, Direction::Load)),
+            Err(DecodeError::ZeroSize(FIELD_BUFFER_SIZE))
+        );
+        assert_eq!(
+            DecodeError::ZeroSize(FIELD_BUFFER_SIZE).to_string(),
+            "flash table buffer size is zero"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            chunks in 1u32..=0x100,
+            buffer_size in 1u32..=0x1_0000,
+            sectors in 1u32..=32,
+            buffers in 1u32..=16,
+            direction in direction(),
+        ) {
+            let buffer_size = buffer_size * sectors;
+            let sector_size = buffer_size / sectors;
+            let flash_size = buffer_size * chunks;
+            let words = encode(
+                flash_size as _,
+                buffer_size as _,
+                buffers as _,
+                sector_size as _,
+                direction,
+            );
+            prop_assert_eq!(decode(&words), Ok(Table {
+                version: VERSION,
+                flash_size,
+                buffer_size,
+                direction,
+                capabilities: CAPABILITIES,
+                sector_size,
+                buffers,
+            }));
+        }
+
+        /// Fields added later (with any length) don't break older hosts.
+        #[test]
+        fn skips_unknown_fields(
+            field in (FIELD_BUFFERS + 1)..,
+            value in prop::collection::vec(any::<u32>(), 0..4),
+            len_pad in 0u16..4,
+            at in 0usize..=6,
+        ) {
+            let words = encode(0x1000, 0x100, 2, 0x80, Direction::LoadVerify);
+            let len = (value.len() as u16 * 4).saturating_sub(len_pad);
+            // Insert the field between two existing fields.
+            let at = 2 + at * 2;
+            let mut extended = words[..at].to_vec();
+            extended.push(header(field, len));
+            extended.extend_from_slice(&value[..(len as usize).div_ceil(4)]);
+            extended.extend_from_slice(&words[at..]);
+            prop_assert_eq!(decode(&extended), decode(&words));
+        }
+
+        #[test]
+        fn rejects_zero_sizes(
+            flash_size in 0u32..2,
+            buffer_size in 0u32..2,
+            sector_size in 1u32..=0x100,
+            direction in direction(),
+        ) {
+            let flash_size = flash_size * 0x1000;
+            let buffer_size = buffer_size * 0x100;
+            let words = encode(flash_size as _, buffer_size as _, 1, sector_size as _, direction);
+            // Version 1 tables only dump or load.
+            let v1 = [flash_size, buffer_size, Direction::Dump.as_u32()];
+            if flash_size == 0 {
+                prop_assert_eq!(decode(&words), Err(DecodeError::ZeroSize(FIELD_FLASH_SIZE)));
+                prop_assert_eq!(decode(&v1), Err(DecodeError::ZeroSize(FIELD_FLASH_SIZE)));
+            } else if buffer_size == 0 {
+                prop_assert_eq!(decode(&words), Err(DecodeError::ZeroSize(FIELD_BUFFER_SIZE)));
+                prop_assert_eq!(decode(&v1), Err(DecodeError::ZeroSize(FIELD_BUFFER_SIZE)));
+            } else {
+                prop_assert!(decode(&v1).is_ok());
+            }
+        }
+
+        #[test]
+        fn rejects_uneven_buffer_sizes(
+            flash_size in 1u32..=0x10_0000,
+            buffer_size in 1u32..=0x1_0000,
+            direction in direction(),
+        ) {
+            let words = encode(flash_size as _, buffer_size as _, 1, buffer_size as _, direction);
+            if flash_size % buffer_size == 0 {
+                prop_assert!(decode(&words).is_ok());
+            } else {
+                prop_assert_eq!(
+                    decode(&words),
+                    Err(DecodeError::BufferSize(buffer_size, flash_size))
+                );
+            }
+        }
+
+        #[test]
+        fn decode_version_1_directions(direction in any::<u32>()) {
+            let v1 = [0x1000, 0x100, direction];
+            match direction {
+                1 | 2 => prop_assert!(decode(&v1).is_ok()),
+                _ => prop_assert_eq!(decode(&v1), Err(DecodeError::Direction(direction))),
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! An adapter for `embedded-storage` NOR flash drivers.
+
+use crate::{FlashDevice, ReadFlashDevice};
+use core::ops::Range;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Adapts any [`NorFlash`] implementor to a [`FlashDevice`], or any
+/// [`ReadNorFlash`] implementor to a [`ReadFlashDevice`].
+///
+/// The buffer and sector sizes passed to
+/// [`flash_interface!`](crate::flash_interface) are checked against the
+/// driver's `READ_SIZE`, `WRITE_SIZE` and `ERASE_SIZE` up front, so a
+/// mismatch fails before the flash is touched. Reads, writes and erases are
+/// still checked against them.
+///
+/// ```ignore
+/// let mut device = rs_flash::NorFlashDevice::new(flash, BUFFER_SIZE, SECTOR_SIZE);
+/// rs_flash::run(rs_flash_interface(), &mut device)
+/// ```
+#[derive(Debug)]
+pub struct NorFlashDevice<F> {
+    flash: F,
+}
+
+impl<F: ReadNorFlash> NorFlashDevice<F> {
+    /// Dump, verify or hash `flash` through buffers of `buffer_size`.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer size isn't a multiple of `F::READ_SIZE`.
+    pub fn read_only(flash: F, buffer_size: usize) -> Self {
+        assert!(
+            buffer_size % F::READ_SIZE == 0,
+            "buffer size must be a multiple of the read size"
+        );
+        Self { flash }
+    }
+}
+
+impl<F: NorFlash> NorFlashDevice<F> {
+    /// Dump or load `flash` through buffers of `buffer_size`, which are
+    /// erased and written in sectors of `sector_size`.
+    ///
+    /// Without a sector size in `flash_interface!`, pass the buffer size.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer size isn't a multiple of `F::READ_SIZE` and the sector
+    /// size, or the sector size isn't a multiple of `F::WRITE_SIZE` and
+    /// `F::ERASE_SIZE`.
+    pub fn new(flash: F, buffer_size: usize, sector_size: usize) -> Self {
+        assert!(
+            buffer_size % sector_size == 0,
+            "buffer size must be a multiple of the sector size"
+        );
+        assert!(
+            sector_size % F::WRITE_SIZE == 0,
+            "sector size must be a multiple of the write size"
+        );
+        assert!(
+            sector_size % F::ERASE_SIZE == 0,
+            "sector size must be a multiple of the erase size"
+        );
+        Self::read_only(flash, buffer_size)
+    }
+}
+
+impl<F> NorFlashDevice<F> {
+    /// Release the driver.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+/// An error of the [`NorFlashDevice`] adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NorFlashDeviceError<E> {
+    /// The request is not aligned, or out of bounds.
+    Check(NorFlashErrorKind),
+    /// The driver failed.
+    Flash(E),
+}
+
+impl<F: ReadNorFlash> ReadFlashDevice for NorFlashDevice<F> {
+    type Error = NorFlashDeviceError<F::Error>;
+
+    fn size(&self) -> usize {
+        self.flash.capacity()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as u32;
+        check_read(&self.flash, offset, buf.len()).map_err(NorFlashDeviceError::Check)?;
+        self.flash
+            .read(offset, buf)
+            .map_err(NorFlashDeviceError::Flash)
+    }
+}
+
+impl<F: NorFlash> FlashDevice for NorFlashDevice<F> {
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as u32;
+        check_write(&self.flash, offset, buf.len()).map_err(NorFlashDeviceError::Check)?;
+        self.flash
+            .write(offset, buf)
+            .map_err(NorFlashDeviceError::Flash)
+    }
+
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error> {
+        let (from, to) = (range.start as u32, range.end as u32);
+        check_erase(&self.flash, from, to).map_err(NorFlashDeviceError::Check)?;
+        self.flash
+            .erase(from, to)
+            .map_err(NorFlashDeviceError::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::ErrorType;
+
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 0x100;
+
+    /// A NOR flash that records operations, and fails at `fail_at`.
+    struct MockFlash {
+        data: Vec<u8>,
+        erases: Vec<(u32, u32)>,
+        fail_at: Option<u32>,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self {
+                data: vec![0xff; 4 * ERASE_SIZE],
+                erases: Vec::new(),
+                fail_at: None,
+            }
+        }
+
+        fn check(&self, offset: u32) -> Result<(), NorFlashErrorKind> {
+            match self.fail_at {
+                Some(fail_at) if fail_at == offset => Err(NorFlashErrorKind::Other),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            self.check(offset)?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = WRITE_SIZE;
+        const ERASE_SIZE: usize = ERASE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.check(from)?;
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases.push((from, to));
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.check(offset)?;
+            let offset = offset as usize;
+            for (flash, data) in self.data[offset..offset + bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+            {
+                *flash &= data;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn erase_write_and_read() {
+        let mut device = NorFlashDevice::new(MockFlash::new(), 2 * ERASE_SIZE, ERASE_SIZE);
+        assert_eq!(device.size(), 4 * ERASE_SIZE);
+
+        let mut data = [0x5a; ERASE_SIZE];
+        device.erase(ERASE_SIZE..2 * ERASE_SIZE).unwrap();
+        device.write(ERASE_SIZE, &mut data).unwrap();
+        let mut buf = [0; ERASE_SIZE];
+        device.read(ERASE_SIZE, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        device.erase_all().unwrap();
+        let flash = device.into_inner();
+        assert_eq!(
+            flash.erases,
+            [
+                (ERASE_SIZE as u32, 2 * ERASE_SIZE as u32),
+                (0, 4 * ERASE_SIZE as u32)
+            ]
+        );
+        assert!(flash.data.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn checks_requests() {
+        let mut device = NorFlashDevice::new(MockFlash::new(), ERASE_SIZE, ERASE_SIZE);
+        assert_eq!(
+            device.erase(1..ERASE_SIZE),
+            Err(NorFlashDeviceError::Check(NorFlashErrorKind::NotAligned))
+        );
+        assert_eq!(
+            device.write(1, &mut [0; WRITE_SIZE]),
+            Err(NorFlashDeviceError::Check(NorFlashErrorKind::NotAligned))
+        );
+        assert_eq!(
+            device.read(4 * ERASE_SIZE, &mut [0; 1]),
+            Err(NorFlashDeviceError::Check(NorFlashErrorKind::OutOfBounds))
+        );
+        assert!(device.into_inner().erases.is_empty());
+    }
+
+    #[test]
+    fn reports_driver_errors() {
+        let mut flash = MockFlash::new();
+        flash.fail_at = Some(ERASE_SIZE as u32);
+        let mut device = NorFlashDevice::new(flash, ERASE_SIZE, ERASE_SIZE);
+        assert_eq!(
+            device.erase(ERASE_SIZE..2 * ERASE_SIZE),
+            Err(NorFlashDeviceError::Flash(NorFlashErrorKind::Other))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "sector size must be a multiple of the erase size")]
+    fn rejects_sectors_smaller_than_erase_size() {
+        NorFlashDevice::new(MockFlash::new(), ERASE_SIZE, ERASE_SIZE / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "sector size must be a multiple of the write size")]
+    fn rejects_sectors_not_aligned_to_write_size() {
+        NorFlashDevice::new(MockFlash::new(), 2 * ERASE_SIZE + 2, ERASE_SIZE + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer size must be a multiple of the sector size")]
+    fn rejects_buffers_not_aligned_to_sectors() {
+        NorFlashDevice::new(MockFlash::new(), ERASE_SIZE + WRITE_SIZE, ERASE_SIZE);
+    }
+}
@@ -3,10 +3,10 @@
 #![no_std]
 #![no_main]
 
-use core::sync::atomic::Ordering;
+use core::ops::Range;
 use defmt_rtt as _;
 use panic_probe as _;
-use rs_flash::flash_interface;
+use rs_flash::{flash_interface, FlashDevice, ReadFlashDevice};
 
 /// The size of the flash in bytes. CHANGE ME!
 const FLASH_SIZE: usize = 16 * 1024 * 1024;
@@ -15,8 +15,38 @@ const FLASH_SIZE: usize = 16 * 1024 * 1024;
 /// The RAM buffer size must divide the flash size without remainder.
 const BUFFER_SIZE: usize = 32 * 1024;
 
+// See `load_verify.rs` to read back and verify each chunk.
 flash_interface!(FLASH_SIZE, BUFFER_SIZE, load);
 
+/// The flash to load.
+struct Device;
+
+impl ReadFlashDevice for Device {
+    type Error = ();
+
+    fn size(&self) -> usize {
+        FLASH_SIZE
+    }
+
+    fn read(&mut self, _offset: usize, _buf: &mut [u8]) -> Result<(), Self::Error> {
+        unreachable!("Loading never reads");
+    }
+}
+
+impl FlashDevice for Device {
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        todo!("Write the buffer into the flash at the offset");
+    }
+
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error> {
+        todo!("Erase the range");
+    }
+
+    fn erase_all(&mut self) -> Result<(), Self::Error> {
+        todo!("Erase the entire flash, or remove this to erase the range `0..FLASH_SIZE`");
+    }
+}
+
 /// Load example.
 #[cortex_m_rt::entry]
 fn main() -> ! {
@@ -24,36 +54,15 @@ fn main() -> ! {
     defmt::info!("init");
 
     todo!("Initialize peripherals");
-    let flash = ();
-
-    // --- Erase the entire flash.
-    defmt::info!("erasing chip...");
-
-    todo!("Erase the entire flash.");
-    flash.erase_all().unwrap();
-
-    // --- Load the entire flash.
-    defmt::info!("loading...");
-
-    for (offset, chunk) in (0..FLASH_SIZE).step_by(BUFFER_SIZE).zip(1..) {
-        defmt::info!("chunk {} / {} (at 0x{:08x})", chunk, _CHUNKS, offset);
-        // Spin until the host has written the buffer.
-        while unsafe { RS_FLASH_CONTROL.load(Ordering::SeqCst) } == 0 {
-            cortex_m::asm::nop();
-        }
-        // Write the next chunk into the flash.
-        {
-            let buf = unsafe { &mut *RS_FLASH_BUFFER.as_mut_ptr() };
-
-            todo!("Write the next chunk into the flash");
-            flash.write(offset, buf).unwrap();
-        }
-        // Signal buffer is ready to be written.
-        unsafe { RS_FLASH_CONTROL.store(0, Ordering::SeqCst) };
+    let mut flash = Device;
+
+    // --- Erase and load the requested range.
+    match rs_flash::run(rs_flash_interface(), &mut flash) {
+        Ok(()) => defmt::info!("done."),
+        Err(e) => defmt::error!("{} failed at 0x{:08x}", e.code.as_str(), e.offset),
     }
 
     // --- Done.
-    defmt::info!("done.");
     cortex_m::asm::bkpt();
     cortex_m::asm::udf()
 }
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+#![no_std]
+#![no_main]
+
+use core::ops::Range;
+use defmt_rtt as _;
+use panic_probe as _;
+use rs_flash::{flash_interface, FlashDevice, ReadFlashDevice};
+
+/// The size of the flash in bytes. CHANGE ME!
+const FLASH_SIZE: usize = 16 * 1024 * 1024;
+/// The size of the RAM buffer in bytes. CHANGE ME!
+///
+/// The RAM buffer size must divide the flash size without remainder.
+const BUFFER_SIZE: usize = 32 * 1024;
+
+flash_interface!(FLASH_SIZE, BUFFER_SIZE, load_verify);
+
+/// The flash to load and verify.
+struct Device;
+
+impl ReadFlashDevice for Device {
+    type Error = ();
+
+    fn size(&self) -> usize {
+        FLASH_SIZE
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        todo!("Read the committed chunk at the offset back into the buffer");
+    }
+}
+
+impl FlashDevice for Device {
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        todo!("Write the buffer into the flash at the offset");
+    }
+
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error> {
+        todo!("Erase the range");
+    }
+
+    fn erase_all(&mut self) -> Result<(), Self::Error> {
+        todo!("Erase the entire flash, or remove this to erase the range `0..FLASH_SIZE`");
+    }
+}
+
+/// Load and verify example.
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    // --- Initialize peripherals.
+    defmt::info!("init");
+
+    todo!("Initialize peripherals");
+    let mut flash = Device;
+
+    // --- Erase, load and read back the requested range.
+    match rs_flash::run(rs_flash_interface(), &mut flash) {
+        Ok(()) => defmt::info!("done."),
+        Err(e) => defmt::error!("{} failed at 0x{:08x}", e.code.as_str(), e.offset),
+    }
+
+    // --- Done.
+    cortex_m::asm::bkpt();
+    cortex_m::asm::udf()
+}
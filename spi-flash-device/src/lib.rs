@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The external SPI flash of the `dump-spi-flash` and `load-spi-flash`
+//! examples, as an rs-flash device.
+
+#![no_std]
+
+use core::ops::Range;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use rs_flash::{FlashDevice, ReadFlashDevice};
+use spi_memory::prelude::*;
+use spi_memory::series25::Flash;
+
+/// The size of a sector erased by the sector erase command.
+pub const SECTOR_SIZE: usize = 4 * 1024;
+
+/// The external SPI flash.
+///
+/// Dumping programs only need the [`ReadFlashDevice`] implementation, so the
+/// write and erase paths aren't linked into them.
+pub struct ExFlash<SPI: Transfer<u8>, CS: OutputPin> {
+    flash: Flash<SPI, CS>,
+    size: usize,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> ExFlash<SPI, CS> {
+    /// Use `flash`, which is `size` bytes.
+    pub fn new(flash: Flash<SPI, CS>, size: usize) -> Self {
+        Self { flash, size }
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> ReadFlashDevice for ExFlash<SPI, CS> {
+    type Error = spi_memory::Error<SPI, CS>;
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset as _, buf)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> FlashDevice for ExFlash<SPI, CS> {
+    fn write(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.write_bytes(offset as _, buf)
+    }
+
+    /// Erase `range`, which must be aligned to [`SECTOR_SIZE`].
+    fn erase(&mut self, range: Range<usize>) -> Result<(), Self::Error> {
+        // `erase_sectors` steps by pages instead of sectors, so erasing more
+        // than one sector at a time would only erase the first one.
+        for offset in range.step_by(SECTOR_SIZE) {
+            self.flash.erase_sectors(offset as _, 1)?;
+        }
+        Ok(())
+    }
+
+    fn erase_all(&mut self) -> Result<(), Self::Error> {
+        self.flash.erase_all()
+    }
+}